@@ -0,0 +1,40 @@
+// Local receiver for trying out webhooks. Run it with
+//   WEBHOOK_SECRET=<secret from the create response> cargo run --example webhookreceiver
+// and create a webhook pointing at http://localhost:9000/webhooks. Set
+// WEBHOOK_FAIL=1 to answer 500 and watch the retries in the delivery log.
+use axum::{Router, routing::post, http::{HeaderMap, StatusCode}};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+async fn receive(headers: HeaderMap, body: String) -> StatusCode {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok()).unwrap_or("").to_string();
+    let secret = std::env::var("WEBHOOK_SECRET").unwrap_or_default();
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(header("X-Webhook-Timestamp").as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    let valid = header("X-Webhook-Signature")
+        .strip_prefix("sha256=")
+        .and_then(|signature| hex::decode(signature).ok())
+        .map(|signature| mac.verify_slice(&signature).is_ok())
+        .unwrap_or(false);
+    println!("{} {} signature {}\n{}\n", header("X-Webhook-Event"), header("X-Webhook-Id"), if valid { "valid" } else { "INVALID" }, body);
+    if !valid {
+        return StatusCode::UNAUTHORIZED
+    }
+    if std::env::var("WEBHOOK_FAIL").is_ok() {
+        return StatusCode::INTERNAL_SERVER_ERROR
+    }
+    StatusCode::OK
+}
+
+#[tokio::main]
+async fn main() {
+    let app = Router::new().route("/webhooks", post(receive));
+    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 9000));
+    println!("listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await
+        .unwrap();
+}
@@ -0,0 +1,32 @@
+-- Ordered, variable-length image gallery per product, replacing the four
+-- fixed columns on productimages.
+CREATE TABLE IF NOT EXISTS productgallery (
+    imageid UUID PRIMARY KEY,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE CASCADE,
+    position INT NOT NULL,
+    original TEXT NOT NULL,
+    thumbnail TEXT,
+    webp TEXT,
+    thumbwebp TEXT,
+    storagekey TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS productgallery_productid_position_idx
+    ON productgallery (productid, position);
+
+INSERT INTO productgallery (imageid, productid, position, original)
+SELECT gen_random_uuid(), products.productid, legacy.position, legacy.url
+FROM products
+INNER JOIN productimages ON products.prodsku = productimages.prodskuid
+CROSS JOIN LATERAL (VALUES
+    (0, productimages.imageone),
+    (1, productimages.imagetwo),
+    (2, productimages.imagethree),
+    (3, productimages.imagefour)
+) AS legacy(position, url)
+WHERE legacy.url IS NOT NULL AND legacy.url <> '';
+
+-- Kept under a new name until the backfill has been checked against it. No
+-- migration drops it; drop it by hand once the gallery has been verified.
+ALTER TABLE productimages RENAME TO productimages_legacy;
@@ -0,0 +1,37 @@
+-- Option types (e.g. Colour, Size) and their values per product
+CREATE TABLE IF NOT EXISTS productoptions (
+    optionid UUID PRIMARY KEY,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    position INT NOT NULL DEFAULT 0,
+    UNIQUE (productid, name)
+);
+
+CREATE TABLE IF NOT EXISTS productoptionvalues (
+    valueid UUID PRIMARY KEY,
+    optionid UUID NOT NULL REFERENCES productoptions(optionid) ON DELETE CASCADE,
+    value TEXT NOT NULL,
+    position INT NOT NULL DEFAULT 0,
+    UNIQUE (optionid, value)
+);
+
+-- A sellable combination of option values with its own SKU, price and stock.
+-- A NULL price falls back to products.price.
+CREATE TABLE IF NOT EXISTS productvariants (
+    variantid UUID PRIMARY KEY,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE CASCADE,
+    sku TEXT NOT NULL UNIQUE,
+    price TEXT,
+    availableqty BIGINT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS variantoptionvalues (
+    variantid UUID NOT NULL REFERENCES productvariants(variantid) ON DELETE CASCADE,
+    valueid UUID NOT NULL REFERENCES productoptionvalues(valueid) ON DELETE CASCADE,
+    PRIMARY KEY (variantid, valueid)
+);
+
+ALTER TABLE productgallery ADD COLUMN IF NOT EXISTS variantid UUID REFERENCES productvariants(variantid) ON DELETE SET NULL;
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS variantid UUID REFERENCES productvariants(variantid);
+ALTER TABLE favourites ADD COLUMN IF NOT EXISTS variantid UUID REFERENCES productvariants(variantid) ON DELETE CASCADE;
@@ -0,0 +1,23 @@
+-- Orders placed before reservations existed already had their stock deducted,
+-- so they are treated as paid.
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'paid';
+ALTER TABLE orderdet ALTER COLUMN status SET DEFAULT 'pending';
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS paymentintentid TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS paid_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS stockreservations (
+    reservationid UUID PRIMARY KEY,
+    orderid BIGINT NOT NULL REFERENCES orderdet(orderid) ON DELETE CASCADE,
+    productid UUID NOT NULL REFERENCES products(productid),
+    variantid UUID REFERENCES productvariants(variantid),
+    quantity INT NOT NULL CHECK (quantity > 0),
+    status TEXT NOT NULL DEFAULT 'held' CHECK (status IN ('held', 'committed', 'released')),
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS stockreservations_held_idx
+    ON stockreservations (productid, variantid) WHERE status = 'held';
+CREATE INDEX IF NOT EXISTS stockreservations_orderid_idx
+    ON stockreservations (orderid);
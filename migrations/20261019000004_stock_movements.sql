@@ -0,0 +1,25 @@
+-- Every change to availableqty is recorded here; the sum of delta per product
+-- (or variant) is the expected on-hand quantity. Ledger rows are never deleted,
+-- so a variant with stock history cannot be deleted either.
+CREATE TABLE IF NOT EXISTS stock_movements (
+    movementid UUID PRIMARY KEY,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE RESTRICT,
+    variantid UUID REFERENCES productvariants(variantid) ON DELETE RESTRICT,
+    reason TEXT NOT NULL CHECK (reason IN ('sale', 'restock', 'adjustment', 'return')),
+    delta BIGINT NOT NULL,
+    actor UUID,
+    orderid BIGINT REFERENCES orderdet(orderid) ON DELETE SET NULL,
+    note TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS stock_movements_product_idx
+    ON stock_movements (productid, variantid, created_at);
+
+INSERT INTO stock_movements (movementid, productid, variantid, reason, delta, note)
+SELECT gen_random_uuid(), productid, NULL, 'adjustment', availableqty, 'opening balance'
+FROM products;
+
+INSERT INTO stock_movements (movementid, productid, variantid, reason, delta, note)
+SELECT gen_random_uuid(), productid, variantid, 'adjustment', availableqty, 'opening balance'
+FROM productvariants;
@@ -0,0 +1,13 @@
+-- Admins are alerted once when availableqty falls below reorderthreshold;
+-- lowstockalerted_at is cleared again when the product is restocked.
+ALTER TABLE products ADD COLUMN IF NOT EXISTS reorderthreshold BIGINT;
+ALTER TABLE products ADD COLUMN IF NOT EXISTS lowstockalerted_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS stocksubscriptions (
+    subid UUID PRIMARY KEY,
+    userid UUID NOT NULL REFERENCES users(usid) ON DELETE CASCADE,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    notified_at TIMESTAMPTZ,
+    UNIQUE (userid, productid)
+);
@@ -0,0 +1,7 @@
+-- Products are archived instead of deleted so past orders and the stock ledger
+-- keep resolving them. Only active products are shown in the catalogue.
+ALTER TABLE products ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'active'
+    CHECK (status IN ('draft', 'active', 'archived'));
+ALTER TABLE products ADD COLUMN IF NOT EXISTS archived_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS products_status_idx ON products (status);
@@ -0,0 +1,17 @@
+-- One review per customer per product. New and edited reviews wait for an admin
+-- to approve them; only approved reviews count towards the product rating.
+CREATE TABLE IF NOT EXISTS productreviews (
+    reviewid UUID PRIMARY KEY,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE CASCADE,
+    userid UUID NOT NULL REFERENCES users(usid) ON DELETE CASCADE,
+    rating SMALLINT NOT NULL CHECK (rating BETWEEN 1 AND 5),
+    body TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'approved', 'rejected')),
+    moderated_by UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (productid, userid)
+);
+
+CREATE INDEX IF NOT EXISTS productreviews_productid_status_idx ON productreviews (productid, status);
+CREATE INDEX IF NOT EXISTS productreviews_status_idx ON productreviews (status, created_at);
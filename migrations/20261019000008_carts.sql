@@ -0,0 +1,25 @@
+-- Server-side carts. A cart belongs either to a user or to a guest token; guest
+-- carts are merged into the user's cart when they log in.
+CREATE TABLE IF NOT EXISTS carts (
+    cartid UUID PRIMARY KEY,
+    userid UUID UNIQUE REFERENCES users(usid) ON DELETE CASCADE,
+    guesttoken TEXT UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CHECK ((userid IS NULL) <> (guesttoken IS NULL))
+);
+
+-- addedprice is the price the customer last saw, so a changed price can be
+-- flagged on the next read.
+CREATE TABLE IF NOT EXISTS cartitems (
+    cartitemid UUID PRIMARY KEY,
+    cartid UUID NOT NULL REFERENCES carts(cartid) ON DELETE CASCADE,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE CASCADE,
+    variantid UUID REFERENCES productvariants(variantid) ON DELETE CASCADE,
+    quantity INT NOT NULL CHECK (quantity > 0),
+    addedprice TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS cartitems_line_idx
+    ON cartitems (cartid, productid, (COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid)));
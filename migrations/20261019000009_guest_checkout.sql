@@ -0,0 +1,25 @@
+-- Guest customers check out with an email address instead of an account. A
+-- signed-in customer can later claim a guest order onto their account with the
+-- order's emailed lookup token; a matching email alone is not enough.
+CREATE TABLE IF NOT EXISTS guestcustomers (
+    guestid UUID PRIMARY KEY,
+    email TEXT NOT NULL,
+    fullname TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS guestcustomers_email_idx ON guestcustomers ((lower(email)));
+
+ALTER TABLE orderdet ALTER COLUMN userid DROP NOT NULL;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS guestid UUID REFERENCES guestcustomers(guestid);
+-- Only a SHA-256 hash of the lookup token emailed to the guest is kept
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS lookuptokenhash TEXT UNIQUE;
+
+-- Where the order ships to, copied at checkout so later address edits do not
+-- change past orders
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shipname TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shipaddress TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shipcity TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shippostcode TEXT;
+
+CREATE INDEX IF NOT EXISTS orderdet_guestid_idx ON orderdet (guestid);
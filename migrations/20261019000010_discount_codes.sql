@@ -0,0 +1,41 @@
+-- Coupon codes. An empty productids/categories list means the code applies to
+-- the whole basket; otherwise only matching lines are discounted.
+CREATE TABLE IF NOT EXISTS discountcodes (
+    codeid UUID PRIMARY KEY,
+    code TEXT NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('percentage', 'fixed', 'freeshipping')),
+    value NUMERIC NOT NULL DEFAULT 0 CHECK (value >= 0),
+    starts_at TIMESTAMPTZ,
+    ends_at TIMESTAMPTZ,
+    maxuses INT,
+    maxusespercustomer INT,
+    minbasket NUMERIC,
+    productids UUID[] NOT NULL DEFAULT '{}',
+    categories TEXT[] NOT NULL DEFAULT '{}',
+    active BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS discountcodes_code_idx ON discountcodes ((upper(code)));
+
+-- One row per order that used a code. Rows for expired or cancelled orders do
+-- not count towards usage limits.
+CREATE TABLE IF NOT EXISTS discountredemptions (
+    redemptionid UUID PRIMARY KEY,
+    codeid UUID NOT NULL REFERENCES discountcodes(codeid),
+    orderid BIGINT NOT NULL REFERENCES orderdet(orderid) ON DELETE CASCADE,
+    userid UUID,
+    guestid UUID,
+    amount NUMERIC NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS discountredemptions_codeid_idx ON discountredemptions (codeid);
+
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS subtotal NUMERIC;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS discounttotal NUMERIC NOT NULL DEFAULT 0;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS discountcode TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS freeshipping BOOLEAN NOT NULL DEFAULT false;
+
+-- The share of the order discount taken off each line, kept for reporting and refunds
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS discount NUMERIC NOT NULL DEFAULT 0;
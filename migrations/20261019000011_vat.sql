@@ -0,0 +1,13 @@
+-- UK VAT. Rates are copied onto each order line so later rate changes do not
+-- alter past orders.
+ALTER TABLE products ADD COLUMN IF NOT EXISTS taxclass TEXT NOT NULL DEFAULT 'standard'
+    CHECK (taxclass IN ('standard', 'reduced', 'zero'));
+
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS taxclass TEXT;
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS taxrate NUMERIC;
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS netamount NUMERIC;
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS taxamount NUMERIC NOT NULL DEFAULT 0;
+
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS pricesincludevat BOOLEAN NOT NULL DEFAULT true;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS nettotal NUMERIC;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS taxtotal NUMERIC NOT NULL DEFAULT 0;
@@ -0,0 +1,28 @@
+-- Shipping methods and their rate rule:
+--   flat       baserate for every order
+--   weight     baserate plus perkg for each started kilogram
+--   freeabove  baserate, or free once the discounted basket reaches freeabove
+-- An empty postcodeprefixes list means the method ships anywhere; otherwise
+-- the postcode area (e.g. BT) or outward code (e.g. IV27) must be listed.
+CREATE TABLE IF NOT EXISTS shippingmethods (
+    methodid UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    description TEXT,
+    ratekind TEXT NOT NULL CHECK (ratekind IN ('flat', 'weight', 'freeabove')),
+    baserate NUMERIC NOT NULL DEFAULT 0 CHECK (baserate >= 0),
+    perkg NUMERIC NOT NULL DEFAULT 0 CHECK (perkg >= 0),
+    freeabove NUMERIC,
+    maxweightgrams INT,
+    postcodeprefixes TEXT[] NOT NULL DEFAULT '{}',
+    active BOOLEAN NOT NULL DEFAULT true,
+    position INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE products ADD COLUMN IF NOT EXISTS weightgrams INT NOT NULL DEFAULT 0 CHECK (weightgrams >= 0);
+
+-- The method name and cost are copied so later edits do not change past orders
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shippingmethodid UUID REFERENCES shippingmethods(methodid);
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shippingmethod TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shippingcost NUMERIC NOT NULL DEFAULT 0;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shippingtax NUMERIC NOT NULL DEFAULT 0;
@@ -0,0 +1,18 @@
+-- Users can keep several addresses. At most one is the default for shipping
+-- and one for billing; the address created at registration becomes both.
+ALTER TABLE useraddr ADD COLUMN IF NOT EXISTS fullname TEXT;
+ALTER TABLE useraddr ADD COLUMN IF NOT EXISTS label TEXT;
+ALTER TABLE useraddr ADD COLUMN IF NOT EXISTS defaultshipping BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE useraddr ADD COLUMN IF NOT EXISTS defaultbilling BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE useraddr ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+UPDATE useraddr SET defaultshipping = true, defaultbilling = true
+WHERE addrid IN (SELECT DISTINCT ON (userid) addrid FROM useraddr ORDER BY userid, addrid);
+
+CREATE UNIQUE INDEX IF NOT EXISTS useraddr_defaultshipping_idx ON useraddr (userid) WHERE defaultshipping;
+CREATE UNIQUE INDEX IF NOT EXISTS useraddr_defaultbilling_idx ON useraddr (userid) WHERE defaultbilling;
+
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS billname TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS billaddress TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS billcity TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS billpostcode TEXT;
@@ -0,0 +1,16 @@
+-- VAT invoices. Numbers come from a single counter row updated inside the
+-- invoice transaction so they stay sequential without gaps. The rendered PDF is
+-- kept so every download of an invoice returns the same document.
+CREATE TABLE IF NOT EXISTS invoicecounter (
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    lastnumber BIGINT NOT NULL DEFAULT 0
+);
+
+INSERT INTO invoicecounter (id, lastnumber) VALUES (true, 0) ON CONFLICT DO NOTHING;
+
+CREATE TABLE IF NOT EXISTS invoices (
+    invoicenumber BIGINT PRIMARY KEY,
+    orderid BIGINT NOT NULL UNIQUE REFERENCES orderdet(orderid),
+    issued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    pdf BYTEA NOT NULL
+);
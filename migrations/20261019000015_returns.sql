@@ -0,0 +1,37 @@
+-- Return requests (RMA) against delivered orders.
+--   requested -> approved -> received
+--   requested -> rejected
+-- The refund is issued on approval; refundstatus tracks the payment side so a
+-- failed refund can be retried without repeating the approval.
+CREATE TABLE IF NOT EXISTS returns (
+    returnid UUID PRIMARY KEY,
+    orderid BIGINT NOT NULL REFERENCES orderdet(orderid),
+    userid UUID NOT NULL,
+    status TEXT NOT NULL DEFAULT 'requested' CHECK (status IN ('requested', 'approved', 'rejected', 'received')),
+    note TEXT,
+    adminnote TEXT,
+    refundamount NUMERIC,
+    refundstatus TEXT CHECK (refundstatus IN ('pending', 'succeeded', 'failed')),
+    refundid TEXT,
+    refunderror TEXT,
+    decided_by UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    decided_at TIMESTAMPTZ,
+    received_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS returns_orderid_idx ON returns (orderid);
+CREATE INDEX IF NOT EXISTS returns_status_idx ON returns (status, created_at);
+
+CREATE TABLE IF NOT EXISTS returnitems (
+    returnitemid UUID PRIMARY KEY,
+    returnid UUID NOT NULL REFERENCES returns(returnid) ON DELETE CASCADE,
+    productid UUID NOT NULL,
+    variantid UUID,
+    quantity INT NOT NULL CHECK (quantity > 0),
+    reason TEXT NOT NULL,
+    refundamount NUMERIC,
+    disposition TEXT CHECK (disposition IN ('restock', 'writeoff'))
+);
+
+CREATE INDEX IF NOT EXISTS returnitems_returnid_idx ON returnitems (returnid);
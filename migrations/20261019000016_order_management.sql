@@ -0,0 +1,33 @@
+-- Fulfilment adds two statuses after payment:
+--   paid -> shipped -> delivered
+-- Every status change made by an admin is kept in orderstatushistory.
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS carrier TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS trackingnumber TEXT;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS shipped_at TIMESTAMPTZ;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS delivered_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS orderdet_status_idx ON orderdet (status, created_at);
+CREATE INDEX IF NOT EXISTS orderdet_created_at_idx ON orderdet (created_at);
+
+CREATE TABLE IF NOT EXISTS orderstatushistory (
+    historyid UUID PRIMARY KEY,
+    orderid BIGINT NOT NULL REFERENCES orderdet(orderid) ON DELETE CASCADE,
+    fromstatus TEXT NOT NULL,
+    tostatus TEXT NOT NULL,
+    actor UUID,
+    note TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS orderstatushistory_orderid_idx ON orderstatushistory (orderid, created_at);
+
+-- Internal notes are only ever shown to admins
+CREATE TABLE IF NOT EXISTS ordernotes (
+    noteid UUID PRIMARY KEY,
+    orderid BIGINT NOT NULL REFERENCES orderdet(orderid) ON DELETE CASCADE,
+    author UUID NOT NULL,
+    note TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS ordernotes_orderid_idx ON ordernotes (orderid, created_at);
@@ -0,0 +1,45 @@
+-- A cart session starts when something is added to a cart with no open session
+-- and ends when the cart is checked out. Carts are reused, so sessions are what
+-- cart-to-order conversion is measured against.
+CREATE TABLE IF NOT EXISTS cartsessions (
+    sessionid UUID PRIMARY KEY,
+    cartid UUID NOT NULL REFERENCES carts(cartid) ON DELETE CASCADE,
+    orderid BIGINT REFERENCES orderdet(orderid) ON DELETE SET NULL,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    checkedout_at TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS cartsessions_open_idx ON cartsessions (cartid) WHERE checkedout_at IS NULL;
+CREATE INDEX IF NOT EXISTS cartsessions_started_at_idx ON cartsessions (started_at);
+
+-- Optional pre-aggregated copies of the daily report queries, used when
+-- REPORTS_MATERIALIZED is set and refreshed in the background. Orders count on
+-- the day they were paid.
+CREATE MATERIALIZED VIEW IF NOT EXISTS reportdailysales AS
+SELECT COALESCE(paid_at, created_at)::date AS day,
+    COUNT(*)::BIGINT AS orders,
+    SUM(total) AS revenue,
+    SUM(COALESCE(nettotal, total)) AS nettotal,
+    SUM(taxtotal) AS taxtotal,
+    SUM(discounttotal) AS discounttotal,
+    SUM(shippingcost) AS shippingcost
+FROM orderdet
+WHERE status IN ('paid', 'shipped', 'delivered', 'refunded')
+GROUP BY 1;
+
+CREATE UNIQUE INDEX IF NOT EXISTS reportdailysales_day_idx ON reportdailysales (day);
+
+CREATE MATERIALIZED VIEW IF NOT EXISTS reportdailyproducts AS
+SELECT COALESCE(orderdet.paid_at, orderdet.created_at)::date AS day,
+    listitems.productid,
+    listitems.variantid,
+    SUM(listitems.quantity)::BIGINT AS units,
+    SUM(COALESCE(listitems.netamount + listitems.taxamount, CASE WHEN products.price ~ '^\s*[0-9]+(\.[0-9]+)?\s*$' THEN products.price::NUMERIC END * listitems.quantity)) AS revenue
+FROM listitems
+INNER JOIN orderdet ON orderdet.orderid = listitems.orderidretr
+INNER JOIN products ON products.productid = listitems.productid
+WHERE orderdet.status IN ('paid', 'shipped', 'delivered', 'refunded')
+GROUP BY 1, 2, 3;
+
+CREATE UNIQUE INDEX IF NOT EXISTS reportdailyproducts_line_idx
+    ON reportdailyproducts (day, productid, (COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid)));
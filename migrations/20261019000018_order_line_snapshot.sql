@@ -0,0 +1,47 @@
+-- What each line looked like when it was bought, so later product edits do not
+-- change past orders. unitprice is the listed price before any discount.
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS unitprice NUMERIC;
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS prodname TEXT;
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS sku TEXT;
+ALTER TABLE listitems ADD COLUMN IF NOT EXISTS image TEXT;
+
+-- Lines placed through checkout can recover the exact price from the amounts
+-- charged. Older lines only have the product as it is now.
+UPDATE listitems SET unitprice = ROUND(
+    (listitems.netamount + listitems.discount + CASE WHEN orderdet.pricesincludevat THEN listitems.taxamount ELSE 0 END)
+    / NULLIF(listitems.quantity, 0), 2)
+FROM orderdet
+WHERE orderdet.orderid = listitems.orderidretr
+AND listitems.unitprice IS NULL AND listitems.netamount IS NOT NULL;
+
+UPDATE listitems SET
+    unitprice = COALESCE(listitems.unitprice, (
+        SELECT CASE WHEN current.price ~ '^\s*[0-9]+(\.[0-9]+)?\s*$' THEN current.price::NUMERIC END
+        FROM (SELECT COALESCE(
+            (SELECT price FROM productvariants WHERE productvariants.variantid = listitems.variantid), products.price) AS price) current)),
+    prodname = COALESCE(listitems.prodname, products.prodname),
+    sku = COALESCE(listitems.sku,
+        (SELECT sku FROM productvariants WHERE productvariants.variantid = listitems.variantid), products.prodsku),
+    image = COALESCE(listitems.image, (
+        SELECT COALESCE(thumbnail, original) FROM productgallery
+        WHERE productgallery.productid = listitems.productid
+        ORDER BY (productgallery.variantid IS NOT DISTINCT FROM listitems.variantid) DESC, position LIMIT 1))
+FROM products
+WHERE products.productid = listitems.productid;
+
+-- Product sales now read the snapshot price for lines without charged amounts
+DROP MATERIALIZED VIEW IF EXISTS reportdailyproducts;
+
+CREATE MATERIALIZED VIEW reportdailyproducts AS
+SELECT COALESCE(orderdet.paid_at, orderdet.created_at)::date AS day,
+    listitems.productid,
+    listitems.variantid,
+    SUM(listitems.quantity)::BIGINT AS units,
+    SUM(COALESCE(listitems.netamount + listitems.taxamount, listitems.unitprice * listitems.quantity)) AS revenue
+FROM listitems
+INNER JOIN orderdet ON orderdet.orderid = listitems.orderidretr
+WHERE orderdet.status IN ('paid', 'shipped', 'delivered', 'refunded')
+GROUP BY 1, 2, 3;
+
+CREATE UNIQUE INDEX IF NOT EXISTS reportdailyproducts_line_idx
+    ON reportdailyproducts (day, productid, (COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid)));
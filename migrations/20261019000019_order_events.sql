@@ -0,0 +1,28 @@
+-- Order changes are published on the orderevents channel so every instance can
+-- push them to connected customers. Notifications are sent on commit.
+CREATE OR REPLACE FUNCTION notifyorderevent() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('orderevents', json_build_object(
+        'orderid', NEW.orderid,
+        'event', CASE
+            WHEN NEW.status = 'paid' AND OLD.status IS DISTINCT FROM 'paid' THEN 'payment'
+            WHEN NEW.status IN ('shipped', 'delivered') AND OLD.status IS DISTINCT FROM NEW.status THEN 'shipping'
+            WHEN NEW.status = OLD.status THEN 'shipping'
+            ELSE 'status' END,
+        'status', NEW.status,
+        'previousstatus', OLD.status,
+        'carrier', NEW.carrier,
+        'trackingnumber', NEW.trackingnumber,
+        'at', now())::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS orderdet_events ON orderdet;
+
+CREATE TRIGGER orderdet_events AFTER UPDATE ON orderdet
+FOR EACH ROW
+WHEN (OLD.status IS DISTINCT FROM NEW.status
+    OR OLD.carrier IS DISTINCT FROM NEW.carrier
+    OR OLD.trackingnumber IS DISTINCT FROM NEW.trackingnumber)
+EXECUTE FUNCTION notifyorderevent();
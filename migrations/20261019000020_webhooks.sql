@@ -0,0 +1,33 @@
+-- Outbound webhooks. Events are written to webhookdeliveries in the same
+-- transaction as the change that caused them and sent by a background worker,
+-- so nothing is lost if the receiver or this server is down.
+CREATE TABLE IF NOT EXISTS webhooks (
+    webhookid UUID PRIMARY KEY,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT[] NOT NULL,
+    description TEXT,
+    active BOOLEAN NOT NULL DEFAULT true,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- payload is the event data as JSON text. A manual redelivery is a new row that
+-- points at the delivery it repeats, so the log keeps every attempt.
+CREATE TABLE IF NOT EXISTS webhookdeliveries (
+    deliveryid UUID PRIMARY KEY,
+    webhookid UUID NOT NULL REFERENCES webhooks(webhookid) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'succeeded', 'failed')),
+    attempts INT NOT NULL DEFAULT 0,
+    nextattempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    lastattempt_at TIMESTAMPTZ,
+    responsestatus INT,
+    responsebody TEXT,
+    error TEXT,
+    redeliveryof UUID REFERENCES webhookdeliveries(deliveryid) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS webhookdeliveries_due_idx ON webhookdeliveries (nextattempt_at) WHERE status = 'pending';
+CREATE INDEX IF NOT EXISTS webhookdeliveries_webhookid_idx ON webhookdeliveries (webhookid, created_at);
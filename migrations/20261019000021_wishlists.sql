@@ -0,0 +1,34 @@
+-- Adding a favourite is now idempotent: drop duplicates made before, keeping one
+-- row per product and variant, and stop new ones with a unique index
+DELETE FROM favourites a USING favourites b
+WHERE a.userid = b.userid
+AND a.productid = b.productid
+AND a.variantid IS NOT DISTINCT FROM b.variantid
+AND a.ctid > b.ctid;
+
+CREATE UNIQUE INDEX IF NOT EXISTS favourites_item_idx
+ON favourites (userid, productid, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid));
+
+-- Price when the item was added, so the list can show what has changed since
+ALTER TABLE favourites ADD COLUMN IF NOT EXISTS addedprice NUMERIC;
+ALTER TABLE favourites ADD COLUMN IF NOT EXISTS added_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- Prices that aren't plain decimals are left NULL rather than failing the cast
+UPDATE favourites SET addedprice = (
+    SELECT CASE WHEN current.price ~ '^\s*[0-9]+(\.[0-9]+)?\s*$' THEN current.price::NUMERIC END
+    FROM (SELECT COALESCE(
+        (SELECT price FROM productvariants WHERE productvariants.variantid = favourites.variantid),
+        (SELECT price FROM products WHERE products.productid = favourites.productid)) AS price) current)
+WHERE addedprice IS NULL;
+
+-- Named public links to a user's wishlist. Anyone with the token can view it
+-- until the owner deletes the link.
+CREATE TABLE IF NOT EXISTS wishlistshares (
+    shareid UUID PRIMARY KEY,
+    userid UUID NOT NULL REFERENCES users(usid) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS wishlistshares_userid_idx ON wishlistshares (userid);
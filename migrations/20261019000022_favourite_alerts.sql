@@ -0,0 +1,28 @@
+-- Per-user email preferences. Rows are created on first use; a user without one
+-- gets every alert. The token backs the unsubscribe link in alert emails.
+CREATE TABLE IF NOT EXISTS notificationpreferences (
+    userid UUID PRIMARY KEY REFERENCES users(usid) ON DELETE CASCADE,
+    pricedrops BOOLEAN NOT NULL DEFAULT true,
+    backinstock BOOLEAN NOT NULL DEFAULT true,
+    unsubscribetoken TEXT NOT NULL UNIQUE,
+    lastdigest_at TIMESTAMPTZ,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Changes to favourited products waiting for the daily digest. Repeated changes
+-- before the digest go out update the pending row, keeping the first old price.
+CREATE TABLE IF NOT EXISTS favouritealerts (
+    alertid UUID PRIMARY KEY,
+    userid UUID NOT NULL REFERENCES users(usid) ON DELETE CASCADE,
+    productid UUID NOT NULL REFERENCES products(productid) ON DELETE CASCADE,
+    variantid UUID REFERENCES productvariants(variantid) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (kind IN ('pricedrop', 'backinstock')),
+    oldprice NUMERIC,
+    newprice NUMERIC,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    sent_at TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS favouritealerts_pending_idx
+ON favouritealerts (userid, productid, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid), kind)
+WHERE sent_at IS NULL;
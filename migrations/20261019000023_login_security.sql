@@ -0,0 +1,36 @@
+-- Failed sign-ins per account. The count restarts after a quiet period, on a
+-- successful sign-in and when the account is locked.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS failedlogins INT NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS lastfailedlogin_at TIMESTAMPTZ;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS lockeduntil TIMESTAMPTZ;
+
+-- Failed sign-ins per client IP, counted over a window, whichever account they were for
+CREATE TABLE IF NOT EXISTS loginipfailures (
+    ip TEXT PRIMARY KEY,
+    failures INT NOT NULL DEFAULT 0,
+    windowstart TIMESTAMPTZ NOT NULL DEFAULT now(),
+    lastfailure_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    blockeduntil TIMESTAMPTZ
+);
+
+-- IPs each user has signed in from, to spot sign-ins from somewhere new
+CREATE TABLE IF NOT EXISTS userloginips (
+    userid UUID NOT NULL REFERENCES users(usid) ON DELETE CASCADE,
+    ip TEXT NOT NULL,
+    first_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (userid, ip)
+);
+
+-- actor is the admin for an unlock. iplockout events have no user.
+CREATE TABLE IF NOT EXISTS securityevents (
+    eventid UUID PRIMARY KEY,
+    userid UUID REFERENCES users(usid) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (kind IN ('lockout', 'unlock', 'newip', 'iplockout')),
+    ip TEXT,
+    actor UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS securityevents_userid_idx ON securityevents (userid, created_at);
+CREATE INDEX IF NOT EXISTS securityevents_created_at_idx ON securityevents (created_at);
@@ -0,0 +1,8 @@
+-- Failed sign-ins for emails with no account, counted the same way as
+-- users.failedlogins so an unknown email is delayed and locked just like a real one
+CREATE TABLE IF NOT EXISTS loginemailfailures (
+    email TEXT PRIMARY KEY,
+    failedlogins INT NOT NULL DEFAULT 0,
+    lastfailedlogin_at TIMESTAMPTZ,
+    lockeduntil TIMESTAMPTZ
+);
@@ -0,0 +1,16 @@
+-- Links sent by the guest order lookup. The link issued at checkout stays in
+-- orderdet.lookuptokenhash; any of them opens the order until it is claimed.
+CREATE TABLE IF NOT EXISTS guestlookuptokens (
+    tokenhash TEXT PRIMARY KEY,
+    orderid BIGINT NOT NULL REFERENCES orderdet(orderid) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS guestlookuptokens_orderid_idx ON guestlookuptokens (orderid);
+
+-- Lookup requests per client IP and per email, counted over a window
+CREATE TABLE IF NOT EXISTS guestlookupattempts (
+    throttlekey TEXT PRIMARY KEY,
+    attempts INT NOT NULL DEFAULT 0,
+    windowstart TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,46 @@
+-- The statuses of orders that were paid for, including ones later shipped,
+-- delivered or refunded. Every report counts sales through this.
+CREATE OR REPLACE FUNCTION soldstatus(status TEXT) RETURNS BOOLEAN
+LANGUAGE SQL IMMUTABLE AS $$
+    SELECT status IN ('paid', 'shipped', 'delivered', 'refunded')
+$$;
+
+-- The daily report queries. Reports read these directly unless
+-- REPORTS_MATERIALIZED is set, in which case they read the copies below.
+CREATE OR REPLACE VIEW reportdailysaleslive AS
+SELECT COALESCE(paid_at, created_at)::date AS day,
+    COUNT(*)::BIGINT AS orders,
+    SUM(total) AS revenue,
+    SUM(COALESCE(nettotal, total)) AS nettotal,
+    SUM(taxtotal) AS taxtotal,
+    SUM(discounttotal) AS discounttotal,
+    SUM(shippingcost) AS shippingcost
+FROM orderdet
+WHERE soldstatus(status)
+GROUP BY 1;
+
+CREATE OR REPLACE VIEW reportdailyproductslive AS
+SELECT COALESCE(orderdet.paid_at, orderdet.created_at)::date AS day,
+    listitems.productid,
+    listitems.variantid,
+    SUM(listitems.quantity)::BIGINT AS units,
+    SUM(COALESCE(listitems.netamount + listitems.taxamount, listitems.unitprice * listitems.quantity)) AS revenue
+FROM listitems
+INNER JOIN orderdet ON orderdet.orderid = listitems.orderidretr
+WHERE soldstatus(orderdet.status)
+GROUP BY 1, 2, 3;
+
+DROP MATERIALIZED VIEW IF EXISTS reportdailysales;
+DROP MATERIALIZED VIEW IF EXISTS reportdailyproducts;
+
+CREATE MATERIALIZED VIEW reportdailysales AS SELECT * FROM reportdailysaleslive;
+
+CREATE UNIQUE INDEX IF NOT EXISTS reportdailysales_day_idx ON reportdailysales (day);
+
+-- Concurrent refreshes need a unique index on plain columns, so the line key
+-- is a column rather than an index expression
+CREATE MATERIALIZED VIEW reportdailyproducts AS
+SELECT *, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid) AS variantkey
+FROM reportdailyproductslive;
+
+CREATE UNIQUE INDEX IF NOT EXISTS reportdailyproducts_line_idx ON reportdailyproducts (day, productid, variantkey);
@@ -0,0 +1,10 @@
+-- Products sold through productvariants alert and notify per variant. Each
+-- variant is measured against its product's reorderthreshold.
+ALTER TABLE productvariants ADD COLUMN IF NOT EXISTS lowstockalerted_at TIMESTAMPTZ;
+
+-- A subscription without a variant waits for the product as a whole
+ALTER TABLE stocksubscriptions ADD COLUMN IF NOT EXISTS variantid UUID REFERENCES productvariants(variantid) ON DELETE CASCADE;
+ALTER TABLE stocksubscriptions DROP CONSTRAINT IF EXISTS stocksubscriptions_userid_productid_key;
+
+CREATE UNIQUE INDEX IF NOT EXISTS stocksubscriptions_item_idx
+ON stocksubscriptions (userid, productid, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid));
@@ -0,0 +1,12 @@
+-- Orders marked refunded by hand record what was refunded and when, so the
+-- revenue report can count it alongside refunds made through returns. The
+-- refund is whatever of the total had not already been refunded by a return.
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS refundamount NUMERIC;
+ALTER TABLE orderdet ADD COLUMN IF NOT EXISTS refunded_at TIMESTAMPTZ;
+
+UPDATE orderdet SET
+    refundamount = GREATEST(orderdet.total - COALESCE((SELECT SUM(returns.refundamount) FROM returns
+        WHERE returns.orderid = orderdet.orderid AND returns.refundstatus = 'succeeded'), 0), 0),
+    refunded_at = COALESCE((SELECT MAX(orderstatushistory.created_at) FROM orderstatushistory
+        WHERE orderstatushistory.orderid = orderdet.orderid AND orderstatushistory.tostatus = 'refunded'), now())
+WHERE orderdet.status = 'refunded' AND orderdet.refundamount IS NULL;
@@ -0,0 +1,196 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{fmt, path::PathBuf};
+
+#[derive(Debug)]
+
+pub enum BlobError {
+    Io(std::io::Error),
+    Http(String),
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Io(e) => write!(f, "storage io error: {}", e),
+            BlobError::Http(e) => write!(f, "storage request failed: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for BlobError {
+    fn from(e: std::io::Error) -> Self {
+        BlobError::Io(e)
+    }
+}
+
+// Keys are generated by the server (e.g. products/<productid>/<imageid>/thumb.jpg),
+// never taken from the client.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>, contenttype: &str) -> Result<String, BlobError>;
+    async fn delete(&self, key: &str) -> Result<(), BlobError>;
+    fn url(&self, key: &str) -> String;
+}
+
+//Local filesystem storage ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub struct LocalBlobStore {
+    pub root: PathBuf,
+    pub publicurl: String,
+}
+
+#[async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>, _contenttype: &str) -> Result<String, BlobError> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(&path, bytes).await?;
+        Ok(self.url(key))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(BlobError::Io(e)),
+        }
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.publicurl.trim_end_matches('/'), key)
+    }
+}
+
+//S3 compatible storage ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Path-style requests signed with AWS SigV4, so it works against AWS S3 as
+// well as MinIO, R2 and other S3 compatible endpoints.
+pub struct S3BlobStore {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub accesskey: String,
+    pub secretkey: String,
+    pub publicurl: String,
+    pub client: reqwest::Client,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn uriencode(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl S3BlobStore {
+    async fn send(&self, method: reqwest::Method, key: &str, bytes: Vec<u8>, contenttype: Option<&str>) -> Result<(), BlobError> {
+        let path = format!("/{}/{}", self.bucket, uriencode(key));
+        let url = reqwest::Url::parse(&format!("{}{}", self.endpoint.trim_end_matches('/'), path))
+            .map_err(|e| BlobError::Http(e.to_string()))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => return Err(BlobError::Http("S3 endpoint has no host".to_string())),
+        };
+
+        let now = Utc::now();
+        let amzdate = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let datestamp = now.format("%Y%m%d").to_string();
+        let payloadhash = hex::encode(Sha256::digest(&bytes));
+        let signedheaders = "host;x-amz-content-sha256;x-amz-date";
+        let canonicalrequest = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method.as_str(), path, host, payloadhash, amzdate, signedheaders, payloadhash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", datestamp, self.region);
+        let stringtosign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amzdate, scope, hex::encode(Sha256::digest(canonicalrequest.as_bytes()))
+        );
+        let datekey = hmac(format!("AWS4{}", self.secretkey).as_bytes(), &datestamp);
+        let regionkey = hmac(&datekey, &self.region);
+        let servicekey = hmac(&regionkey, "s3");
+        let signingkey = hmac(&servicekey, "aws4_request");
+        let signature = hex::encode(hmac(&signingkey, &stringtosign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.accesskey, scope, signedheaders, signature
+        );
+
+        let mut request = self.client.request(method, url)
+            .header("x-amz-date", amzdate)
+            .header("x-amz-content-sha256", payloadhash)
+            .header("Authorization", authorization);
+        if let Some(contenttype) = contenttype {
+            request = request.header("Content-Type", contenttype);
+        }
+        let response = request.body(bytes).send().await.map_err(|e| BlobError::Http(e.to_string()))?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(BlobError::Http(format!("S3 responded with {}", response.status())))
+        }
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, key: &str, bytes: Vec<u8>, contenttype: &str) -> Result<String, BlobError> {
+        self.send(reqwest::Method::PUT, key, bytes, Some(contenttype)).await?;
+        Ok(self.url(key))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), BlobError> {
+        self.send(reqwest::Method::DELETE, key, Vec::new(), None).await
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.publicurl.trim_end_matches('/'), key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> LocalBlobStore {
+        LocalBlobStore {
+            root: std::env::temp_dir().join(format!("blobstore-{}", uuid::Uuid::new_v4())),
+            publicurl: "http://localhost:10000/uploads/".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn local_put_writes_the_file_and_returns_its_url() {
+        let store = store();
+        let url = store.put("products/a/b/thumb.jpg", vec![1, 2, 3], "image/jpeg").await.unwrap();
+        assert_eq!(url, "http://localhost:10000/uploads/products/a/b/thumb.jpg");
+        assert_eq!(tokio::fs::read(store.root.join("products/a/b/thumb.jpg")).await.unwrap(), vec![1, 2, 3]);
+        tokio::fs::remove_dir_all(&store.root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_delete_removes_the_file_and_ignores_missing_ones() {
+        let store = store();
+        store.put("products/a/b/large.webp", vec![4, 5], "image/webp").await.unwrap();
+        store.delete("products/a/b/large.webp").await.unwrap();
+        assert!(!store.root.join("products/a/b/large.webp").exists());
+        store.delete("products/a/b/large.webp").await.unwrap();
+        tokio::fs::remove_dir_all(&store.root).await.unwrap();
+    }
+}
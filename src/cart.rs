@@ -0,0 +1,244 @@
+use bigdecimal::BigDecimal;
+use serde::Serialize;
+use sqlx::{self, Executor, FromRow, Pool, Postgres, Transaction};
+use uuid::Uuid;
+use crate::{inventory::{HELD_PRODUCT_SQL, HELD_VARIANT_SQL}, pricing::parseprice};
+
+// Cart lines are keyed on variantid, with NULL standing in for "no variant"
+pub const CART_LINE_CONFLICT: &str = "(cartid, productid, (COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid)))";
+
+pub enum CartOwner {
+    User(Uuid),
+    Guest(String)
+}
+
+#[derive(FromRow, Debug)]
+
+struct CartRow {
+    cartitemid: Uuid,
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    sku: String,
+    prodname: String,
+    category: String,
+    taxclass: String,
+    weightgrams: i32,
+    image: Option<String>,
+    quantity: i32,
+    price: String,
+    addedprice: String,
+    status: String,
+    availabletosell: i64
+}
+
+#[derive(Serialize, Debug)]
+
+pub struct CartLine {
+    pub cartitemid: Uuid,
+    pub productid: Uuid,
+    pub variantid: Option<Uuid>,
+    pub sku: String,
+    pub prodname: String,
+    pub category: String,
+    pub taxclass: String,
+    pub weightgrams: i32,
+    pub image: Option<String>,
+    pub quantity: i32,
+    pub price: BigDecimal,
+    pub previousprice: Option<BigDecimal>,
+    pub linetotal: BigDecimal,
+    pub availabletosell: i64,
+    pub issues: Vec<&'static str>
+}
+
+#[derive(Serialize, Debug)]
+
+pub struct Cart {
+    pub cartid: Option<Uuid>,
+    pub items: Vec<CartLine>,
+    pub subtotal: BigDecimal,
+    pub weightgrams: i64,
+    pub valid: bool
+}
+
+impl Cart {
+    pub fn empty() -> Cart {
+        Cart { cartid: None, items: Vec::new(), subtotal: BigDecimal::from(0), weightgrams: 0, valid: false }
+    }
+}
+
+pub async fn findcart(db: &Pool<Postgres>, owner: &CartOwner) -> Result<Option<Uuid>, sqlx::Error> {
+    let cart = match owner {
+        CartOwner::User(userid) => sqlx::query_as::<_, (Uuid,)>("SELECT cartid FROM carts WHERE userid = $1")
+            .bind(userid)
+            .fetch_optional(db)
+            .await?,
+        CartOwner::Guest(token) => sqlx::query_as::<_, (Uuid,)>("SELECT cartid FROM carts WHERE guesttoken = $1")
+            .bind(token)
+            .fetch_optional(db)
+            .await?,
+    };
+    Ok(cart.map(|(cartid,)| cartid))
+}
+
+pub async fn findorcreatecart(tx: &mut Transaction<'_, Postgres>, owner: &CartOwner) -> Result<Uuid, sqlx::Error> {
+    let cartid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    let (cartid,) = match owner {
+        CartOwner::User(userid) => sqlx::query_as::<_, (Uuid,)>(
+            "INSERT INTO carts (cartid, userid) VALUES ($1, $2)
+            ON CONFLICT (userid) DO UPDATE SET updated_at = now()
+            RETURNING cartid")
+            .bind(cartid)
+            .bind(userid)
+            .fetch_one(&mut *tx)
+            .await?,
+        CartOwner::Guest(token) => sqlx::query_as::<_, (Uuid,)>(
+            "INSERT INTO carts (cartid, guesttoken) VALUES ($1, $2)
+            ON CONFLICT (guesttoken) DO UPDATE SET updated_at = now()
+            RETURNING cartid")
+            .bind(cartid)
+            .bind(token)
+            .fetch_one(&mut *tx)
+            .await?,
+    };
+    Ok(cartid)
+}
+
+// Opens a session for the cart unless one is already open
+pub async fn startsession(tx: &mut Transaction<'_, Postgres>, cartid: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO cartsessions (sessionid, cartid) VALUES ($1, $2)
+        ON CONFLICT (cartid) WHERE checkedout_at IS NULL DO NOTHING")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(cartid)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+// Reads the cart against current prices, product status and available-to-sell.
+// Nothing is changed here; callers decide what to do with the issues found.
+pub async fn loadcart<'e, E: Executor<'e, Database = Postgres>>(executor: E, cartid: Uuid) -> Result<Cart, sqlx::Error> {
+    let rows = sqlx::query_as::<_, CartRow>(&format!(
+        "SELECT cartitems.cartitemid, cartitems.productid, cartitems.variantid, cartitems.quantity, cartitems.addedprice,
+        COALESCE(productvariants.sku, products.prodsku) AS sku,
+        products.prodname, products.category, products.taxclass, products.weightgrams, products.status,
+        COALESCE(productvariants.price, products.price) AS price,
+        CASE WHEN cartitems.variantid IS NULL
+            THEN products.availableqty - {}
+            ELSE productvariants.availableqty - {}
+        END AS availabletosell,
+        cover.image
+        FROM cartitems
+        INNER JOIN products ON products.productid = cartitems.productid
+        LEFT JOIN productvariants ON productvariants.variantid = cartitems.variantid
+        LEFT JOIN LATERAL (
+            SELECT COALESCE(thumbnail, original) AS image FROM productgallery
+            WHERE productgallery.productid = cartitems.productid
+            ORDER BY (productgallery.variantid IS NOT DISTINCT FROM cartitems.variantid) DESC, position LIMIT 1
+        ) cover ON true
+        WHERE cartitems.cartid = $1
+        ORDER BY cartitems.created_at", HELD_PRODUCT_SQL, HELD_VARIANT_SQL))
+        .bind(cartid)
+        .fetch_all(executor)
+        .await?;
+    let mut subtotal = BigDecimal::from(0);
+    let mut weightgrams = 0;
+    let items: Vec<CartLine> = rows.into_iter().map(|row| {
+        let price = parseprice(&row.price);
+        let addedprice = parseprice(&row.addedprice);
+        let pricechanged = price.is_some() && price != addedprice;
+        let mut issues = Vec::new();
+        // A price that can't be read can't be sold until it is corrected
+        if row.status != "active" || price.is_none() {
+            issues.push("unavailable");
+        } else if row.availabletosell <= 0 {
+            issues.push("outofstock");
+        } else if row.quantity as i64 > row.availabletosell {
+            issues.push("insufficientstock");
+        }
+        if pricechanged {
+            issues.push("pricechanged");
+        }
+        let price = price.unwrap_or_else(|| BigDecimal::from(0));
+        let linetotal = &price * BigDecimal::from(row.quantity);
+        subtotal += &linetotal;
+        weightgrams += row.weightgrams as i64 * row.quantity as i64;
+        CartLine {
+            cartitemid: row.cartitemid,
+            productid: row.productid,
+            variantid: row.variantid,
+            sku: row.sku,
+            prodname: row.prodname,
+            category: row.category,
+            taxclass: row.taxclass,
+            weightgrams: row.weightgrams,
+            image: row.image,
+            quantity: row.quantity,
+            previousprice: if pricechanged { addedprice } else { None },
+            price,
+            linetotal,
+            availabletosell: row.availabletosell.max(0),
+            issues
+        }
+    }).collect();
+    let valid = !items.is_empty() && items.iter().all(|line| line.issues.is_empty());
+    Ok(Cart { cartid: Some(cartid), items, subtotal, weightgrams, valid })
+}
+
+// Accepts the current price of every line, once the customer has confirmed the changes
+pub async fn acknowledgeprices(db: &Pool<Postgres>, cartid: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE cartitems SET addedprice = current.price
+        FROM (
+            SELECT cartitems.cartitemid, COALESCE(productvariants.price, products.price) AS price
+            FROM cartitems
+            INNER JOIN products ON products.productid = cartitems.productid
+            LEFT JOIN productvariants ON productvariants.variantid = cartitems.variantid
+            WHERE cartitems.cartid = $1
+        ) current
+        WHERE cartitems.cartitemid = current.cartitemid AND cartitems.addedprice <> current.price")
+        .bind(cartid)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+// Moves every line of the guest cart into the user's cart, adding quantities
+// where both carts hold the same line, then drops the guest cart.
+pub async fn mergeguestcart(db: &Pool<Postgres>, guesttoken: &str, userid: Uuid) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let guestcart = sqlx::query_as::<_, (Uuid,)>("SELECT cartid FROM carts WHERE guesttoken = $1 FOR UPDATE")
+        .bind(guesttoken)
+        .fetch_optional(&mut tx)
+        .await?;
+    let guestcart = match guestcart {
+        Some((cartid,)) => cartid,
+        None => return Ok(()),
+    };
+    let usercart = findorcreatecart(&mut tx, &CartOwner::User(userid)).await?;
+    sqlx::query(&format!(
+        "INSERT INTO cartitems (cartitemid, cartid, productid, variantid, quantity, addedprice, created_at)
+        SELECT gen_random_uuid(), $1, productid, variantid, quantity, addedprice, created_at
+        FROM cartitems WHERE cartid = $2
+        ON CONFLICT {} DO UPDATE SET quantity = cartitems.quantity + EXCLUDED.quantity", CART_LINE_CONFLICT))
+        .bind(usercart)
+        .bind(guestcart)
+        .execute(&mut tx)
+        .await?;
+    // The guest's session carries over unless the user already has one open
+    sqlx::query(
+        "UPDATE cartsessions SET cartid = $1
+        WHERE cartid = $2 AND checkedout_at IS NULL
+        AND NOT EXISTS (SELECT 1 FROM cartsessions WHERE cartid = $1 AND checkedout_at IS NULL)")
+        .bind(usercart)
+        .bind(guestcart)
+        .execute(&mut tx)
+        .await?;
+    sqlx::query("DELETE FROM carts WHERE cartid = $1")
+        .bind(guestcart)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
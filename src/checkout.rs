@@ -0,0 +1,194 @@
+use bigdecimal::BigDecimal;
+use serde::Deserialize;
+use sqlx::{self, FromRow, Postgres, Transaction};
+use uuid::Uuid;
+use crate::{cart::{self, Cart}, discounts::{self, DiscountError}, inventory::{self, ReservationError}, pricing::{self, DiscountKind, PricedLine, TaxClass}, shipping::{self, ShippingError}, webhooks};
+
+#[derive(Debug)]
+
+pub enum CheckoutError {
+    Empty,
+    Unavailable { productid: Uuid, variantid: Option<Uuid> },
+    PriceChanged { productid: Uuid, variantid: Option<Uuid> },
+    Insufficient { productid: Uuid, variantid: Option<Uuid>, available: i64 },
+    Discount(DiscountError),
+    Shipping(ShippingError),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for CheckoutError {
+    fn from(e: sqlx::Error) -> Self {
+        CheckoutError::Database(e)
+    }
+}
+
+impl From<DiscountError> for CheckoutError {
+    fn from(e: DiscountError) -> Self {
+        match e {
+            DiscountError::Database(e) => CheckoutError::Database(e),
+            e => CheckoutError::Discount(e),
+        }
+    }
+}
+
+impl From<ShippingError> for CheckoutError {
+    fn from(e: ShippingError) -> Self {
+        match e {
+            ShippingError::Database(e) => CheckoutError::Database(e),
+            e => CheckoutError::Shipping(e),
+        }
+    }
+}
+
+pub enum Customer {
+    User(Uuid),
+    Guest(Uuid)
+}
+
+impl Customer {
+    // (userid, guestid) as stored on orders and redemptions
+    pub fn ids(&self) -> (Option<Uuid>, Option<Uuid>) {
+        match self {
+            Customer::User(userid) => (Some(*userid), None),
+            Customer::Guest(guestid) => (None, Some(*guestid)),
+        }
+    }
+}
+
+#[derive(Deserialize, FromRow, Debug)]
+
+pub struct ShippingAddress {
+    pub fullname: String,
+    pub address: String,
+    pub city: String,
+    pub postcode: String
+}
+
+#[derive(Debug)]
+
+pub struct PlacedOrder {
+    pub orderid: i64,
+    pub subtotal: BigDecimal,
+    pub discounttotal: BigDecimal,
+    pub shippingcost: BigDecimal,
+    pub nettotal: BigDecimal,
+    pub taxtotal: BigDecimal,
+    pub total: BigDecimal
+}
+
+// Turns a cart into a pending order: prices are taken from the catalogue, every
+// line is reserved and the cart is emptied. Runs inside the caller's transaction
+// so a failure on any line leaves the cart untouched.
+pub async fn placeorder(tx: &mut Transaction<'_, Postgres>, cartid: Uuid, customer: &Customer, shipto: &ShippingAddress, shippingmethodid: Uuid, discountcode: Option<&str>, pricesincludevat: bool) -> Result<PlacedOrder, CheckoutError> {
+    let Cart { items, subtotal, weightgrams, .. } = cart::loadcart(&mut *tx, cartid).await?;
+    if items.is_empty() {
+        return Err(CheckoutError::Empty)
+    }
+    if let Some(line) = items.iter().find(|line| line.issues.contains(&"unavailable")) {
+        return Err(CheckoutError::Unavailable { productid: line.productid, variantid: line.variantid })
+    }
+    // The customer has to confirm a new price before paying it
+    if let Some(line) = items.iter().find(|line| line.issues.contains(&"pricechanged")) {
+        return Err(CheckoutError::PriceChanged { productid: line.productid, variantid: line.variantid })
+    }
+    let mut lines: Vec<PricedLine> = items.iter().map(|line| PricedLine {
+        productid: line.productid,
+        category: line.category.clone(),
+        linetotal: line.linetotal.clone(),
+        discount: BigDecimal::from(0),
+        taxclass: TaxClass::from_str(&line.taxclass).unwrap_or(TaxClass::Standard),
+        netamount: BigDecimal::from(0),
+        taxamount: BigDecimal::from(0)
+    }).collect();
+    let discount = match discountcode.map(str::trim).filter(|code| !code.is_empty()) {
+        Some(code) => {
+            let discount = discounts::loadforcheckout(tx, code, customer, &subtotal).await?;
+            let rule = discount.rule();
+            if !lines.iter().any(|line| rule.applies(line)) {
+                return Err(CheckoutError::Discount(DiscountError::NotApplicable))
+            }
+            let amount = pricing::allocatediscount(&rule, &mut lines);
+            Some((discount, rule.kind, amount))
+        },
+        None => None,
+    };
+    let discounttotal = discount.as_ref().map_or_else(|| BigDecimal::from(0), |(_, _, amount)| amount.clone());
+    let freeshipping = matches!(discount, Some((_, DiscountKind::FreeShipping, _)));
+    let shipping = shipping::quote(tx, shippingmethodid, &shipto.postcode, &(&subtotal - &discounttotal), weightgrams).await?;
+    let shippingcost = if freeshipping { BigDecimal::from(0) } else { shipping.cost };
+    // Delivery follows the standard rate
+    let (shippingnet, shippingtax) = pricing::taxon(&shippingcost, TaxClass::Standard, pricesincludevat);
+    let (nettotal, taxtotal) = pricing::applytax(&mut lines, pricesincludevat);
+    let (nettotal, taxtotal) = (nettotal + &shippingnet, taxtotal + &shippingtax);
+    let total = if pricesincludevat { &subtotal - &discounttotal + &shippingcost } else { &nettotal + &taxtotal };
+    let (userid, guestid) = customer.ids();
+    let (orderid,) = sqlx::query_as::<_, (i64,)>(
+        "INSERT INTO orderdet (total, subtotal, discounttotal, discountcode, freeshipping, userid, guestid, shipname, shipaddress, shipcity, shippostcode, created_at,
+        pricesincludevat, nettotal, taxtotal, shippingmethodid, shippingmethod, shippingcost, shippingtax)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19) RETURNING orderid")
+        .bind(&total)
+        .bind(&subtotal)
+        .bind(&discounttotal)
+        .bind(discount.as_ref().map(|(discount, _, _)| discount.code.clone()))
+        .bind(freeshipping)
+        .bind(userid)
+        .bind(guestid)
+        .bind(shipto.fullname.trim())
+        .bind(shipto.address.trim())
+        .bind(shipto.city.trim())
+        .bind(shipto.postcode.trim().to_uppercase())
+        .bind(chrono::Utc::now())
+        .bind(pricesincludevat)
+        .bind(&nettotal)
+        .bind(&taxtotal)
+        .bind(shipping.methodid)
+        .bind(&shipping.name)
+        .bind(&shippingcost)
+        .bind(&shippingtax)
+        .fetch_one(&mut *tx)
+        .await?;
+    for (line, priced) in items.iter().zip(&lines) {
+        match inventory::reserve(tx, orderid, line.productid, line.variantid, line.quantity).await {
+            Ok(_) => {},
+            Err(ReservationError::Insufficient { available }) => return Err(CheckoutError::Insufficient {
+                productid: line.productid,
+                variantid: line.variantid,
+                available: available.max(0)
+            }),
+            Err(ReservationError::NotFound) => return Err(CheckoutError::Unavailable { productid: line.productid, variantid: line.variantid }),
+            Err(ReservationError::Database(e)) => return Err(CheckoutError::Database(e)),
+        }
+        sqlx::query(
+            "INSERT INTO listitems (productid, variantid, orderidretr, quantity, discount, taxclass, taxrate, netamount, taxamount, unitprice, prodname, sku, image)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)")
+            .bind(line.productid)
+            .bind(line.variantid)
+            .bind(orderid)
+            .bind(line.quantity)
+            .bind(&priced.discount)
+            .bind(priced.taxclass.as_str())
+            .bind(priced.taxclass.rate())
+            .bind(&priced.netamount)
+            .bind(&priced.taxamount)
+            .bind(&line.price)
+            .bind(&line.prodname)
+            .bind(&line.sku)
+            .bind(&line.image)
+            .execute(&mut *tx)
+            .await?;
+    }
+    if let Some((discount, _, amount)) = &discount {
+        discounts::recordredemption(tx, discount.codeid, orderid, customer, amount).await?;
+    }
+    sqlx::query("DELETE FROM cartitems WHERE cartid = $1")
+        .bind(cartid)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE cartsessions SET orderid = $2, checkedout_at = now() WHERE cartid = $1 AND checkedout_at IS NULL")
+        .bind(cartid)
+        .bind(orderid)
+        .execute(&mut *tx)
+        .await?;
+    webhooks::orderevent(&mut *tx, "order.created", orderid).await?;
+    Ok(PlacedOrder { orderid, subtotal, discounttotal, shippingcost, nettotal, taxtotal, total })
+}
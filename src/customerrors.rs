@@ -1,7 +1,9 @@
 
 
 use axum::{http::StatusCode, response::IntoResponse, Json};
-use serde_json::json;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::mware::{ClaimsAccessToken, Role};
 
 #[derive(Debug)]
 
@@ -26,3 +28,34 @@ impl IntoResponse for CustomErrors {
             
     }
 }
+
+pub fn badrequest(message: &str) -> (StatusCode, Json<Value>) {
+    (StatusCode::BAD_REQUEST, Json(json!({
+        "status": "error",
+        "message": message,
+    })))
+}
+
+// Users may only act on their own records unless they are an admin. Records
+// without an owner, such as guest orders, are for admins only.
+pub fn ownsoradmin(sub: Uuid, role: &str, owner: Option<Uuid>) -> bool {
+    owner == Some(sub) || Role::from_str(role) == Role::Admin
+}
+
+pub fn forbidden(claims: &ClaimsAccessToken, userid: Uuid, message: &str) -> Option<(StatusCode, Json<Value>)> {
+    if ownsoradmin(claims.sub, &claims.role, Some(userid)) {
+        return None
+    }
+    Some((StatusCode::FORBIDDEN, Json(json!({
+        "status": "error",
+        "message": message,
+    }))))
+}
+
+pub fn servererror(e: impl ToString) -> (StatusCode, Json<Value>) {
+    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+        "status": "error",
+        "message": "Something went wrong",
+        "error": e.to_string(),
+    })))
+}
@@ -0,0 +1,124 @@
+use bigdecimal::BigDecimal;
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::{self, FromRow, Postgres, Transaction};
+use uuid::Uuid;
+use crate::{checkout::Customer, pricing::{DiscountKind, DiscountRule}};
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct DiscountCode {
+    pub codeid: Uuid,
+    pub code: String,
+    pub kind: String,
+    pub value: BigDecimal,
+    pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub maxuses: Option<i32>,
+    pub maxusespercustomer: Option<i32>,
+    pub minbasket: Option<BigDecimal>,
+    pub productids: Vec<Uuid>,
+    pub categories: Vec<String>,
+    pub active: bool,
+    pub created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Debug)]
+
+pub enum DiscountError {
+    NotFound,
+    NotActive,
+    UsedUp,
+    CustomerLimit,
+    MinimumNotMet { minbasket: BigDecimal },
+    NotApplicable,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for DiscountError {
+    fn from(e: sqlx::Error) -> Self {
+        DiscountError::Database(e)
+    }
+}
+
+impl DiscountError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            DiscountError::NotFound => "Discount code not found",
+            DiscountError::NotActive => "Discount code is not valid at the moment",
+            DiscountError::UsedUp => "Discount code has been fully redeemed",
+            DiscountError::CustomerLimit => "You have already used this discount code",
+            DiscountError::MinimumNotMet { .. } => "Basket is below the minimum for this discount code",
+            DiscountError::NotApplicable => "Discount code does not apply to anything in the basket",
+            DiscountError::Database(_) => "Something went wrong",
+        }
+    }
+}
+
+impl DiscountCode {
+    pub fn rule(&self) -> DiscountRule {
+        DiscountRule {
+            kind: DiscountKind::from_str(&self.kind).unwrap_or(DiscountKind::Fixed),
+            value: self.value.clone(),
+            productids: self.productids.clone(),
+            categories: self.categories.clone()
+        }
+    }
+}
+
+// Locks the code so concurrent checkouts cannot both take its last use, then
+// checks the validity window and the usage limits.
+pub async fn loadforcheckout(tx: &mut Transaction<'_, Postgres>, code: &str, customer: &Customer, subtotal: &BigDecimal) -> Result<DiscountCode, DiscountError> {
+    let discount = sqlx::query_as::<_, DiscountCode>(
+        "SELECT * FROM discountcodes WHERE upper(code) = upper($1) FOR UPDATE")
+        .bind(code.trim())
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(DiscountError::NotFound)?;
+    let now = Utc::now();
+    if !discount.active
+        || matches!(discount.starts_at, Some(starts_at) if starts_at > now)
+        || matches!(discount.ends_at, Some(ends_at) if ends_at <= now) {
+        return Err(DiscountError::NotActive)
+    }
+    if let Some(minbasket) = &discount.minbasket {
+        if subtotal < minbasket {
+            return Err(DiscountError::MinimumNotMet { minbasket: minbasket.clone() })
+        }
+    }
+    let (userid, guestid) = customer.ids();
+    let (uses, customeruses) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COUNT(*),
+        COUNT(*) FILTER (WHERE discountredemptions.userid = $2 OR discountredemptions.guestid = $3)
+        FROM discountredemptions
+        INNER JOIN orderdet ON orderdet.orderid = discountredemptions.orderid
+        WHERE discountredemptions.codeid = $1 AND orderdet.status NOT IN ('expired', 'cancelled')")
+        .bind(discount.codeid)
+        .bind(userid)
+        .bind(guestid)
+        .fetch_one(&mut *tx)
+        .await?;
+    if matches!(discount.maxuses, Some(maxuses) if uses >= maxuses as i64) {
+        return Err(DiscountError::UsedUp)
+    }
+    if matches!(discount.maxusespercustomer, Some(maxuses) if customeruses >= maxuses as i64) {
+        return Err(DiscountError::CustomerLimit)
+    }
+    Ok(discount)
+}
+
+pub async fn recordredemption(tx: &mut Transaction<'_, Postgres>, codeid: Uuid, orderid: i64, customer: &Customer, amount: &BigDecimal) -> Result<(), sqlx::Error> {
+    let (userid, guestid) = customer.ids();
+    sqlx::query(
+        "INSERT INTO discountredemptions (redemptionid, codeid, orderid, userid, guestid, amount)
+        VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(codeid)
+        .bind(orderid)
+        .bind(userid)
+        .bind(guestid)
+        .bind(amount)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
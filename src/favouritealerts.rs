@@ -0,0 +1,208 @@
+use bigdecimal::BigDecimal;
+use sqlx::{self, FromRow, Postgres, Transaction};
+use uuid::Uuid;
+use crate::{AppState, inventory::HELD_PRODUCT_SQL, mailer, pricing::pricesql};
+
+const DIGEST_CHECK_SECONDS: u64 = 600;
+
+// Price and available-to-sell of a product, read before and after an admin edit
+#[derive(FromRow, Debug)]
+
+pub struct ProductState {
+    price: Option<BigDecimal>,
+    availabletosell: i64,
+    status: String
+}
+
+#[derive(FromRow, Debug)]
+
+struct DigestUser {
+    userid: Uuid,
+    email: String,
+    fullname: String,
+    pricedrops: bool,
+    backinstock: bool,
+    unsubscribetoken: String
+}
+
+// An alert is only still worth sending if the product is live and the drop or
+// stock has not been undone since
+#[derive(FromRow, Debug)]
+
+struct PendingAlert {
+    alertid: Uuid,
+    productid: Uuid,
+    kind: String,
+    prodname: String,
+    oldprice: Option<BigDecimal>,
+    price: Option<BigDecimal>,
+    stillvalid: bool
+}
+
+pub async fn productstate(tx: &mut Transaction<'_, Postgres>, productid: Uuid) -> Result<Option<ProductState>, sqlx::Error> {
+    sqlx::query_as::<_, ProductState>(&format!(
+        "SELECT {} AS price, availableqty - {} AS availabletosell, status
+        FROM products WHERE productid = $1", pricesql("price"), HELD_PRODUCT_SQL))
+        .bind(productid)
+        .fetch_optional(&mut *tx)
+        .await
+}
+
+// Compares the product with how it was before the edit and queues an alert for
+// everyone who favourited it. Variants with their own price or stock are not
+// affected by a product edit.
+pub async fn queuealerts(tx: &mut Transaction<'_, Postgres>, productid: Uuid, before: &ProductState) -> Result<(), sqlx::Error> {
+    let after = match productstate(tx, productid).await? {
+        Some(after) if after.status == "active" => after,
+        _ => return Ok(()),
+    };
+    if let (Some(oldprice), Some(newprice)) = (&before.price, &after.price) {
+        if newprice < oldprice {
+            sqlx::query(
+                "INSERT INTO favouritealerts (alertid, userid, productid, variantid, kind, oldprice, newprice)
+                SELECT gen_random_uuid(), favourites.userid, favourites.productid, favourites.variantid, 'pricedrop', $2, $3
+                FROM favourites
+                LEFT JOIN productvariants ON productvariants.variantid = favourites.variantid
+                WHERE favourites.productid = $1 AND NULLIF(productvariants.price, '') IS NULL
+                ON CONFLICT (userid, productid, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid), kind)
+                WHERE sent_at IS NULL
+                DO UPDATE SET newprice = EXCLUDED.newprice")
+                .bind(productid)
+                .bind(oldprice)
+                .bind(newprice)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    if before.availabletosell <= 0 && after.availabletosell > 0 {
+        sqlx::query(
+            "INSERT INTO favouritealerts (alertid, userid, productid, kind, newprice)
+            SELECT gen_random_uuid(), userid, productid, 'backinstock', $2
+            FROM favourites
+            WHERE productid = $1 AND variantid IS NULL
+            ON CONFLICT (userid, productid, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid), kind)
+            WHERE sent_at IS NULL
+            DO NOTHING")
+            .bind(productid)
+            .bind(&after.price)
+            .execute(&mut *tx)
+            .await?;
+    }
+    Ok(())
+}
+
+// Creates the user's preferences with defaults if they have none yet
+pub async fn ensurepreferences<'e, E: sqlx::Executor<'e, Database = Postgres>>(executor: E, userid: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO notificationpreferences (userid, unsubscribetoken)
+        VALUES ($1, $2)
+        ON CONFLICT (userid) DO NOTHING")
+        .bind(userid)
+        .bind(format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple()))
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+fn digestbody(state: &AppState, user: &DigestUser, alerts: &[PendingAlert]) -> String {
+    let mut lines = Vec::new();
+    for alert in alerts {
+        let price = alert.price.as_ref().map(|price| format!("£{}", price.with_scale(2))).unwrap_or_default();
+        let line = match (alert.kind.as_str(), &alert.oldprice) {
+            ("pricedrop", Some(oldprice)) => format!("{} is down from £{} to {}", alert.prodname, oldprice.with_scale(2), price),
+            _ => format!("{} is back in stock at {}", alert.prodname, price),
+        };
+        lines.push(format!("- {}: {}/products/{}", line, state.mailer.siteurl, alert.productid));
+    }
+    format!(
+        "Hi {},\n\nSome of your favourites have changed:\n\n{}\n\nTo stop these emails: {}/api/v1/notifications/unsubscribe/{}\n",
+        user.fullname, lines.join("\n"), state.mailer.siteurl, user.unsubscribetoken
+    )
+}
+
+// Sends one user's pending alerts. Alerts the user has turned off or that no
+// longer apply are marked sent without being included. If the email fails the
+// alerts stay pending for the next digest.
+async fn senddigest(state: &AppState, user: &DigestUser) -> Result<(), sqlx::Error> {
+    let mut tx = state.database.db.begin().await?;
+    let alerts = sqlx::query_as::<_, PendingAlert>(&format!(
+        "SELECT favouritealerts.alertid, favouritealerts.productid, favouritealerts.kind, products.prodname, favouritealerts.oldprice,
+        current.price,
+        products.status = 'active'
+        AND EXISTS (SELECT 1 FROM favourites WHERE favourites.userid = favouritealerts.userid AND favourites.productid = favouritealerts.productid
+            AND favourites.variantid IS NOT DISTINCT FROM favouritealerts.variantid)
+        AND CASE WHEN favouritealerts.kind = 'pricedrop'
+            THEN current.price < favouritealerts.oldprice
+            ELSE products.availableqty - {} > 0 END AS stillvalid
+        FROM favouritealerts
+        INNER JOIN products ON products.productid = favouritealerts.productid
+        LEFT JOIN productvariants ON productvariants.variantid = favouritealerts.variantid
+        CROSS JOIN LATERAL (SELECT {} AS price) current
+        WHERE favouritealerts.userid = $1 AND favouritealerts.sent_at IS NULL
+        ORDER BY favouritealerts.created_at
+        FOR UPDATE OF favouritealerts SKIP LOCKED", HELD_PRODUCT_SQL, pricesql("COALESCE(productvariants.price, products.price)")))
+        .bind(user.userid)
+        .fetch_all(&mut tx)
+        .await?;
+    let alertids: Vec<Uuid> = alerts.iter().map(|alert| alert.alertid).collect();
+    sqlx::query("UPDATE favouritealerts SET sent_at = now() WHERE alertid = ANY($1)")
+        .bind(&alertids)
+        .execute(&mut tx)
+        .await?;
+    let included: Vec<PendingAlert> = alerts.into_iter()
+        .filter(|alert| alert.stillvalid && if alert.kind == "pricedrop" { user.pricedrops } else { user.backinstock })
+        .collect();
+    if !included.is_empty() {
+        let subject = if included.len() == 1 { format!("Update on {}", included[0].prodname) } else { format!("{} of your favourites have changed", included.len()) };
+        if let Err(e) = mailer::sendmail(&state.mailer, &user.email, &subject, digestbody(state, user, &included)).await {
+            println!("favourite alert digest to {} failed: {}", user.userid, e);
+            tx.rollback().await?;
+            return Ok(())
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Once a day after the digest hour (UTC), each user with pending alerts gets one
+// email. lastdigest_at makes this safe to check often and across restarts.
+async fn rundigest(state: &AppState, hour: u32) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO notificationpreferences (userid, unsubscribetoken)
+        SELECT DISTINCT userid, replace(gen_random_uuid()::text, '-', '') || replace(gen_random_uuid()::text, '-', '')
+        FROM favouritealerts WHERE sent_at IS NULL
+        ON CONFLICT (userid) DO NOTHING")
+        .execute(&state.database.db)
+        .await?;
+    let users = sqlx::query_as::<_, DigestUser>(
+        "WITH cutoff AS (SELECT date_trunc('day', now()) + make_interval(hours => $1) AS at)
+        UPDATE notificationpreferences SET lastdigest_at = now()
+        FROM users, cutoff
+        WHERE users.usid = notificationpreferences.userid
+        AND now() >= cutoff.at
+        AND (notificationpreferences.lastdigest_at IS NULL OR notificationpreferences.lastdigest_at < cutoff.at)
+        AND EXISTS (SELECT 1 FROM favouritealerts WHERE favouritealerts.userid = notificationpreferences.userid AND favouritealerts.sent_at IS NULL)
+        RETURNING notificationpreferences.userid, users.email, users.fullname, notificationpreferences.pricedrops,
+        notificationpreferences.backinstock, notificationpreferences.unsubscribetoken")
+        .bind(hour as i32)
+        .fetch_all(&state.database.db)
+        .await?;
+    for user in users {
+        if let Err(e) = senddigest(state, &user).await {
+            println!("favourite alert digest for {} failed: {:?}", user.userid, e);
+        }
+    }
+    Ok(())
+}
+
+pub fn spawndigest(state: AppState, hour: u32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(DIGEST_CHECK_SECONDS));
+        loop {
+            interval.tick().await;
+            if let Err(e) = rundigest(&state, hour.min(23)).await {
+                println!("favourite alert digest failed: {:?}", e);
+            }
+        }
+    });
+}
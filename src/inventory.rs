@@ -0,0 +1,253 @@
+use chrono::{Duration, Utc};
+use sqlx::{self, FromRow, Pool, Postgres, Transaction};
+use uuid::Uuid;
+
+pub const RESERVATION_TTL_MINUTES: i64 = 15;
+const SWEEP_INTERVAL_SECONDS: u64 = 60;
+
+// Quantity held by unexpired reservations, subtracted from availableqty to get
+// the available-to-sell figure. Used inline by product and variant queries.
+pub const HELD_PRODUCT_SQL: &str = "COALESCE((SELECT SUM(stockreservations.quantity) FROM stockreservations
+    WHERE stockreservations.productid = products.productid AND stockreservations.variantid IS NULL
+    AND stockreservations.status = 'held' AND stockreservations.expires_at > now()), 0)::BIGINT";
+pub const HELD_VARIANT_SQL: &str = "COALESCE((SELECT SUM(stockreservations.quantity) FROM stockreservations
+    WHERE stockreservations.variantid = productvariants.variantid
+    AND stockreservations.status = 'held' AND stockreservations.expires_at > now()), 0)::BIGINT";
+
+#[derive(Debug)]
+
+pub enum ReservationError {
+    Insufficient { available: i64 },
+    NotFound,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for ReservationError {
+    fn from(e: sqlx::Error) -> Self {
+        ReservationError::Database(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+
+pub enum StockReason {
+    Sale,
+    Restock,
+    Adjustment,
+    Return,
+}
+
+impl StockReason {
+    pub fn from_str(reason: &str) -> Option<StockReason> {
+        match reason {
+            "sale" => Some(StockReason::Sale),
+            "restock" => Some(StockReason::Restock),
+            "adjustment" => Some(StockReason::Adjustment),
+            "return" => Some(StockReason::Return),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StockReason::Sale => "sale",
+            StockReason::Restock => "restock",
+            StockReason::Adjustment => "adjustment",
+            StockReason::Return => "return",
+        }
+    }
+}
+
+pub struct StockMovement<'a> {
+    pub productid: Uuid,
+    pub variantid: Option<Uuid>,
+    pub reason: StockReason,
+    pub delta: i64,
+    pub actor: Option<Uuid>,
+    pub orderid: Option<i64>,
+    pub note: Option<&'a str>
+}
+
+#[derive(FromRow, Debug)]
+
+struct HeldStock {
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    quantity: i32
+}
+
+// Locks the product (or variant) row so concurrent checkouts are serialised,
+// then returns what can still be sold.
+pub async fn availabletosell(tx: &mut Transaction<'_, Postgres>, productid: Uuid, variantid: Option<Uuid>) -> Result<i64, ReservationError> {
+    let onhand = match variantid {
+        Some(variantid) => sqlx::query_as::<_, (i64,)>(
+            "SELECT productvariants.availableqty FROM productvariants
+            INNER JOIN products ON products.productid = productvariants.productid
+            WHERE productvariants.variantid = $1 AND productvariants.productid = $2 AND products.status = 'active'
+            FOR UPDATE OF productvariants")
+            .bind(variantid)
+            .bind(productid)
+            .fetch_optional(&mut *tx)
+            .await?,
+        None => sqlx::query_as::<_, (i64,)>(
+            "SELECT availableqty FROM products WHERE productid = $1 AND status = 'active' FOR UPDATE")
+            .bind(productid)
+            .fetch_optional(&mut *tx)
+            .await?,
+    };
+    // Draft and archived products cannot be sold
+    let (onhand,) = onhand.ok_or(ReservationError::NotFound)?;
+    let (held,) = sqlx::query_as::<_, (i64,)>(
+        "SELECT COALESCE(SUM(quantity), 0)::BIGINT FROM stockreservations
+        WHERE productid = $1 AND variantid IS NOT DISTINCT FROM $2
+        AND status = 'held' AND expires_at > now()")
+        .bind(productid)
+        .bind(variantid)
+        .fetch_one(&mut *tx)
+        .await?;
+    Ok(onhand - held)
+}
+
+pub async fn reserve(tx: &mut Transaction<'_, Postgres>, orderid: i64, productid: Uuid, variantid: Option<Uuid>, quantity: i32) -> Result<Uuid, ReservationError> {
+    let available = availabletosell(tx, productid, variantid).await?;
+    if available < quantity as i64 {
+        return Err(ReservationError::Insufficient { available })
+    }
+    let reservationid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    sqlx::query(
+        "INSERT INTO stockreservations (reservationid, orderid, productid, variantid, quantity, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(reservationid)
+        .bind(orderid)
+        .bind(productid)
+        .bind(variantid)
+        .bind(quantity)
+        .bind(Utc::now() + Duration::minutes(RESERVATION_TTL_MINUTES))
+        .execute(&mut *tx)
+        .await?;
+    Ok(reservationid)
+}
+
+// The only place availableqty is changed: writes the ledger row and applies the
+// delta in the same transaction. Returns the new on-hand quantity.
+pub async fn recordmovement(tx: &mut Transaction<'_, Postgres>, movement: StockMovement<'_>) -> Result<i64, sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO stock_movements (movementid, productid, variantid, reason, delta, actor, orderid, note, created_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(movement.productid)
+        .bind(movement.variantid)
+        .bind(movement.reason.as_str())
+        .bind(movement.delta)
+        .bind(movement.actor)
+        .bind(movement.orderid)
+        .bind(movement.note)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+    let (onhand,) = match movement.variantid {
+        Some(variantid) => sqlx::query_as::<_, (i64,)>(
+            "UPDATE productvariants SET availableqty = availableqty + $1 WHERE variantid = $2 RETURNING availableqty")
+            .bind(movement.delta)
+            .bind(variantid)
+            .fetch_one(&mut *tx)
+            .await?,
+        None => sqlx::query_as::<_, (i64,)>(
+            "UPDATE products SET availableqty = availableqty + $1 WHERE productid = $2 RETURNING availableqty")
+            .bind(movement.delta)
+            .bind(movement.productid)
+            .fetch_one(&mut *tx)
+            .await?,
+    };
+    Ok(onhand)
+}
+
+// Turns an order's reservations into committed stock. Reservations the sweeper
+// already released are committed too: the customer has paid for them.
+pub async fn commitreservations(tx: &mut Transaction<'_, Postgres>, orderid: i64) -> Result<Vec<Uuid>, sqlx::Error> {
+    let reservations = sqlx::query_as::<_, HeldStock>(
+        "UPDATE stockreservations SET status = 'committed', updated_at = now()
+        WHERE orderid = $1 AND status <> 'committed'
+        RETURNING productid, variantid, quantity")
+        .bind(orderid)
+        .fetch_all(&mut *tx)
+        .await?;
+    let productids = reservations.iter().map(|reservation| reservation.productid).collect();
+    for reservation in reservations {
+        recordmovement(tx, StockMovement {
+            productid: reservation.productid,
+            variantid: reservation.variantid,
+            reason: StockReason::Sale,
+            delta: -(reservation.quantity as i64),
+            actor: None,
+            orderid: Some(orderid),
+            note: None
+        }).await?;
+    }
+    Ok(productids)
+}
+
+// Reservations that were released or ran out before the order was paid no longer
+// hold anything, so the stock may have been sold since. True if every one of
+// them can still be met.
+pub async fn lapsedstillavailable(tx: &mut Transaction<'_, Postgres>, orderid: i64) -> Result<bool, sqlx::Error> {
+    let lapsed = sqlx::query_as::<_, HeldStock>(
+        "SELECT productid, variantid, SUM(quantity)::INT AS quantity FROM stockreservations
+        WHERE orderid = $1 AND (status = 'released' OR (status = 'held' AND expires_at <= now()))
+        GROUP BY productid, variantid")
+        .bind(orderid)
+        .fetch_all(&mut *tx)
+        .await?;
+    for reservation in lapsed {
+        match availabletosell(tx, reservation.productid, reservation.variantid).await {
+            Ok(available) if available >= reservation.quantity as i64 => {},
+            Ok(_) | Err(ReservationError::NotFound) | Err(ReservationError::Insufficient { .. }) => return Ok(false),
+            Err(ReservationError::Database(e)) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+pub async fn releasereservations(tx: &mut Transaction<'_, Postgres>, orderid: i64) -> Result<u64, sqlx::Error> {
+    let released = sqlx::query(
+        "UPDATE stockreservations SET status = 'released', updated_at = now()
+        WHERE orderid = $1 AND status = 'held'")
+        .bind(orderid)
+        .execute(&mut *tx)
+        .await?;
+    Ok(released.rows_affected())
+}
+
+pub async fn releaseexpired(db: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let released = sqlx::query_as::<_, (i64,)>(
+        "UPDATE stockreservations SET status = 'released', updated_at = now()
+        WHERE status = 'held' AND expires_at <= now()
+        RETURNING orderid")
+        .fetch_all(&mut tx)
+        .await?;
+    let orderids: Vec<i64> = released.into_iter().map(|(orderid,)| orderid).collect();
+    sqlx::query(
+        "UPDATE orderdet SET status = 'expired'
+        WHERE orderid = ANY($1) AND status = 'pending'
+        AND NOT EXISTS (SELECT 1 FROM stockreservations WHERE stockreservations.orderid = orderdet.orderid AND status = 'held')")
+        .bind(&orderids)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(orderids.len() as u64)
+}
+
+pub fn spawnsweeper(db: Pool<Postgres>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(SWEEP_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            match releaseexpired(&db).await {
+                Ok(0) => {},
+                Ok(released) => println!("released {} expired stock reservations", released),
+                Err(e) => println!("reservation sweep failed: {:?}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,330 @@
+use bigdecimal::BigDecimal;
+use printpdf::{BuiltinFont, IndirectFontRef, Line, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference, Point};
+use sqlx::{self, FromRow};
+use crate::{AppState, SellerSettings, pricing::{roundmoney, TaxClass}};
+
+#[derive(FromRow, Debug)]
+
+struct InvoiceOrder {
+    orderid: i64,
+    status: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    pricesincludevat: bool,
+    discounttotal: BigDecimal,
+    shippingmethod: Option<String>,
+    shippingcost: BigDecimal,
+    shippingtax: BigDecimal,
+    nettotal: Option<BigDecimal>,
+    taxtotal: BigDecimal,
+    total: BigDecimal,
+    email: Option<String>,
+    billname: Option<String>,
+    billaddress: Option<String>,
+    billcity: Option<String>,
+    billpostcode: Option<String>,
+    shipname: Option<String>,
+    shipaddress: Option<String>,
+    shipcity: Option<String>,
+    shippostcode: Option<String>
+}
+
+#[derive(FromRow, Debug)]
+
+struct InvoiceLine {
+    sku: String,
+    prodname: String,
+    quantity: i64,
+    discount: BigDecimal,
+    netamount: BigDecimal,
+    taxrate: BigDecimal,
+    taxamount: BigDecimal
+}
+
+#[derive(Debug)]
+
+pub enum InvoiceError {
+    NotFound,
+    NotInvoiceable,
+    Render(String),
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for InvoiceError {
+    fn from(e: sqlx::Error) -> Self {
+        InvoiceError::Database(e)
+    }
+}
+
+pub struct Invoice {
+    pub invoicenumber: i64,
+    pub pdf: Vec<u8>
+}
+
+pub fn invoicename(invoicenumber: i64) -> String {
+    format!("INV-{:06}", invoicenumber)
+}
+
+fn money(amount: &BigDecimal) -> String {
+    format!("£{}", roundmoney(amount).with_scale(2))
+}
+
+fn percent(rate: &BigDecimal) -> String {
+    format!("{}%", rate.with_scale(0))
+}
+
+fn addresslines(name: &Option<String>, address: &Option<String>, city: &Option<String>, postcode: &Option<String>) -> Vec<String> {
+    [name, address, city, postcode].iter()
+        .filter_map(|line| line.as_ref())
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+// Returns the stored invoice for an order, issuing the next number and rendering
+// the PDF the first time. The order row is locked so two first downloads cannot
+// both take a number.
+pub async fn orderinvoice(state: &AppState, orderid: i64) -> Result<Invoice, InvoiceError> {
+    let stored = sqlx::query_as::<_, (i64, Vec<u8>)>("SELECT invoicenumber, pdf FROM invoices WHERE orderid = $1")
+        .bind(orderid)
+        .fetch_optional(&state.database.db)
+        .await?;
+    if let Some((invoicenumber, pdf)) = stored {
+        return Ok(Invoice { invoicenumber, pdf })
+    }
+    let mut tx = state.database.db.begin().await?;
+    let order = sqlx::query_as::<_, InvoiceOrder>(
+        "SELECT orderdet.orderid, orderdet.status, orderdet.created_at, orderdet.pricesincludevat, orderdet.discounttotal,
+        orderdet.shippingmethod, orderdet.shippingcost, orderdet.shippingtax, orderdet.nettotal, orderdet.taxtotal, orderdet.total,
+        COALESCE(users.email, guestcustomers.email) AS email,
+        orderdet.billname, orderdet.billaddress, orderdet.billcity, orderdet.billpostcode,
+        orderdet.shipname, orderdet.shipaddress, orderdet.shipcity, orderdet.shippostcode
+        FROM orderdet
+        LEFT JOIN users ON users.usid = orderdet.userid
+        LEFT JOIN guestcustomers ON guestcustomers.guestid = orderdet.guestid
+        WHERE orderdet.orderid = $1
+        FOR UPDATE OF orderdet")
+        .bind(orderid)
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(InvoiceError::NotFound)?;
+    let stored = sqlx::query_as::<_, (i64, Vec<u8>)>("SELECT invoicenumber, pdf FROM invoices WHERE orderid = $1")
+        .bind(orderid)
+        .fetch_optional(&mut tx)
+        .await?;
+    if let Some((invoicenumber, pdf)) = stored {
+        tx.rollback().await?;
+        return Ok(Invoice { invoicenumber, pdf })
+    }
+    // Only paid orders placed with tax recorded can be invoiced
+    if matches!(order.status.as_str(), "pending" | "expired" | "cancelled") || order.nettotal.is_none() {
+        tx.rollback().await?;
+        return Err(InvoiceError::NotInvoiceable)
+    }
+    let lines = sqlx::query_as::<_, InvoiceLine>(
+        "SELECT COALESCE(listitems.sku, products.prodsku) AS sku, COALESCE(listitems.prodname, products.prodname) AS prodname,
+        listitems.quantity::BIGINT AS quantity, listitems.discount, listitems.netamount, listitems.taxrate, listitems.taxamount
+        FROM listitems
+        INNER JOIN products ON products.productid = listitems.productid
+        WHERE listitems.orderidretr = $1 AND listitems.taxrate IS NOT NULL
+        ORDER BY prodname, sku")
+        .bind(orderid)
+        .fetch_all(&mut tx)
+        .await?;
+    let (invoicenumber,) = sqlx::query_as::<_, (i64,)>(
+        "UPDATE invoicecounter SET lastnumber = lastnumber + 1 RETURNING lastnumber")
+        .fetch_one(&mut tx)
+        .await?;
+    let issued_at = chrono::Utc::now();
+    let pdf = renderinvoice(&state.seller, invoicenumber, issued_at, &order, &lines).map_err(InvoiceError::Render)?;
+    sqlx::query("INSERT INTO invoices (invoicenumber, orderid, issued_at, pdf) VALUES ($1, $2, $3, $4)")
+        .bind(invoicenumber)
+        .bind(orderid)
+        .bind(issued_at)
+        .bind(&pdf)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Invoice { invoicenumber, pdf })
+}
+
+
+// Page writer that moves down the page and starts a new one when it runs out of room
+struct InvoiceWriter {
+    doc: PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+    y: f32
+}
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 20.0;
+
+impl InvoiceWriter {
+    fn text(&self, x: f32, size: f32, bold: bool, text: &str) {
+        self.layer.use_text(text, size, Mm(x), Mm(self.y), if bold { &self.bold } else { &self.regular });
+    }
+
+    fn rule(&self) {
+        self.layer.add_line(Line {
+            points: vec![(Point::new(Mm(MARGIN), Mm(self.y)), false), (Point::new(Mm(PAGE_WIDTH - MARGIN), Mm(self.y)), false)],
+            is_closed: false
+        });
+    }
+
+    fn down(&mut self, height: f32) {
+        self.y -= height;
+        if self.y < MARGIN + 10.0 {
+            let (page, layer) = self.doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+            self.layer = self.doc.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT - MARGIN;
+        }
+    }
+}
+
+fn renderinvoice(seller: &SellerSettings, invoicenumber: i64, issued_at: chrono::DateTime<chrono::Utc>, order: &InvoiceOrder, lines: &[InvoiceLine]) -> Result<Vec<u8>, String> {
+    let title = invoicename(invoicenumber);
+    let (doc, page, layer) = PdfDocument::new(title.as_str(), Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Layer 1");
+    let regular = doc.add_builtin_font(BuiltinFont::Helvetica).map_err(|e| e.to_string())?;
+    let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold).map_err(|e| e.to_string())?;
+    let layer = doc.get_page(page).get_layer(layer);
+    let mut writer = InvoiceWriter { doc, layer, regular, bold, y: PAGE_HEIGHT - MARGIN };
+
+    // Heading with the seller on the right
+    writer.text(MARGIN, 18.0, true, "VAT INVOICE");
+    writer.text(125.0, 11.0, true, &seller.name);
+    let mut sellerlines = seller.address.clone();
+    if let Some(vatnumber) = &seller.vatnumber {
+        sellerlines.push(format!("VAT reg. no. {}", vatnumber));
+    }
+    if let Some(email) = &seller.email {
+        sellerlines.push(email.clone());
+    }
+    for line in &sellerlines {
+        writer.down(5.0);
+        writer.text(125.0, 9.0, false, line);
+    }
+    writer.down(12.0);
+    for (label, value) in [
+        ("Invoice number", title.clone()),
+        ("Invoice date", issued_at.format("%d %B %Y").to_string()),
+        ("Order number", order.orderid.to_string()),
+        ("Order date", order.created_at.format("%d %B %Y").to_string()),
+    ] {
+        writer.text(MARGIN, 9.0, true, label);
+        writer.text(55.0, 9.0, false, &value);
+        writer.down(5.0);
+    }
+
+    // Billing falls back to the delivery address when none was recorded
+    let mut billto = addresslines(&order.billname, &order.billaddress, &order.billcity, &order.billpostcode);
+    let shipto = addresslines(&order.shipname, &order.shipaddress, &order.shipcity, &order.shippostcode);
+    if billto.is_empty() {
+        billto = shipto.clone();
+    }
+    if let Some(email) = &order.email {
+        billto.push(email.clone());
+    }
+    writer.down(7.0);
+    writer.text(MARGIN, 10.0, true, "Bill to");
+    writer.text(110.0, 10.0, true, "Deliver to");
+    for index in 0..billto.len().max(shipto.len()) {
+        writer.down(5.0);
+        if let Some(line) = billto.get(index) {
+            writer.text(MARGIN, 9.0, false, line);
+        }
+        if let Some(line) = shipto.get(index) {
+            writer.text(110.0, 9.0, false, line);
+        }
+    }
+
+    // Line items. Amounts are after any discount on the line.
+    let columns = [("Item", MARGIN), ("Qty", 102.0), ("Discount", 114.0), ("Net", 135.0), ("VAT rate", 153.0), ("VAT", 170.0), ("Gross", 184.0)];
+    writer.down(12.0);
+    for (heading, x) in columns {
+        writer.text(x, 9.0, true, heading);
+    }
+    writer.down(2.5);
+    writer.rule();
+    for line in lines {
+        writer.down(6.0);
+        let item: String = format!("{} ({})", line.prodname, line.sku).chars().take(48).collect();
+        let values = [
+            item,
+            line.quantity.to_string(),
+            money(&line.discount),
+            money(&line.netamount),
+            percent(&line.taxrate),
+            money(&line.taxamount),
+            money(&(&line.netamount + &line.taxamount)),
+        ];
+        for ((_, x), value) in columns.iter().zip(values.iter()) {
+            writer.text(*x, 8.5, false, value);
+        }
+    }
+    let shippingnet = if order.pricesincludevat { &order.shippingcost - &order.shippingtax } else { order.shippingcost.clone() };
+    let zero = BigDecimal::from(0);
+    if let Some(method) = &order.shippingmethod {
+        writer.down(6.0);
+        let values = [
+            format!("Delivery: {}", method).chars().take(48).collect(),
+            "1".to_string(),
+            money(&zero),
+            money(&shippingnet),
+            percent(&TaxClass::Standard.rate()),
+            money(&order.shippingtax),
+            money(&(&shippingnet + &order.shippingtax)),
+        ];
+        for ((_, x), value) in columns.iter().zip(values.iter()) {
+            writer.text(*x, 8.5, false, value);
+        }
+    }
+    writer.down(3.0);
+    writer.rule();
+
+    // VAT breakdown per rate, delivery counted at the standard rate
+    let mut rates: Vec<(BigDecimal, BigDecimal, BigDecimal)> = Vec::new();
+    let shippingline = order.shippingmethod.as_ref().map(|_| (TaxClass::Standard.rate(), shippingnet.clone(), order.shippingtax.clone()));
+    for (rate, net, tax) in lines.iter().map(|line| (line.taxrate.clone(), line.netamount.clone(), line.taxamount.clone())).chain(shippingline) {
+        match rates.iter_mut().find(|(existing, _, _)| *existing == rate) {
+            Some((_, totalnet, totaltax)) => {
+                *totalnet += net;
+                *totaltax += tax;
+            },
+            None => rates.push((rate, net, tax)),
+        }
+    }
+    rates.sort_by(|a, b| b.0.cmp(&a.0));
+    writer.down(10.0);
+    writer.text(MARGIN, 10.0, true, "VAT summary");
+    writer.down(6.0);
+    for (heading, x) in [("Rate", MARGIN), ("Net", 50.0), ("VAT", 80.0)] {
+        writer.text(x, 9.0, true, heading);
+    }
+    for (rate, net, tax) in &rates {
+        writer.down(5.0);
+        writer.text(MARGIN, 9.0, false, &percent(rate));
+        writer.text(50.0, 9.0, false, &money(net));
+        writer.text(80.0, 9.0, false, &money(tax));
+    }
+
+    writer.down(10.0);
+    let nettotal = order.nettotal.clone().unwrap_or_else(|| zero.clone());
+    let mut totals = Vec::new();
+    if order.discounttotal > zero {
+        totals.push(("Discounts applied", money(&order.discounttotal)));
+    }
+    totals.push(("Total net", money(&nettotal)));
+    totals.push(("Total VAT", money(&order.taxtotal)));
+    totals.push(("Total", money(&order.total)));
+    for (label, value) in totals {
+        writer.text(135.0, 10.0, label == "Total", label);
+        writer.text(170.0, 10.0, label == "Total", &value);
+        writer.down(6.0);
+    }
+    writer.down(6.0);
+    writer.text(MARGIN, 8.0, false, if order.pricesincludevat { "Prices shown on the site include VAT." } else { "Prices shown on the site exclude VAT." });
+
+    writer.doc.save_to_bytes().map_err(|e| e.to_string())
+}
@@ -0,0 +1,266 @@
+use axum::http::HeaderMap;
+use serde_json::json;
+use sqlx::{self, Executor, FromRow, Postgres};
+use std::net::{IpAddr, SocketAddr};
+use uuid::Uuid;
+use crate::{AppState, LoginSettings, notifications};
+
+// Failures allowed before each further attempt has to wait, doubling up to MAX_DELAY_SECONDS
+const ACCOUNT_FREE_FAILURES: i32 = 2;
+const IP_FREE_FAILURES: i32 = 5;
+const MAX_DELAY_SECONDS: i64 = 60;
+
+#[derive(FromRow, Debug)]
+
+struct IpFailures {
+    failures: i32,
+    windowstart: chrono::DateTime<chrono::Utc>,
+    lastfailure_at: chrono::DateTime<chrono::Utc>,
+    blockeduntil: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(FromRow, Debug)]
+
+struct EmailFailures {
+    failedlogins: i32,
+    lastfailedlogin_at: Option<chrono::DateTime<chrono::Utc>>,
+    lockeduntil: Option<chrono::DateTime<chrono::Utc>>
+}
+
+// The connecting address or, behind TRUST_PROXY proxies, the X-Forwarded-For
+// entry added by the outermost of them. Entries to its left come from the client
+// and can say anything.
+pub fn clientip(settings: &LoginSettings, headers: &HeaderMap, addr: SocketAddr) -> String {
+    if settings.proxyhops == 0 {
+        return addr.ip().to_string()
+    }
+    let forwarded: Vec<&str> = headers.get_all("X-Forwarded-For").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    forwarded.len().checked_sub(settings.proxyhops)
+        .and_then(|index| forwarded[index].parse::<IpAddr>().ok())
+        .unwrap_or_else(|| addr.ip())
+        .to_string()
+}
+
+fn delayseconds(failures: i32, free: i32) -> i64 {
+    if failures <= free {
+        return 0
+    }
+    2_i64.pow((failures - free - 1).min(6) as u32).min(MAX_DELAY_SECONDS)
+}
+
+fn secondsuntil(at: chrono::DateTime<chrono::Utc>) -> i64 {
+    (at - chrono::Utc::now()).num_seconds().max(1)
+}
+
+// Seconds the account has to wait before it may try again, if any
+pub fn accountretryafter(settings: &LoginSettings, failedlogins: i32, lastfailedlogin_at: Option<chrono::DateTime<chrono::Utc>>, lockeduntil: Option<chrono::DateTime<chrono::Utc>>) -> Option<i64> {
+    let now = chrono::Utc::now();
+    if let Some(lockeduntil) = lockeduntil.filter(|lockeduntil| *lockeduntil > now) {
+        return Some(secondsuntil(lockeduntil))
+    }
+    let lastfailedlogin_at = lastfailedlogin_at.filter(|at| *at > now - chrono::Duration::minutes(settings.windowminutes))?;
+    let retryat = lastfailedlogin_at + chrono::Duration::seconds(delayseconds(failedlogins, ACCOUNT_FREE_FAILURES));
+    if retryat > now { Some(secondsuntil(retryat)) } else { None }
+}
+
+// The same for an email with no account, so the response doesn't give away
+// which emails are registered
+pub async fn emailretryafter(state: &AppState, email: &str) -> Result<Option<i64>, sqlx::Error> {
+    let failures = sqlx::query_as::<_, EmailFailures>(
+        "SELECT failedlogins, lastfailedlogin_at, lockeduntil FROM loginemailfailures WHERE email = lower($1)")
+        .bind(email)
+        .fetch_optional(&state.database.db)
+        .await?;
+    Ok(failures.and_then(|failures| accountretryafter(&state.login, failures.failedlogins, failures.lastfailedlogin_at, failures.lockeduntil)))
+}
+
+// Seconds the IP has to wait before it may try again, if any
+pub async fn ipretryafter(state: &AppState, ip: &str) -> Result<Option<i64>, sqlx::Error> {
+    let failures = sqlx::query_as::<_, IpFailures>(
+        "SELECT failures, windowstart, lastfailure_at, blockeduntil FROM loginipfailures WHERE ip = $1")
+        .bind(ip)
+        .fetch_optional(&state.database.db)
+        .await?;
+    let now = chrono::Utc::now();
+    let failures = match failures {
+        Some(failures) => failures,
+        None => return Ok(None),
+    };
+    if let Some(blockeduntil) = failures.blockeduntil.filter(|blockeduntil| *blockeduntil > now) {
+        return Ok(Some(secondsuntil(blockeduntil)))
+    }
+    if failures.windowstart < now - chrono::Duration::minutes(state.login.windowminutes) {
+        return Ok(None)
+    }
+    let retryat = failures.lastfailure_at + chrono::Duration::seconds(delayseconds(failures.failures, IP_FREE_FAILURES));
+    Ok(if retryat > now { Some(secondsuntil(retryat)) } else { None })
+}
+
+pub async fn recordevent<'e, E: Executor<'e, Database = Postgres>>(executor: E, userid: Option<Uuid>, kind: &str, ip: Option<&str>, actor: Option<Uuid>) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO securityevents (eventid, userid, kind, ip, actor) VALUES ($1, $2, $3, $4, $5)")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(userid)
+        .bind(kind)
+        .bind(ip)
+        .bind(actor)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+// Counts a failed sign-in against the IP and against the account, or the email
+// when it matched no account. Each locks once it reaches its limit.
+pub async fn recordfailure(state: &AppState, userid: Option<Uuid>, email: &str, ip: &str) -> Result<(), sqlx::Error> {
+    let settings = &state.login;
+    let (ipfailures,) = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO loginipfailures (ip, failures) VALUES ($1, 1)
+        ON CONFLICT (ip) DO UPDATE SET
+        failures = CASE WHEN loginipfailures.windowstart < now() - make_interval(mins => $2) THEN 1 ELSE loginipfailures.failures + 1 END,
+        windowstart = CASE WHEN loginipfailures.windowstart < now() - make_interval(mins => $2) THEN now() ELSE loginipfailures.windowstart END,
+        lastfailure_at = now()
+        RETURNING failures")
+        .bind(ip)
+        .bind(settings.windowminutes as i32)
+        .fetch_one(&state.database.db)
+        .await?;
+    if ipfailures >= settings.ipmaxfailures {
+        sqlx::query(
+            "UPDATE loginipfailures SET blockeduntil = now() + make_interval(mins => $2), failures = 0, windowstart = now()
+            WHERE ip = $1")
+            .bind(ip)
+            .bind(settings.lockoutminutes as i32)
+            .execute(&state.database.db)
+            .await?;
+        recordevent(&state.database.db, None, "iplockout", Some(ip), None).await?;
+        notifications::adminalert(
+            state,
+            format!("Sign-ins blocked from {}", ip),
+            format!("{} failed sign-ins from {} in {} minutes. Sign-ins from it are blocked for {} minutes.", ipfailures, ip, settings.windowminutes, settings.lockoutminutes),
+            json!({ "event": "security.iplockout", "ip": ip, "failures": ipfailures }),
+        );
+    }
+    let userid = match userid {
+        Some(userid) => userid,
+        None => return recordemailfailure(state, email).await,
+    };
+    let (failedlogins,) = sqlx::query_as::<_, (i32,)>(
+        "UPDATE users SET
+        failedlogins = CASE WHEN lastfailedlogin_at IS NULL OR lastfailedlogin_at < now() - make_interval(mins => $2) THEN 1 ELSE failedlogins + 1 END,
+        lastfailedlogin_at = now()
+        WHERE usid = $1
+        RETURNING failedlogins")
+        .bind(userid)
+        .bind(settings.windowminutes as i32)
+        .fetch_one(&state.database.db)
+        .await?;
+    if failedlogins >= settings.maxfailures {
+        sqlx::query("UPDATE users SET lockeduntil = now() + make_interval(mins => $2), failedlogins = 0 WHERE usid = $1")
+            .bind(userid)
+            .bind(settings.lockoutminutes as i32)
+            .execute(&state.database.db)
+            .await?;
+        recordevent(&state.database.db, Some(userid), "lockout", Some(ip), None).await?;
+        notifications::customeremail(state, userid, "Your account has been locked".to_string(), format!(
+            "There were {} failed attempts to sign in to your account, the last from {}. To protect it, signing in is blocked for {} minutes.\n\nIf this wasn't you, we recommend resetting your password.",
+            failedlogins, ip, settings.lockoutminutes));
+    }
+    Ok(())
+}
+
+// Unknown emails are locked without an email or security event, as there is no one to tell
+async fn recordemailfailure(state: &AppState, email: &str) -> Result<(), sqlx::Error> {
+    let settings = &state.login;
+    let (failedlogins,) = sqlx::query_as::<_, (i32,)>(
+        "INSERT INTO loginemailfailures (email, failedlogins, lastfailedlogin_at) VALUES (lower($1), 1, now())
+        ON CONFLICT (email) DO UPDATE SET
+        failedlogins = CASE WHEN loginemailfailures.lastfailedlogin_at < now() - make_interval(mins => $2) THEN 1 ELSE loginemailfailures.failedlogins + 1 END,
+        lastfailedlogin_at = now()
+        RETURNING failedlogins")
+        .bind(email)
+        .bind(settings.windowminutes as i32)
+        .fetch_one(&state.database.db)
+        .await?;
+    if failedlogins >= settings.maxfailures {
+        sqlx::query("UPDATE loginemailfailures SET lockeduntil = now() + make_interval(mins => $2), failedlogins = 0 WHERE email = lower($1)")
+            .bind(email)
+            .bind(settings.lockoutminutes as i32)
+            .execute(&state.database.db)
+            .await?;
+    }
+    Ok(())
+}
+
+// Clears the account's failures and remembers the IP. The first sign-in from an
+// IP after the account's very first one is recorded and emailed.
+pub async fn recordsuccess(state: &AppState, userid: Uuid, ip: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE users SET failedlogins = 0, lastfailedlogin_at = NULL WHERE usid = $1")
+        .bind(userid)
+        .execute(&state.database.db)
+        .await?;
+    let (known, seen) = sqlx::query_as::<_, (bool, bool)>(
+        "SELECT EXISTS (SELECT 1 FROM userloginips WHERE userid = $1 AND ip = $2),
+        EXISTS (SELECT 1 FROM userloginips WHERE userid = $1)")
+        .bind(userid)
+        .bind(ip)
+        .fetch_one(&state.database.db)
+        .await?;
+    sqlx::query(
+        "INSERT INTO userloginips (userid, ip) VALUES ($1, $2)
+        ON CONFLICT (userid, ip) DO UPDATE SET last_seen = now()")
+        .bind(userid)
+        .bind(ip)
+        .execute(&state.database.db)
+        .await?;
+    if !known && seen {
+        recordevent(&state.database.db, Some(userid), "newip", Some(ip), None).await?;
+        notifications::customeremail(state, userid, "New sign-in to your account".to_string(), format!(
+            "Your account was signed in to from a new IP address, {}, at {} UTC.\n\nIf this wasn't you, please reset your password straight away.",
+            ip, chrono::Utc::now().format("%Y-%m-%d %H:%M")));
+    }
+    Ok(())
+}
+
+// Returns false if there is no such user
+pub async fn unlock(state: &AppState, userid: Uuid, actor: Uuid) -> Result<bool, sqlx::Error> {
+    let unlocked = sqlx::query("UPDATE users SET lockeduntil = NULL, failedlogins = 0, lastfailedlogin_at = NULL WHERE usid = $1")
+        .bind(userid)
+        .execute(&state.database.db)
+        .await?;
+    if unlocked.rows_affected() == 0 {
+        return Ok(false)
+    }
+    recordevent(&state.database.db, Some(userid), "unlock", None, Some(actor)).await?;
+    notifications::customeremail(state, userid, "Your account has been unlocked".to_string(),
+        "Your account has been unlocked by our team and you can sign in again.".to_string());
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_failures_have_no_delay() {
+        assert_eq!(delayseconds(0, ACCOUNT_FREE_FAILURES), 0);
+        assert_eq!(delayseconds(ACCOUNT_FREE_FAILURES, ACCOUNT_FREE_FAILURES), 0);
+        assert_eq!(delayseconds(IP_FREE_FAILURES, IP_FREE_FAILURES), 0);
+    }
+
+    #[test]
+    fn delay_doubles_after_the_free_failures() {
+        assert_eq!(delayseconds(3, 2), 1);
+        assert_eq!(delayseconds(4, 2), 2);
+        assert_eq!(delayseconds(5, 2), 4);
+        assert_eq!(delayseconds(8, 2), 32);
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        assert_eq!(delayseconds(9, 2), MAX_DELAY_SECONDS);
+        assert_eq!(delayseconds(i32::MAX, 2), MAX_DELAY_SECONDS);
+    }
+}
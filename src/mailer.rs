@@ -0,0 +1,30 @@
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use crate::MailSettings;
+
+// Builds and sends a plain text email on a blocking thread so the SMTP round
+// trip does not stall the async runtime. When mail isn't configured the email
+// is logged and skipped.
+pub async fn sendmail(settings: &MailSettings, to: &str, subject: &str, body: String) -> Result<(), String> {
+    let settings = match &settings.smtp {
+        Some(settings) => settings,
+        None => {
+            println!("mail is not configured, skipped \"{}\" to {}", subject, to);
+            return Ok(())
+        }
+    };
+    let email = Message::builder()
+        .from(settings.from.parse().map_err(|e| format!("invalid sender: {:?}", e))?)
+        .to(to.parse().map_err(|e| format!("invalid recipient {}: {:?}", to, e))?)
+        .subject(subject)
+        .body(body)
+        .map_err(|e| e.to_string())?;
+    let creds = Credentials::new(settings.username.clone(), settings.password.clone());
+    let mailer = SmtpTransport::relay(&settings.relay)
+        .map_err(|e| e.to_string())?
+        .credentials(creds)
+        .build();
+    tokio::task::spawn_blocking(move || mailer.send(&email).map(|_| ()).map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| e.to_string())?
+}
@@ -1,15 +1,51 @@
-use axum::{routing::{get, post, put, delete},Router, middleware};
+use axum::{routing::{get, get_service, post, put, delete},Router, middleware, extract::DefaultBodyLimit};
 use dotenv::dotenv;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
-use tower_http::cors::CorsLayer;
+use tower_http::{cors::CorsLayer, services::ServeDir};
 use http::Method;
 mod routesuser;
 mod routesproduct;
 mod paymentapi;
 mod orderroutes;
+mod routesimages;
+mod routesvariants;
+mod blobstore;
+mod inventory;
+mod routesinventory;
+mod routesimport;
+mod routesreviews;
+mod cart;
+mod checkout;
+mod routescart;
+mod routesguest;
+mod pricing;
+mod discounts;
+mod routesdiscounts;
+mod shipping;
+mod routesshipping;
+mod routesaddresses;
+mod invoices;
+mod routesreturns;
+mod orderstatus;
+mod routesadminorders;
+mod reports;
+mod routesreports;
+mod orderevents;
+mod webhooks;
+mod routeswebhooks;
+mod routeswishlists;
+mod favouritealerts;
+mod routesnotifications;
+mod loginsecurity;
+mod routessecurity;
+mod mailer;
+mod notifications;
+use blobstore::{BlobStore, LocalBlobStore, S3BlobStore};
+use orderevents::OrderEvents;
+use std::sync::Arc;
 use tower_cookies::CookieManagerLayer;
 mod mware;
-use mware::{admin_auth_middleware, auth_middleware};
+use mware::{admin_auth_middleware, auth_middleware, optional_auth_middleware};
 mod customerrors;
 
 #[derive(Clone)]
@@ -19,7 +55,16 @@ pub struct AppState {
     pub refreshtoken: RefreshToken,
     pub passrecovertoken: PasswordRecoveryToken,
     pub stripetoken: StripeToken,
-    pub stripepubtoken: StripePublicToken
+    pub stripepubtoken: StripePublicToken,
+    pub stripewebhooksecret: StripeWebhookSecret,
+    pub blobstore: BlobStorage,
+    pub mailer: MailSettings,
+    pub alerts: AlertSettings,
+    pub tax: TaxSettings,
+    pub seller: SellerSettings,
+    pub reports: ReportSettings,
+    pub orderevents: OrderEvents,
+    pub login: LoginSettings
 }
 
 #[derive(Clone)]
@@ -46,6 +91,55 @@ pub struct StripeToken {
 pub struct StripePublicToken {
     pub stripepubtoken: String
 }
+#[derive(Clone)]
+pub struct StripeWebhookSecret {
+    pub stripewebhooksecret: Option<String>
+}
+#[derive(Clone)]
+pub struct BlobStorage {
+    pub store: Arc<dyn BlobStore>
+}
+#[derive(Clone)]
+pub struct MailSettings {
+    pub smtp: Option<SmtpSettings>,
+    pub siteurl: String
+}
+#[derive(Clone)]
+pub struct SmtpSettings {
+    pub relay: String,
+    pub username: String,
+    pub password: String,
+    pub from: String
+}
+#[derive(Clone)]
+pub struct AlertSettings {
+    pub email: Option<String>,
+    pub webhook: Option<String>
+}
+#[derive(Clone)]
+pub struct TaxSettings {
+    pub pricesincludevat: bool
+}
+#[derive(Clone)]
+pub struct ReportSettings {
+    pub materialized: bool,
+    pub refreshminutes: u64
+}
+#[derive(Clone)]
+pub struct LoginSettings {
+    pub maxfailures: i32,
+    pub ipmaxfailures: i32,
+    pub windowminutes: i64,
+    pub lockoutminutes: i64,
+    pub proxyhops: usize
+}
+#[derive(Clone)]
+pub struct SellerSettings {
+    pub name: String,
+    pub address: Vec<String>,
+    pub vatnumber: Option<String>,
+    pub email: Option<String>
+}
 
 
 
@@ -57,7 +151,71 @@ async fn main() {
     let refresh_token_secret: String = std::env::var("REFRESH_TOKEN_SECRET").expect("REFRESH_TOKEN_SECRET must be set");
     let stripe_token_secret: String = std::env::var("STRIPE_SECRET_KEY").expect("STRIPE_SECRET_KEY must be set");
     let stripe_public_secret: String = std::env::var("STRIPE_PUBLISH_KEY").expect("STRIPE_PUBLISH_KEY must be set");
+    // Without a webhook secret the app still runs; Stripe webhooks are refused
+    let stripe_webhook_secret: Option<String> = std::env::var("STRIPE_WEBHOOK_SECRET").ok();
+    if stripe_webhook_secret.is_none() {
+        println!("STRIPE_WEBHOOK_SECRET is not set, Stripe webhooks will be refused");
+    }
     let reset_passwprd_secret: String = std::env::var("RESET_PASSWORD_SECRET").expect("RESET_PASSWORD_SECRET must be set");
+    // Without SMTP details the app still runs; emails are logged and skipped
+    let smtp_settings = match (std::env::var("SMTP_USERNAME"), std::env::var("SMTP_PASSWORD"), std::env::var("MAIL_FROM")) {
+        (Ok(username), Ok(password), Ok(from)) => Some(SmtpSettings {
+            relay: std::env::var("SMTP_RELAY").unwrap_or_else(|_| "smtp.gmail.com".to_string()),
+            username,
+            password,
+            from,
+        }),
+        _ => {
+            println!("SMTP_USERNAME, SMTP_PASSWORD and MAIL_FROM are not all set, emails will not be sent");
+            None
+        }
+    };
+    let mail_settings = MailSettings {
+        smtp: smtp_settings,
+        siteurl: std::env::var("SITE_URL").unwrap_or_else(|_| "https://toystoreldn.shuttleapp.rs".to_string()),
+    };
+    let alert_settings = AlertSettings {
+        email: std::env::var("ADMIN_ALERT_EMAIL").ok(),
+        webhook: std::env::var("ADMIN_ALERT_WEBHOOK").ok(),
+    };
+    let tax_settings = TaxSettings {
+        pricesincludevat: std::env::var("PRICES_INCLUDE_VAT").map(|value| value != "false").unwrap_or(true),
+    };
+    let seller_settings = SellerSettings {
+        name: std::env::var("SELLER_NAME").unwrap_or_else(|_| "Toy Store London".to_string()),
+        address: std::env::var("SELLER_ADDRESS").unwrap_or_else(|_| "London, United Kingdom".to_string())
+            .split(',').map(|line| line.trim().to_string()).filter(|line| !line.is_empty()).collect(),
+        vatnumber: std::env::var("SELLER_VAT_NUMBER").ok(),
+        email: std::env::var("SELLER_EMAIL").ok(),
+    };
+    let report_settings = ReportSettings {
+        materialized: std::env::var("REPORTS_MATERIALIZED").map(|value| value == "true").unwrap_or(false),
+        refreshminutes: std::env::var("REPORTS_REFRESH_MINUTES").ok().and_then(|value| value.parse().ok()).unwrap_or(60),
+    };
+    let login_settings = LoginSettings {
+        maxfailures: std::env::var("LOGIN_MAX_FAILURES").ok().and_then(|value| value.parse().ok()).unwrap_or(5),
+        ipmaxfailures: std::env::var("LOGIN_IP_MAX_FAILURES").ok().and_then(|value| value.parse().ok()).unwrap_or(20),
+        windowminutes: std::env::var("LOGIN_WINDOW_MINUTES").ok().and_then(|value| value.parse().ok()).unwrap_or(15),
+        lockoutminutes: std::env::var("LOGIN_LOCKOUT_MINUTES").ok().and_then(|value| value.parse().ok()).unwrap_or(15),
+        // Number of proxies in front of the app; true is taken as one
+        proxyhops: std::env::var("TRUST_PROXY").ok().and_then(|value| if value == "true" { Some(1) } else { value.parse().ok() }).unwrap_or(0),
+    };
+    let upload_dir: String = std::env::var("UPLOAD_DIR").unwrap_or_else(|_| "./uploads".to_string());
+    let blobstore: Arc<dyn BlobStore> = match std::env::var("BLOB_BACKEND").as_deref() {
+        Ok("s3") => Arc::new(S3BlobStore {
+            endpoint: std::env::var("S3_ENDPOINT").expect("S3_ENDPOINT must be set"),
+            bucket: std::env::var("S3_BUCKET").expect("S3_BUCKET must be set"),
+            region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            accesskey: std::env::var("S3_ACCESS_KEY").expect("S3_ACCESS_KEY must be set"),
+            secretkey: std::env::var("S3_SECRET_KEY").expect("S3_SECRET_KEY must be set"),
+            publicurl: std::env::var("S3_PUBLIC_URL").expect("S3_PUBLIC_URL must be set"),
+            client: reqwest::Client::new(),
+        }),
+        _ => Arc::new(LocalBlobStore {
+            root: upload_dir.clone().into(),
+            publicurl: std::env::var("UPLOAD_PUBLIC_URL").unwrap_or_else(|_| "/uploads".to_string()),
+        }),
+    };
     let cors = CorsLayer::new()
     .allow_methods(vec![Method::GET, Method::POST, Method::OPTIONS])
     .allow_credentials(true);
@@ -66,30 +224,126 @@ async fn main() {
     .connect(&database_url)
     .await
     .expect("Failed to create pool");
+    sqlx::migrate!("./migrations")
+    .run(&pool)
+    .await
+    .expect("Failed to run migrations");
+    inventory::spawnsweeper(pool.clone());
+    let order_events = OrderEvents::new(256);
+    orderevents::spawnlistener(database_url.clone(), order_events.clone());
+    webhooks::spawndispatcher(pool.clone());
+    if report_settings.materialized {
+        reports::spawnrefresher(pool.clone(), report_settings.refreshminutes);
+    }
     let state = AppState { 
         database: Database { db: pool },
         accesstoken: AccessToken { accesstoken: access_token_secret },
         refreshtoken: RefreshToken { refreshtoken: refresh_token_secret },
         passrecovertoken: PasswordRecoveryToken { passrecovertoken: reset_passwprd_secret },
         stripetoken: StripeToken { stripetoken: stripe_token_secret },
-        stripepubtoken: StripePublicToken { stripepubtoken: stripe_public_secret }
+        stripepubtoken: StripePublicToken { stripepubtoken: stripe_public_secret },
+        stripewebhooksecret: StripeWebhookSecret { stripewebhooksecret: stripe_webhook_secret },
+        blobstore: BlobStorage { store: blobstore },
+        mailer: mail_settings,
+        alerts: alert_settings,
+        tax: tax_settings,
+        seller: seller_settings,
+        reports: report_settings,
+        orderevents: order_events,
+        login: login_settings
     };
+    let digest_hour: u32 = std::env::var("ALERT_DIGEST_HOUR").ok().and_then(|value| value.parse().ok()).unwrap_or(8);
+    favouritealerts::spawndigest(state.clone(), digest_hour);
     let app = Router::new()
     .route("/api/v1/products/:productid", delete(routesproduct::deleteproducthandler))
     .route("/api/v1/products/:productid", put(routesproduct::updateproducthandler))
+    .route("/api/v1/products/:productid/images", post(routesimages::uploadproductimages).layer(DefaultBodyLimit::max(20 * 1024 * 1024)))
+    .route("/api/v1/products/:productid/images", put(routesimages::reorderproductimages))
+    .route("/api/v1/products/:productid/images/:imageid", delete(routesimages::deleteproductimage))
+    .route("/api/v1/products/:productid/options", post(routesvariants::createoptionhandler))
+    .route("/api/v1/products/:productid/variants", post(routesvariants::createvarianthandler))
+    .route("/api/v1/products/:productid/variants/:variantid", put(routesvariants::updatevarianthandler))
+    .route("/api/v1/products/:productid/variants/:variantid", delete(routesvariants::deletevarianthandler))
+    .route("/api/v1/admin/products/:productid/stock", post(routesinventory::adjuststockhandler))
+    .route("/api/v1/admin/products/:productid/stock/movements", get(routesinventory::stockmovementshandler))
+    .route("/api/v1/admin/stock/reconcile", get(routesinventory::stockdiscrepancieshandler))
+    .route("/api/v1/admin/stock/reconcile", post(routesinventory::reconcilestockhandler))
+    .route("/api/v1/admin/products/import", post(routesimport::importproductshandler).layer(DefaultBodyLimit::max(20 * 1024 * 1024)))
+    .route("/api/v1/admin/products/export", get(routesimport::exportproductshandler))
+    .route("/api/v1/admin/products/archived", get(routesproduct::fetcharchivedproductshandler))
+    .route("/api/v1/admin/products/:productid/restore", post(routesproduct::restoreproducthandler))
+    .route("/api/v1/admin/reviews", get(routesreviews::fetchreviewqueuehandler))
+    .route("/api/v1/admin/reviews/:reviewid", put(routesreviews::moderatereviewhandler))
+    .route("/api/v1/admin/discounts", get(routesdiscounts::fetchdiscountshandler))
+    .route("/api/v1/admin/discounts", post(routesdiscounts::creatediscounthandler))
+    .route("/api/v1/admin/discounts/:codeid", put(routesdiscounts::updatediscounthandler))
+    .route("/api/v1/admin/shipping/methods", get(routesshipping::fetchshippingmethodshandler))
+    .route("/api/v1/admin/shipping/methods", post(routesshipping::createshippingmethodhandler))
+    .route("/api/v1/admin/shipping/methods/:methodid", put(routesshipping::updateshippingmethodhandler))
+    .route("/api/v1/admin/returns", get(routesreturns::fetchreturnshandler))
+    .route("/api/v1/admin/returns/:returnid/approve", post(routesreturns::approvereturnhandler))
+    .route("/api/v1/admin/returns/:returnid/reject", post(routesreturns::rejectreturnhandler))
+    .route("/api/v1/admin/returns/:returnid/receive", post(routesreturns::receivereturnhandler))
+    .route("/api/v1/admin/orders", get(routesadminorders::fetchadminordershandler))
+    .route("/api/v1/admin/orders/:orderid", get(routesadminorders::fetchadminorderhandler))
+    .route("/api/v1/admin/orders/:orderid/notes", post(routesadminorders::addordernotehandler))
+    .route("/api/v1/admin/orders/:orderid/status", put(routesadminorders::changeorderstatushandler))
+    .route("/api/v1/admin/orders/:orderid/ship", post(routesadminorders::shiporderhandler))
+    .route("/api/v1/admin/reports/revenue", get(routesreports::revenuereporthandler))
+    .route("/api/v1/admin/reports/products", get(routesreports::productsreporthandler))
+    .route("/api/v1/admin/reports/customers", get(routesreports::customersreporthandler))
+    .route("/api/v1/admin/reports/conversion", get(routesreports::conversionreporthandler))
+    .route("/api/v1/admin/reports/refresh", post(routesreports::refreshreportshandler))
+    .route("/api/v1/admin/webhooks", get(routeswebhooks::fetchwebhookshandler))
+    .route("/api/v1/admin/webhooks", post(routeswebhooks::createwebhookhandler))
+    .route("/api/v1/admin/webhooks/:webhookid", put(routeswebhooks::updatewebhookhandler))
+    .route("/api/v1/admin/webhooks/:webhookid", delete(routeswebhooks::deletewebhookhandler))
+    .route("/api/v1/admin/webhooks/:webhookid/deliveries", get(routeswebhooks::fetchdeliverieshandler))
+    .route("/api/v1/admin/webhooks/:webhookid/test", post(routeswebhooks::testwebhookhandler))
+    .route("/api/v1/admin/webhooks/deliveries/:deliveryid/redeliver", post(routeswebhooks::redeliverhandler))
+    .route("/api/v1/admin/users/:userid/unlock", post(routessecurity::unlockuserhandler))
+    .route("/api/v1/admin/security/events", get(routessecurity::fetchsecurityeventshandler))
     .route("/api/v1/users", get(routesuser::fetchusershandler))
     .route_layer(middleware::from_fn_with_state(state.clone(), admin_auth_middleware))
     .route("/api/v1/products/create-payment-intent", post(paymentapi::paymentintent))
-    .route("/api/v1/createorders", post(orderroutes::corder))
-    .route("/api/v1/createorders/items", post(orderroutes::createorderdetails)) 
+    .route("/api/v1/guest/orders/claim", post(routesguest::claimguestorderhandler))
     .route("/api/v1/users/:userid", put(routesuser::updateuserhandler))
     .route("/api/v1/users/:userid", get(routesuser::fetchsingleusershandler))
+    .route("/api/v1/users/:userid/addresses", get(routesaddresses::fetchaddresseshandler))
+    .route("/api/v1/users/:userid/notifications", get(routesnotifications::fetchpreferenceshandler))
+    .route("/api/v1/users/:userid/notifications", put(routesnotifications::updatepreferenceshandler))
+    .route("/api/v1/users/:userid/addresses", post(routesaddresses::createaddresshandler))
+    .route("/api/v1/users/:userid/addresses/:addrid", put(routesaddresses::updateaddresshandler))
+    .route("/api/v1/users/:userid/addresses/:addrid", delete(routesaddresses::deleteaddresshandler))
     .route("/api/v1/orders/:orderid", get(orderroutes::selectallorders))
     .route("/api/v1/orders/singleorder/:orderid", get(orderroutes::selectsingleorder))
-    .route("/api/v1/favourites/:userid/:productid", post(routesproduct::addfavouriteitems))
-    .route("/api/v1/favourites/:userid", get(routesproduct::fetchfavouriteitems))
-    .route("/api/v1/favourites/:userid/:productid", delete(routesproduct::deletefavorite))
+    .route("/api/v1/orders/:orderid/invoice.pdf", get(orderroutes::orderinvoicehandler))
+    .route("/api/v1/orders/:orderid/events", get(orderroutes::ordereventshandler))
+    .route("/api/v1/orders/:orderid/events/token", post(orderroutes::streamtokenhandler))
+    .route("/api/v1/orders/:orderid/returns", post(routesreturns::createreturnhandler))
+    .route("/api/v1/returns", get(routesreturns::fetchmyreturnshandler))
+    .route("/api/v1/favourites/:userid/:productid", post(routeswishlists::addfavouriteitems))
+    .route("/api/v1/favourites/:userid", get(routeswishlists::fetchfavouriteitems))
+    .route("/api/v1/favourites/:userid/:productid", delete(routeswishlists::deletefavorite))
+    .route("/api/v1/favourites/:userid/shares", get(routeswishlists::fetchshareshandler))
+    .route("/api/v1/favourites/:userid/shares", post(routeswishlists::createsharehandler))
+    .route("/api/v1/favourites/:userid/shares/:shareid", delete(routeswishlists::deletesharehandler))
+    .route("/api/v1/stocknotify/:userid/:productid", post(routesproduct::subscribebackinstock))
+    .route("/api/v1/stocknotify/:userid/:productid", delete(routesproduct::unsubscribebackinstock))
+    .route("/api/v1/products/:productid/reviews", post(routesreviews::createreviewhandler))
+    .route("/api/v1/reviews", get(routesreviews::fetchmyreviewshandler))
+    .route("/api/v1/reviews/:reviewid", put(routesreviews::updatereviewhandler))
+    .route("/api/v1/reviews/:reviewid", delete(routesreviews::deletereviewhandler))
+    .route("/api/v1/cart/checkout", post(routescart::checkouthandler))
     .route_layer(middleware::from_fn_with_state(state.clone(), auth_middleware))
+    .route("/api/v1/cart", get(routescart::fetchcarthandler))
+    .route("/api/v1/cart", delete(routescart::clearcarthandler))
+    .route("/api/v1/cart/items", post(routescart::additemhandler))
+    .route("/api/v1/cart/acknowledge-prices", post(routescart::acknowledgepriceshandler))
+    .route("/api/v1/cart/items/:cartitemid", put(routescart::updateitemhandler))
+    .route("/api/v1/cart/items/:cartitemid", delete(routescart::removeitemhandler))
+    .route("/api/v1/shipping/methods", get(routesshipping::shippingoptionshandler))
+    .route_layer(middleware::from_fn_with_state(state.clone(), optional_auth_middleware))
     //create middleware with  secret key
        
 
@@ -103,13 +357,26 @@ async fn main() {
 
     .route("/api/v1/products", get(routesproduct::fetchproductshandler))
     .route("/api/v1/products/:productid", get(routesproduct::fetchproducthandler))
+    .route("/api/v1/products/:productid/variants", get(routesvariants::fetchvariantshandler))
+    .route("/api/v1/products/:productid/reviews", get(routesreviews::fetchproductreviewshandler))
+    .route("/api/v1/wishlists/:token", get(routeswishlists::sharedwishlisthandler))
+    .route("/api/v1/notifications/unsubscribe/:token", get(routesnotifications::unsubscribehandler))
     .route("/api/v1/products/payment", post(paymentapi::pay))
+    .route("/api/v1/payments/webhook", post(paymentapi::stripewebhook))
+    .route("/api/v1/guest/checkout", post(routesguest::guestcheckouthandler))
+    .route("/api/v1/guest/create-payment-intent", post(paymentapi::guestpaymentintent))
+    .route("/api/v1/guest/orders/lookup", post(routesguest::guestorderlookuphandler))
+    .route("/api/v1/guest/orders/:token", get(routesguest::guestorderhandler))
+    .route("/api/v1/orders/:orderid/events/stream", get(orderroutes::orderstreamhandler))
     .route("/api/v1/users/refreshtoken", get(routesuser::refreshtokenhandler))
+    .nest_service("/uploads", get_service(ServeDir::new(upload_dir)).handle_error(|e: std::io::Error| async move {
+        (http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    }))
     .layer(cors)
     .layer(CookieManagerLayer::new())
     .with_state(state);
     axum::Server::bind(&"0.0.0.0:10000".parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>())
         .await
         .unwrap();
 }
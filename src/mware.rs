@@ -33,7 +33,7 @@ impl Role { pub fn from_str(role: &str) -> Role {
     Role::Admin => write!(f, "Admin"),
 }}}
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 
 pub struct ClaimsAccessToken { 
     pub sub: Uuid,
@@ -58,7 +58,7 @@ impl ClaimsAccessToken {
 
 pub async fn auth_middleware<B>(
     State(state): State<AppState>,
-    request: Request<B>,
+    mut request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, CustomErrors> 
 where
@@ -73,8 +73,10 @@ where
         let access_secret = &access_token_secret.as_bytes();
         let access_verify = jsonwebtoken::decode::<ClaimsAccessToken>(&authtoken, &DecodingKey::from_secret(access_secret), &validation);
         match access_verify {
-            Ok(_) => 
-                Ok(next.run(request).await),
+            Ok(claims) => {
+                request.extensions_mut().insert(claims.claims);
+                Ok(next.run(request).await)
+            }
             Err(e) => {
                 println!("access_verify: {:?}", e);
                 match e.kind() {
@@ -90,7 +92,7 @@ where
 
         pub async fn admin_auth_middleware<B>(
             State(state): State<AppState>,
-            request: Request<B>,
+            mut request: Request<B>,
             next: Next<B>,
         ) -> Result<Response, CustomErrors>
         where
@@ -109,6 +111,7 @@ where
                     let role = Role::from_str(&claims.claims.role);
                     
                     if role == Role::Admin {
+                        request.extensions_mut().insert(claims.claims);
                         Ok(next.run(request).await)
                     } else {
                         Err(CustomErrors::NotAuthorized)
@@ -127,3 +130,35 @@ where
             }}}}}
 
 
+
+
+        // For routes open to guests: claims are added when a valid token is sent,
+        // a missing token lets the request through without them.
+        pub async fn optional_auth_middleware<B>(
+            State(state): State<AppState>,
+            mut request: Request<B>,
+            next: Next<B>,
+        ) -> Result<Response, CustomErrors>
+        where
+            B: Send,
+        {
+            let auth = match request.headers().get("Authorization") {
+                Some(auth) => auth,
+                None => return Ok(next.run(request).await),
+            };
+            let token = auth.to_str().map_err(|_| CustomErrors::MissingCreds)?;
+            let authtoken = token.replace("Bearer ", "");
+            let validation = Validation::new(Algorithm::HS256);
+            let access_secret = state.accesstoken.accesstoken.as_bytes();
+            let access_verify = jsonwebtoken::decode::<ClaimsAccessToken>(&authtoken, &DecodingKey::from_secret(access_secret), &validation);
+            match access_verify {
+                Ok(claims) => {
+                    request.extensions_mut().insert(claims.claims);
+                    Ok(next.run(request).await)
+                }
+                Err(e) => {
+                    println!("access_verify: {:?}", e);
+                    match e.kind() {
+                        ErrorKind::InvalidToken => Err(CustomErrors::InvalidToken),
+                        _ => Err(CustomErrors::InvalidKey)
+            }}}}
@@ -0,0 +1,196 @@
+use serde::Serialize;
+use serde_json::json;
+use sqlx::{self, FromRow};
+use uuid::Uuid;
+use crate::{AppState, inventory::{HELD_PRODUCT_SQL, HELD_VARIANT_SQL}, mailer, webhooks};
+
+#[derive(Serialize, FromRow, Debug)]
+
+struct LowStock {
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    prodname: String,
+    prodsku: String,
+    availableqty: i64,
+    reorderthreshold: i64
+}
+
+#[derive(FromRow, Debug)]
+
+struct Subscriber {
+    email: String,
+    fullname: String,
+    prodname: String,
+    sku: Option<String>
+}
+
+// Sends an admin alert to whichever of email and webhook are configured.
+// Runs in the background; failures are logged and never reach the caller.
+pub fn adminalert(state: &AppState, subject: String, body: String, payload: serde_json::Value) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Some(email) = &state.alerts.email {
+            if let Err(e) = mailer::sendmail(&state.mailer, email, &subject, body).await {
+                println!("admin alert email failed: {}", e);
+            }
+        }
+        if let Some(webhook) = &state.alerts.webhook {
+            let response = reqwest::Client::new().post(webhook).json(&payload).send().await;
+            match response {
+                Ok(response) if !response.status().is_success() => println!("admin alert webhook responded with {}", response.status()),
+                Err(e) => println!("admin alert webhook failed: {}", e),
+                Ok(_) => {}
+            }
+        }
+    });
+}
+
+// Emails a registered customer in the background
+pub fn customeremail(state: &AppState, userid: Uuid, subject: String, body: String) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let customer = sqlx::query_as::<_, (String, String)>("SELECT email, fullname FROM users WHERE usid = $1")
+            .bind(userid)
+            .fetch_optional(&state.database.db)
+            .await;
+        match customer {
+            Ok(Some((email, fullname))) => {
+                let body = format!("Hi {},\n\n{}\n", fullname, body);
+                if let Err(e) = mailer::sendmail(&state.mailer, &email, &subject, body).await {
+                    println!("customer email to {} failed: {}", userid, e);
+                }
+            },
+            Ok(None) => {},
+            Err(e) => println!("customer email to {} failed: {}", userid, e),
+        }
+    });
+}
+
+// Emails whoever placed the order, registered or guest, in the background
+pub fn orderemail(state: &AppState, orderid: i64, subject: String, body: String) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let customer = sqlx::query_as::<_, (String, String)>(
+            "SELECT COALESCE(users.email, guestcustomers.email), COALESCE(users.fullname, guestcustomers.fullname)
+            FROM orderdet
+            LEFT JOIN users ON users.usid = orderdet.userid
+            LEFT JOIN guestcustomers ON guestcustomers.guestid = orderdet.guestid
+            WHERE orderdet.orderid = $1 AND COALESCE(users.email, guestcustomers.email) IS NOT NULL")
+            .bind(orderid)
+            .fetch_optional(&state.database.db)
+            .await;
+        match customer {
+            Ok(Some((email, fullname))) => {
+                let body = format!("Hi {},\n\n{}\n", fullname, body);
+                if let Err(e) = mailer::sendmail(&state.mailer, &email, &subject, body).await {
+                    println!("order email for {} failed: {}", orderid, e);
+                }
+            },
+            Ok(None) => {},
+            Err(e) => println!("order email for {} failed: {}", orderid, e),
+        }
+    });
+}
+
+// Called after an order has taken stock. Each product, or each variant of a
+// product sold through variants, alerts once until it is restocked above the
+// threshold again.
+pub async fn lowstockcheck(state: &AppState, productids: &[Uuid]) -> Result<(), sqlx::Error> {
+    let mut lowstock = sqlx::query_as::<_, LowStock>(
+        "UPDATE products SET lowstockalerted_at = now()
+        WHERE productid = ANY($1)
+        AND reorderthreshold IS NOT NULL
+        AND availableqty < reorderthreshold
+        AND lowstockalerted_at IS NULL
+        AND NOT EXISTS (SELECT 1 FROM productvariants WHERE productvariants.productid = products.productid)
+        RETURNING productid, NULL::uuid AS variantid, prodname, prodsku, availableqty, reorderthreshold")
+        .bind(productids)
+        .fetch_all(&state.database.db)
+        .await?;
+    lowstock.extend(sqlx::query_as::<_, LowStock>(&format!(
+        "UPDATE productvariants SET lowstockalerted_at = now()
+        FROM products
+        WHERE productvariants.productid = ANY($1)
+        AND products.productid = productvariants.productid
+        AND products.reorderthreshold IS NOT NULL
+        AND productvariants.availableqty - {held} < products.reorderthreshold
+        AND productvariants.lowstockalerted_at IS NULL
+        RETURNING productvariants.productid, productvariants.variantid, products.prodname, productvariants.sku AS prodsku,
+        productvariants.availableqty - {held} AS availableqty, products.reorderthreshold", held = HELD_VARIANT_SQL))
+        .bind(productids)
+        .fetch_all(&state.database.db)
+        .await?);
+    for product in lowstock {
+        webhooks::enqueue(&state.database.db, "stock.low", &json!({ "product": &product })).await?;
+        adminalert(
+            state,
+            format!("Low stock: {}", product.prodname),
+            format!(
+                "{} ({}) is down to {} units, below its reorder threshold of {}.",
+                product.prodname, product.prodsku, product.availableqty, product.reorderthreshold
+            ),
+            json!({ "event": "stock.low", "product": product }),
+        );
+    }
+    Ok(())
+}
+
+// Called after stock was added to a product or any of its variants. Re-arms
+// the low stock alerts and emails everyone waiting for the product, or for a
+// variant of it, to come back.
+pub async fn restockcheck(state: &AppState, productid: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE products SET lowstockalerted_at = NULL
+        WHERE productid = $1 AND lowstockalerted_at IS NOT NULL
+        AND availableqty >= COALESCE(reorderthreshold, 0)")
+        .bind(productid)
+        .execute(&state.database.db)
+        .await?;
+    sqlx::query(&format!(
+        "UPDATE productvariants SET lowstockalerted_at = NULL
+        FROM products
+        WHERE productvariants.productid = $1 AND productvariants.lowstockalerted_at IS NOT NULL
+        AND products.productid = productvariants.productid
+        AND productvariants.availableqty - {} >= COALESCE(products.reorderthreshold, 0)", HELD_VARIANT_SQL))
+        .bind(productid)
+        .execute(&state.database.db)
+        .await?;
+    // A product sold through variants is back when any of its variants is
+    let subscribers = sqlx::query_as::<_, Subscriber>(&format!(
+        "UPDATE stocksubscriptions SET notified_at = now()
+        FROM users, products
+        WHERE stocksubscriptions.productid = $1
+        AND stocksubscriptions.notified_at IS NULL
+        AND users.usid = stocksubscriptions.userid
+        AND products.productid = stocksubscriptions.productid
+        AND CASE
+            WHEN stocksubscriptions.variantid IS NOT NULL THEN EXISTS (SELECT 1 FROM productvariants
+                WHERE productvariants.variantid = stocksubscriptions.variantid AND productvariants.availableqty - {held_variant} > 0)
+            WHEN EXISTS (SELECT 1 FROM productvariants WHERE productvariants.productid = products.productid) THEN EXISTS (SELECT 1 FROM productvariants
+                WHERE productvariants.productid = products.productid AND productvariants.availableqty - {held_variant} > 0)
+            ELSE products.availableqty - {held_product} > 0
+        END
+        RETURNING users.email, users.fullname, products.prodname,
+        (SELECT sku FROM productvariants WHERE productvariants.variantid = stocksubscriptions.variantid) AS sku",
+        held_variant = HELD_VARIANT_SQL, held_product = HELD_PRODUCT_SQL))
+        .bind(productid)
+        .fetch_all(&state.database.db)
+        .await?;
+    for subscriber in subscribers {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let item = match &subscriber.sku {
+                Some(sku) => format!("{} ({})", subscriber.prodname, sku),
+                None => subscriber.prodname.clone(),
+            };
+            let body = format!(
+                "Hi {},\n\n{} is back in stock: {}/products/{}\n",
+                subscriber.fullname, item, state.mailer.siteurl, productid
+            );
+            if let Err(e) = mailer::sendmail(&state.mailer, &subscriber.email, &format!("{} is back in stock", item), body).await {
+                println!("back in stock email failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
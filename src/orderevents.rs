@@ -0,0 +1,66 @@
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tokio::sync::broadcast;
+
+pub const ORDER_EVENTS_CHANNEL: &str = "orderevents";
+const LISTENER_RETRY_SECONDS: u64 = 5;
+
+// event is payment, shipping or status from the database trigger, or snapshot
+// for the current state sent when a stream starts
+#[derive(Serialize, Deserialize, Clone, Debug)]
+
+pub struct OrderEvent {
+    pub orderid: i64,
+    pub event: String,
+    pub status: String,
+    pub previousstatus: Option<String>,
+    pub carrier: Option<String>,
+    pub trackingnumber: Option<String>,
+    pub at: chrono::DateTime<chrono::Utc>
+}
+
+// Fans notifications from this instance's listener out to its open streams
+#[derive(Clone)]
+pub struct OrderEvents {
+    sender: broadcast::Sender<OrderEvent>
+}
+
+impl OrderEvents {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        OrderEvents { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<OrderEvent> {
+        self.sender.subscribe()
+    }
+}
+
+// Holds its own connection rather than one from the pool, and reconnects if it drops
+pub fn spawnlistener(database_url: String, events: OrderEvents) {
+    tokio::spawn(async move {
+        loop {
+            let listener = match PgListener::connect(&database_url).await {
+                Ok(mut listener) => listener.listen(ORDER_EVENTS_CHANNEL).await.map(|_| listener),
+                Err(e) => Err(e),
+            };
+            match listener {
+                Ok(mut listener) => loop {
+                    match listener.recv().await {
+                        Ok(notification) => match serde_json::from_str::<OrderEvent>(notification.payload()) {
+                            // Sending only fails when no stream is open
+                            Ok(event) => { let _ = events.sender.send(event); },
+                            Err(e) => println!("order event could not be read: {}", e),
+                        },
+                        Err(e) => {
+                            println!("order event listener failed: {:?}", e);
+                            break;
+                        }
+                    }
+                },
+                Err(e) => println!("order event listener could not connect: {:?}", e),
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(LISTENER_RETRY_SECONDS)).await;
+        }
+    });
+}
@@ -1,128 +1,174 @@
-use axum_macros::debug_handler;
 use serde::{Deserialize, Serialize};
-use sqlx::{self, FromRow};
+use sqlx::{self, FromRow, Pool, Postgres};
 use uuid::Uuid;
-use crate::AppState;
-use axum::{Json, extract::{State, Path}, response::IntoResponse, http::StatusCode};
+use crate::{AppState, customerrors::{forbidden, ownsoradmin, servererror}, invoices::{self, InvoiceError}, mware::ClaimsAccessToken, orderevents::OrderEvent};
+use axum::{Json, Extension, extract::{State, Path, Query}, response::{IntoResponse, Response, sse::{Event, KeepAlive, Sse}}, http::{header, StatusCode}};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
 use serde_json::json;
 
-#[derive(Serialize, Deserialize, FromRow, Debug)]
+#[derive(Deserialize, Debug)]
 
-pub struct Orders {
-    total: f64,
-    userid: Uuid
+pub struct OrderHistoryQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>
 }
 
-#[derive(Debug, Serialize, FromRow, Deserialize)]
+#[derive(Debug, Serialize, FromRow)]
 
-pub struct OrderResponse {
-    orderid: i64
+pub struct OrderSummary {
+    orderid: i64,
+    created_at: chrono::DateTime<chrono::Utc>,
+    status: String,
+    paymentstatus: String,
+    shippingstatus: String,
+    itemcount: i64,
+    total: bigdecimal::BigDecimal,
+    thumbnail: Option<String>
 }
 
-#[derive(Debug, Serialize, FromRow, Deserialize)]
+#[derive(Debug, Serialize, FromRow)]
 
-pub struct OrderItems {
-    productid: Uuid,
-    orderidretr: i64,
-    quantity: i32
+pub struct OrderHeader {
+    orderid: i64,
+    created_at: chrono::DateTime<chrono::Utc>,
+    status: String,
+    paymentstatus: String,
+    shippingstatus: String,
+    itemcount: i64,
+    paid_at: Option<chrono::DateTime<chrono::Utc>>,
+    subtotal: Option<bigdecimal::BigDecimal>,
+    discountcode: Option<String>,
+    discounttotal: bigdecimal::BigDecimal,
+    shippingcost: bigdecimal::BigDecimal,
+    total: bigdecimal::BigDecimal,
+    refunded: bigdecimal::BigDecimal
 }
-#[derive(Debug, Serialize, FromRow, Deserialize)]
 
-pub struct OrderItemsResponse {
-    created_at: chrono::DateTime<chrono::Utc>,
+// unitprice is the price listed when the order was placed and linetotal what was
+// paid for the line after discount, including VAT
+#[derive(Debug, Serialize, FromRow)]
+
+pub struct OrderLine {
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    sku: String,
     prodname: String,
-    price: String,
-    proddescr: String,
-    imageone: String,
+    image: Option<String>,
     quantity: i64,
-    total: bigdecimal::BigDecimal
+    unitprice: Option<bigdecimal::BigDecimal>,
+    discount: bigdecimal::BigDecimal,
+    linetotal: Option<bigdecimal::BigDecimal>,
+    taxrate: Option<bigdecimal::BigDecimal>
 }
 
+#[derive(Debug, Serialize, FromRow)]
 
-#[debug_handler]
-pub async fn corder(State(state): State<AppState>, req: Json<Orders>) -> impl IntoResponse {
-    let created_at = chrono::Utc::now();
-    let response = sqlx::query_as::<_, OrderResponse>(
-        "INSERT INTO orderdet(total, userid, created_at) VALUES ($1, $2, $3) RETURNING orderid")
-        .bind(&req.total)
-        .bind(&req.userid)
-        .bind(&created_at)
-        .fetch_all(&state.database.db)
-        .await;
-    match response {
-        Ok(orderid) => (StatusCode::CREATED, Json(json!({
-            "response": orderid
-        }))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
-            "status": "error",
-            "message": "Something went wrong",
-            "error": e.to_string(),
-        }))),
-    }
+pub struct ShippingDetails {
+    shippingmethod: Option<String>,
+    shippingcost: bigdecimal::BigDecimal,
+    carrier: Option<String>,
+    trackingnumber: Option<String>,
+    shipped_at: Option<chrono::DateTime<chrono::Utc>>,
+    delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    shipname: Option<String>,
+    shipaddress: Option<String>,
+    shipcity: Option<String>,
+    shippostcode: Option<String>,
+    billname: Option<String>,
+    billaddress: Option<String>,
+    billcity: Option<String>,
+    billpostcode: Option<String>
 }
 
+#[derive(Debug, Serialize, FromRow)]
 
+pub struct VatRate {
+    taxclass: String,
+    taxrate: bigdecimal::BigDecimal,
+    netamount: bigdecimal::BigDecimal,
+    taxamount: bigdecimal::BigDecimal
+}
 
+#[derive(Debug, Serialize, FromRow)]
 
-#[debug_handler]
-pub async fn createorderdetails(State(state): State<AppState>, req: Json<OrderItems>) -> impl IntoResponse {
-    let mut tx = state.database.db.begin().await.unwrap();
-    let response = sqlx::query(
-        "INSERT INTO listitems(productid, orderidretr, quantity) VALUES ($1, $2, $3)")
-        .bind(&req.productid)
-        .bind(&req.orderidretr)
-        .bind(&req.quantity)
-        .execute(&mut tx)
-        .await;
-    match response {
-        Ok( _ ) => {
-            let response = sqlx::query("UPDATE products 
-            SET availableqty = availableqty - $1
-            WHERE productid = $2")
-            .bind(&req.quantity)
-            .bind(&req.productid)
-            .execute(&mut tx)
-            .await;
-            match response {
-                Ok(_) => {
-                    tx.commit().await.unwrap();
-                    (StatusCode::OK, Json(json!({
-                        "status": "Success",
-                        "message": "Order created successfully"
-                    })))
-                },
-                Err(e) => {
-                    tx.rollback().await.unwrap();
-                    (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
-                        "status": "error",
-                        "message": "Something went wrong",
-                        "error": e.to_string(),
-                    })))
-                }
-    }
+pub struct VatTotals {
+    pricesincludevat: bool,
+    discounttotal: bigdecimal::BigDecimal,
+    shippingcost: bigdecimal::BigDecimal,
+    shippingtax: bigdecimal::BigDecimal,
+    nettotal: Option<bigdecimal::BigDecimal>,
+    taxtotal: bigdecimal::BigDecimal,
+    total: bigdecimal::BigDecimal
 }
-        Err(e) => {
-            tx.rollback().await.unwrap();
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
-                "status": "error",
-                "message": "Something went wrong",
-                "error": e.to_string(),
-            })))
-        }
-    }
+
+#[derive(Debug, Serialize)]
+
+pub struct VatSummary {
+    #[serde(flatten)]
+    totals: VatTotals,
+    rates: Vec<VatRate>
+}
+
+// Payment and delivery progress as a customer sees it, derived from the order status
+const ORDER_PROGRESS_SQL: &str = "CASE
+        WHEN orderdet.status = 'refunded' THEN 'refunded'
+        WHEN orderdet.status IN ('paid', 'shipped', 'delivered') AND EXISTS (
+            SELECT 1 FROM returns WHERE returns.orderid = orderdet.orderid AND returns.refundstatus = 'succeeded') THEN 'partiallyrefunded'
+        WHEN orderdet.status IN ('paid', 'shipped', 'delivered') THEN 'paid'
+        WHEN orderdet.status = 'pending' THEN 'awaitingpayment'
+        ELSE 'unpaid' END AS paymentstatus,
+    CASE
+        WHEN orderdet.delivered_at IS NOT NULL THEN 'delivered'
+        WHEN orderdet.shipped_at IS NOT NULL THEN 'shipped'
+        WHEN orderdet.status = 'paid' THEN 'processing'
+        ELSE 'notshipped' END AS shippingstatus,
+    (SELECT COALESCE(SUM(quantity), 0) FROM listitems WHERE listitems.orderidretr = orderdet.orderid)::BIGINT AS itemcount";
+
+
+fn ordernotfound() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "status": "error",
+        "message": "Order not found",
+    })))
 }
 
 
-#[debug_handler]
-pub async fn selectallorders(State(state): State<AppState>, Path(usid): Path<Uuid>) -> impl IntoResponse {
-    let response = sqlx::query_as::<_,OrderResponse>(
-        "SELECT orderid FROM orderdet where userid = $1")
-        .bind(&usid)
+//Order history routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Newest first; the thumbnail is the cover image of the first line as the order
+// detail lists them, by product name then SKU
+pub async fn selectallorders(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(usid): Path<Uuid>, Query(query): Query<OrderHistoryQuery>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, usid, "You can only view your own orders") {
+        return response
+    }
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let offset = query.offset.unwrap_or(0).max(0);
+    let response = sqlx::query_as::<_, OrderSummary>(&format!(
+        "SELECT orderdet.orderid, orderdet.created_at, orderdet.status, orderdet.total, {},
+        (SELECT listitems.image FROM listitems
+            WHERE listitems.orderidretr = orderdet.orderid AND listitems.image IS NOT NULL
+            ORDER BY listitems.prodname, listitems.sku
+            LIMIT 1) AS thumbnail
+        FROM orderdet
+        WHERE orderdet.userid = $1
+        AND ($2::text IS NULL OR orderdet.status = $2)
+        ORDER BY orderdet.created_at DESC, orderdet.orderid DESC
+        LIMIT $3 OFFSET $4", ORDER_PROGRESS_SQL))
+        .bind(usid)
+        .bind(&query.status)
+        .bind(limit)
+        .bind(offset)
         .fetch_all(&state.database.db)
         .await;
     match response {
-        Ok(orderid) => (StatusCode::OK, Json(json!({
-            "response": orderid
+        Ok(orders) => (StatusCode::OK, Json(json!({
+            "response": orders,
+            "limit": limit,
+            "offset": offset
         }))),
         Err(e) => {
             (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
@@ -134,24 +180,91 @@ pub async fn selectallorders(State(state): State<AppState>, Path(usid): Path<Uui
     }
 }
 
-pub async fn selectsingleorder(State(state): State<AppState>, Path(orderid): Path<i64>) -> impl IntoResponse {
-    let response = sqlx::query_as::<_, OrderItemsResponse>(
-        "SELECT 
-        listitems.quantity, 
-        orderdet.created_at, orderdet.total,
-        products.prodname, products.price, products.proddescr,
-        productimages.imageone
+// Lines are read from the snapshot taken when they were ordered. Lines from
+// checkout also carry the amounts charged.
+pub async fn fetchorderitems(db: &Pool<Postgres>, orderid: i64) -> Result<Vec<OrderLine>, sqlx::Error> {
+    sqlx::query_as::<_, OrderLine>(
+        "SELECT
+        listitems.productid, listitems.variantid, listitems.quantity::BIGINT AS quantity, listitems.discount, listitems.taxrate,
+        COALESCE(listitems.sku, products.prodsku) AS sku, COALESCE(listitems.prodname, products.prodname) AS prodname,
+        listitems.image, listitems.unitprice,
+        COALESCE(listitems.netamount + listitems.taxamount, listitems.unitprice * listitems.quantity) AS linetotal
         FROM listitems
-        INNER JOIN orderdet ON listitems.orderidretr = orderdet.orderid
         INNER JOIN products ON listitems.productid = products.productid
-        INNER JOIN productimages ON products.prodsku = productimages.prodskuid
-        where orderid = $1")
-        .bind(&orderid)
-        .fetch_all(&state.database.db)
+        WHERE listitems.orderidretr = $1
+        ORDER BY prodname, sku")
+        .bind(orderid)
+        .fetch_all(db)
+        .await
+}
+
+// Orders placed before VAT was recorded have no nettotal and no rate lines
+pub async fn fetchvatsummary(db: &Pool<Postgres>, orderid: i64) -> Result<Option<VatSummary>, sqlx::Error> {
+    let totals = sqlx::query_as::<_, VatTotals>(
+        "SELECT pricesincludevat, discounttotal, shippingcost, shippingtax, nettotal, taxtotal, total FROM orderdet WHERE orderid = $1")
+        .bind(orderid)
+        .fetch_optional(db)
+        .await?;
+    let totals = match totals {
+        Some(totals) => totals,
+        None => return Ok(None),
+    };
+    let rates = sqlx::query_as::<_, VatRate>(
+        "SELECT taxclass, taxrate, SUM(netamount) AS netamount, SUM(taxamount) AS taxamount
+        FROM listitems
+        WHERE orderidretr = $1 AND taxrate IS NOT NULL
+        GROUP BY taxclass, taxrate
+        ORDER BY taxrate DESC")
+        .bind(orderid)
+        .fetch_all(db)
+        .await?;
+    Ok(Some(VatSummary { totals, rates }))
+}
+
+pub async fn selectsingleorder(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>) -> impl IntoResponse {
+    let db = &state.database.db;
+    let header = sqlx::query_as::<_, (Option<Uuid>,)>("SELECT userid FROM orderdet WHERE orderid = $1")
+        .bind(orderid)
+        .fetch_optional(db)
+        .await;
+    match header {
+        Ok(Some((userid,))) if ownsoradmin(claims.sub, &claims.role, userid) => {},
+        Ok(_) => return ordernotfound(),
+        Err(e) => return servererror(e),
+    }
+    let header = sqlx::query_as::<_, OrderHeader>(&format!(
+        "SELECT orderdet.orderid, orderdet.created_at, orderdet.status, orderdet.paid_at, orderdet.subtotal, orderdet.discountcode,
+        orderdet.discounttotal, orderdet.shippingcost, orderdet.total, {},
+        (SELECT COALESCE(SUM(refundamount), 0) FROM returns WHERE returns.orderid = orderdet.orderid AND returns.refundstatus = 'succeeded') AS refunded
+        FROM orderdet WHERE orderdet.orderid = $1", ORDER_PROGRESS_SQL))
+        .bind(orderid)
+        .fetch_one(db)
         .await;
+    let response = match header {
+        Ok(header) => fetchorderitems(db, orderid).await.map(|lines| (header, lines)),
+        Err(e) => Err(e),
+    };
+    let response = match response {
+        Ok((header, lines)) => fetchvatsummary(db, orderid).await.map(|vat| (header, lines, vat)),
+        Err(e) => Err(e),
+    };
+    let response = match response {
+        Ok((header, lines, vat)) => sqlx::query_as::<_, ShippingDetails>(
+            "SELECT shippingmethod, shippingcost, carrier, trackingnumber, shipped_at, delivered_at,
+            shipname, shipaddress, shipcity, shippostcode, billname, billaddress, billcity, billpostcode
+            FROM orderdet WHERE orderid = $1")
+            .bind(orderid)
+            .fetch_one(db)
+            .await
+            .map(|shipping| (header, lines, vat, shipping)),
+        Err(e) => Err(e),
+    };
     match response {
-        Ok(response) => (StatusCode::OK, Json(json!({
-            "response": response
+        Ok((header, lines, vat, shipping)) => (StatusCode::OK, Json(json!({
+            "order": header,
+            "lines": lines,
+            "shipping": shipping,
+            "vat": vat
         }))),
         Err(e) => {
             (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
@@ -161,4 +274,162 @@ pub async fn selectsingleorder(State(state): State<AppState>, Path(orderid): Pat
             })))
         }
     }
+}
+
+
+//Invoice route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn orderinvoicehandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>) -> Response {
+    let owner = sqlx::query_as::<_, (Option<Uuid>,)>("SELECT userid FROM orderdet WHERE orderid = $1")
+        .bind(orderid)
+        .fetch_optional(&state.database.db)
+        .await;
+    match owner {
+        Ok(Some((userid,))) if ownsoradmin(claims.sub, &claims.role, userid) => {},
+        Ok(_) => return ordernotfound().into_response(),
+        Err(e) => return servererror(e).into_response(),
+    }
+    match invoices::orderinvoice(&state, orderid).await {
+        Ok(invoice) => {
+            let filename = format!("attachment; filename=\"{}.pdf\"", invoices::invoicename(invoice.invoicenumber));
+            (StatusCode::OK, [(header::CONTENT_TYPE, "application/pdf".to_string()), (header::CONTENT_DISPOSITION, filename)], invoice.pdf).into_response()
+        },
+        Err(InvoiceError::NotFound) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Order not found",
+        }))).into_response(),
+        Err(InvoiceError::NotInvoiceable) => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "An invoice is only available once the order has been paid",
+        }))).into_response(),
+        Err(InvoiceError::Render(e)) => servererror(e).into_response(),
+        Err(InvoiceError::Database(e)) => servererror(e).into_response(),
+    }
+}
+
+
+//Order events route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+async fn ordersnapshot(db: &Pool<Postgres>, orderid: i64) -> Result<Option<(Option<Uuid>, OrderEvent)>, sqlx::Error> {
+    let order = sqlx::query_as::<_, (Option<Uuid>, String, Option<String>, Option<String>)>(
+        "SELECT userid, status, carrier, trackingnumber FROM orderdet WHERE orderid = $1")
+        .bind(orderid)
+        .fetch_optional(db)
+        .await?;
+    Ok(order.map(|(userid, status, carrier, trackingnumber)| (userid, OrderEvent {
+        orderid,
+        event: "snapshot".to_string(),
+        status,
+        previousstatus: None,
+        carrier,
+        trackingnumber,
+        at: chrono::Utc::now()
+    })))
+}
+
+// Starts with the order as it is now, then sends each change as it happens. The
+// stream ends at `until` so the client reconnects with fresh credentials.
+async fn orderstream(state: AppState, orderid: i64, sub: Uuid, role: &str, until: i64) -> Response {
+    // Subscribing before the snapshot is read means no change can fall in between
+    let receiver = state.orderevents.subscribe();
+    let db = state.database.db.clone();
+    let snapshot = match ordersnapshot(&db, orderid).await {
+        Ok(Some((userid, snapshot))) if ownsoradmin(sub, role, userid) => snapshot,
+        Ok(_) => return ordernotfound().into_response(),
+        Err(e) => return servererror(e).into_response(),
+    };
+    let remaining = (until - chrono::Utc::now().timestamp()).max(0) as u64;
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(remaining);
+    let stream = futures::stream::unfold((Some(snapshot), receiver, db), move |(pending, mut receiver, db)| async move {
+        if let Some(event) = pending {
+            return Some((Event::default().event(event.event.clone()).json_data(&event), (None, receiver, db)))
+        }
+        loop {
+            match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                Ok(Ok(event)) if event.orderid == orderid => {
+                    return Some((Event::default().event(event.event.clone()).json_data(&event), (None, receiver, db)))
+                },
+                Ok(Ok(_)) => continue,
+                // Events were dropped for a slow stream; send the current state instead
+                Ok(Err(RecvError::Lagged(_))) => match ordersnapshot(&db, orderid).await {
+                    Ok(Some((_, snapshot))) => return Some((Event::default().event("snapshot").json_data(&snapshot), (None, receiver, db))),
+                    _ => return None,
+                },
+                Ok(Err(RecvError::Closed)) | Err(_) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
+
+pub async fn ordereventshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>) -> Response {
+    orderstream(state, orderid, claims.sub, &claims.role, claims.exp).await
+}
+
+// Lets a browser EventSource, which cannot send an Authorization header, open
+// the stream of the one order it was issued for.
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct ClaimsStreamToken {
+    sub: Uuid,
+    role: String,
+    aud: String,
+    orderid: i64,
+    exp: i64,
+    until: i64
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct StreamQuery {
+    token: String
+}
+
+const STREAM_TOKEN_SECONDS: i64 = 60;
+const STREAM_TOKEN_AUDIENCE: &str = "orderstream";
+
+// Stream tokens are signed with a key derived from the access token secret, so
+// neither kind of token verifies as the other.
+fn streamtokenkey(state: &AppState) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(state.accesstoken.accesstoken.as_bytes()).expect("hmac accepts any key length");
+    mac.update(STREAM_TOKEN_AUDIENCE.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// The token has to be used within a minute. The stream it opens lasts as long as
+// the access token it was issued for.
+pub async fn streamtokenhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>) -> impl IntoResponse {
+    match ordersnapshot(&state.database.db, orderid).await {
+        Ok(Some((userid, _))) if ownsoradmin(claims.sub, &claims.role, userid) => {},
+        Ok(_) => return ordernotfound(),
+        Err(e) => return servererror(e),
+    }
+    let streamclaims = ClaimsStreamToken {
+        sub: claims.sub,
+        role: claims.role,
+        aud: STREAM_TOKEN_AUDIENCE.to_string(),
+        orderid,
+        exp: chrono::Utc::now().timestamp() + STREAM_TOKEN_SECONDS,
+        until: claims.exp
+    };
+    let token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &streamclaims, &EncodingKey::from_secret(&streamtokenkey(&state))).unwrap();
+    (StatusCode::OK, Json(json!({
+        "streamtoken": token,
+        "expiresin": STREAM_TOKEN_SECONDS
+    })))
+}
+
+pub async fn orderstreamhandler(State(state): State<AppState>, Path(orderid): Path<i64>, Query(query): Query<StreamQuery>) -> Response {
+    let mut validation = Validation::new(Algorithm::HS256);
+    validation.set_audience(&[STREAM_TOKEN_AUDIENCE]);
+    match jsonwebtoken::decode::<ClaimsStreamToken>(&query.token, &DecodingKey::from_secret(&streamtokenkey(&state)), &validation) {
+        Ok(token) if token.claims.orderid == orderid => {
+            let claims = token.claims;
+            orderstream(state, orderid, claims.sub, &claims.role, claims.until).await
+        },
+        _ => (StatusCode::UNAUTHORIZED, Json(json!({
+            "status": "error",
+            "message": "Invalid or expired stream token",
+        }))).into_response(),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,72 @@
+use sqlx::{self, Postgres, Transaction};
+use uuid::Uuid;
+use crate::{inventory, webhooks};
+
+#[derive(Debug)]
+
+pub enum StatusChangeError {
+    NotFound,
+    NotAllowed { from: String },
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for StatusChangeError {
+    fn from(e: sqlx::Error) -> Self {
+        StatusChangeError::Database(e)
+    }
+}
+
+pub const STATUSES: [&str; 7] = ["pending", "expired", "cancelled", "paid", "shipped", "delivered", "refunded"];
+
+// Payment moves orders from pending to paid; everything after that is done by hand.
+// Refunded is for refunds made outside the returns workflow; it refunds whatever
+// returns have not already.
+pub fn allowedtransition(from: &str, to: &str) -> bool {
+    matches!((from, to),
+        ("pending" | "expired", "cancelled")
+        | ("paid", "shipped")
+        | ("shipped", "delivered")
+        | ("paid" | "shipped" | "delivered", "refunded"))
+}
+
+// Locks the order, checks the move is allowed and records it. Returns the previous status.
+pub async fn changestatus(tx: &mut Transaction<'_, Postgres>, orderid: i64, to: &str, actor: Uuid, note: Option<&str>) -> Result<String, StatusChangeError> {
+    let (from,) = sqlx::query_as::<_, (String,)>("SELECT status FROM orderdet WHERE orderid = $1 FOR UPDATE")
+        .bind(orderid)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(StatusChangeError::NotFound)?;
+    if !allowedtransition(&from, to) {
+        return Err(StatusChangeError::NotAllowed { from })
+    }
+    sqlx::query(
+        "UPDATE orderdet SET status = $2,
+        shipped_at = CASE WHEN $2 = 'shipped' THEN now() ELSE shipped_at END,
+        delivered_at = CASE WHEN $2 = 'delivered' THEN now() ELSE delivered_at END,
+        refundamount = CASE WHEN $2 = 'refunded' THEN GREATEST(total - COALESCE((SELECT SUM(returns.refundamount) FROM returns
+            WHERE returns.orderid = $1 AND returns.refundstatus = 'succeeded'), 0), 0) ELSE refundamount END,
+        refunded_at = CASE WHEN $2 = 'refunded' THEN now() ELSE refunded_at END
+        WHERE orderid = $1")
+        .bind(orderid)
+        .bind(to)
+        .execute(&mut *tx)
+        .await?;
+    if to == "cancelled" {
+        inventory::releasereservations(tx, orderid).await?;
+    }
+    sqlx::query(
+        "INSERT INTO orderstatushistory (historyid, orderid, fromstatus, tostatus, actor, note) VALUES ($1, $2, $3, $4, $5, $6)")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(orderid)
+        .bind(&from)
+        .bind(to)
+        .bind(actor)
+        .bind(note)
+        .execute(&mut *tx)
+        .await?;
+    let event = format!("order.{}", to);
+    if webhooks::WEBHOOK_EVENTS.contains(&event.as_str()) {
+        webhooks::orderevent(&mut *tx, &event, orderid).await?;
+    }
+    Ok(from)
+}
@@ -1,9 +1,13 @@
-use crate::AppState;
-use axum::{extract::State, Json, response::IntoResponse,http::StatusCode};
+use crate::{AppState, customerrors::{ownsoradmin, servererror}, inventory, mware::ClaimsAccessToken, notifications, routesguest, webhooks};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use axum::{extract::State, Extension, Json, response::IntoResponse,http::{StatusCode, HeaderMap}};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use stripe::{
-    AttachPaymentMethod, CardDetailsParams, Client, CreateCustomer, CreatePaymentIntent,
-    CreatePaymentMethod, CreatePaymentMethodCardUnion, Currency, Customer, PaymentIntent,
-    PaymentIntentConfirmParams, PaymentMethod, PaymentMethodTypeFilter, UpdatePaymentIntent};
+    AttachPaymentMethod, CancelPaymentIntent, CardDetailsParams, Client, CreateCustomer, CreatePaymentIntent,
+    CreatePaymentMethod, CreatePaymentMethodCardUnion, CreateRefund, Currency, Customer, PaymentIntent,
+    PaymentIntentConfirmParams, PaymentMethod, PaymentMethodTypeFilter, Refund, RequestStrategy, UpdatePaymentIntent};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
@@ -26,10 +30,39 @@ pub struct PaymentIntentId {
     pub customerid: String,
     pub customername: String,
     pub customeremail: String,
-    pub paymentamount: i64
+    pub orderid: i64
 }
 
-pub async fn paymentintent(State(state): State<AppState>, req: Json<PaymentIntentId>) -> impl IntoResponse {
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct GuestPaymentIntent {
+    pub lookuptoken: String
+}
+
+const WEBHOOK_TOLERANCE_SECONDS: i64 = 300;
+
+fn pennies(total: &BigDecimal) -> i64 {
+    (total * BigDecimal::from(100)).round(0).to_i64().unwrap_or(0)
+}
+
+pub async fn paymentintent(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, req: Json<PaymentIntentId>) -> impl IntoResponse {
+    // The amount always comes from the order's own total, discounts included
+    let order = sqlx::query_as::<_, (BigDecimal, Option<uuid::Uuid>)>("SELECT total, userid FROM orderdet WHERE orderid = $1 AND status = 'pending'")
+        .bind(req.orderid)
+        .fetch_optional(&state.database.db)
+        .await;
+    let paymentamount = match order {
+        Ok(Some((total, userid))) if ownsoradmin(claims.sub, &claims.role, userid) => pennies(&total),
+        Ok(Some(_)) => return (StatusCode::FORBIDDEN, Json(json!({
+            "status": "error",
+            "message": "You can only pay for your own orders",
+        }))),
+        Ok(None) => return (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "Order is not awaiting payment",
+        }))),
+        Err(e) => return servererror(e),
+    };
     let client = Client::new(&state.stripetoken.stripetoken);
     let customer = Customer::create(
         &client,
@@ -45,18 +78,24 @@ pub async fn paymentintent(State(state): State<AppState>, req: Json<PaymentInten
     )
     .await
     .unwrap();
-    let mut create_intent = CreatePaymentIntent::new(req.paymentamount, Currency::GBP);
+    let mut create_intent = CreatePaymentIntent::new(paymentamount, Currency::GBP);
     create_intent.payment_method_types = Some(vec!["card".to_string()]);
-    create_intent.metadata = Some([("db_id".to_string(),req.customerid.to_string())].iter().cloned().collect());
+    create_intent.metadata = Some([
+        ("db_id".to_string(), req.customerid.to_string()),
+        ("orderid".to_string(), req.orderid.to_string())
+    ].iter().cloned().collect());
     create_intent.customer = Some(customer.id);
     let payment_intent = PaymentIntent::create(&client, create_intent).await;
     
     match payment_intent {
-        Ok(response) => (StatusCode::OK, Json(json!({
-            "clientSecret": response.client_secret,
-            "status": response.status,
-            "publishableKey": &state.stripepubtoken.stripepubtoken
-        }))),
+        Ok(response) => {
+            recordpaymentintent(&state, req.orderid, response.id.as_str()).await;
+            (StatusCode::OK, Json(json!({
+                "clientSecret": response.client_secret,
+                "status": response.status,
+                "publishableKey": &state.stripepubtoken.stripepubtoken
+            })))
+        },
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
             "status": "error",
             "message": "Something went wrong",
@@ -66,6 +105,85 @@ pub async fn paymentintent(State(state): State<AppState>, req: Json<PaymentInten
 
 }
 
+// The latest intent is kept on the order so that cancelling the order can cancel it too
+async fn recordpaymentintent(state: &AppState, orderid: i64, paymentintentid: &str) {
+    let response = sqlx::query("UPDATE orderdet SET paymentintentid = $1 WHERE orderid = $2 AND status = 'pending'")
+        .bind(paymentintentid)
+        .bind(orderid)
+        .execute(&state.database.db)
+        .await;
+    if let Err(e) = response {
+        println!("payment intent {} could not be recorded on order {}: {:?}", paymentintentid, orderid, e);
+    }
+}
+
+// Stops an order that was cancelled before it was paid from being paid for. A payment
+// that gets through anyway is refunded when its webhook arrives.
+pub async fn cancelorderpayment(state: &AppState, orderid: i64) {
+    let paymentintentid = sqlx::query_as::<_, (Option<String>,)>("SELECT paymentintentid FROM orderdet WHERE orderid = $1")
+        .bind(orderid)
+        .fetch_optional(&state.database.db)
+        .await;
+    let paymentintentid = match paymentintentid {
+        Ok(Some((Some(paymentintentid),))) => paymentintentid,
+        Ok(_) => return,
+        Err(e) => {
+            println!("payment intent lookup failed for cancelled order {}: {:?}", orderid, e);
+            return
+        }
+    };
+    let client = Client::new(&state.stripetoken.stripetoken);
+    if let Err(e) = PaymentIntent::cancel(&client, &paymentintentid, CancelPaymentIntent::default()).await {
+        println!("payment intent {} for cancelled order {} was not cancelled: {:?}", paymentintentid, orderid, e);
+    }
+}
+
+// Guests pay for an order they can prove they own; the amount comes from the order
+pub async fn guestpaymentintent(State(state): State<AppState>, req: Json<GuestPaymentIntent>) -> impl IntoResponse {
+    let order = match routesguest::findguestorder(&state, &req.lookuptoken).await {
+        Ok(Some(order)) if order.status == "pending" => order,
+        Ok(Some(_)) => return (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "Order is no longer awaiting payment",
+        }))),
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Order not found",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    let amount = pennies(&order.total);
+    let client = Client::new(&state.stripetoken.stripetoken);
+    let customer = Customer::create(
+        &client,
+        CreateCustomer {
+            name: Some(&order.fullname),
+            email: Some(&order.email),
+            ..Default::default()
+        },
+    )
+    .await;
+    let customer = match customer {
+        Ok(customer) => customer,
+        Err(e) => return servererror(e),
+    };
+    let mut create_intent = CreatePaymentIntent::new(amount, Currency::GBP);
+    create_intent.payment_method_types = Some(vec!["card".to_string()]);
+    create_intent.metadata = Some([("orderid".to_string(), order.orderid.to_string())].iter().cloned().collect());
+    create_intent.customer = Some(customer.id);
+    match PaymentIntent::create(&client, create_intent).await {
+        Ok(response) => {
+            recordpaymentintent(&state, order.orderid, response.id.as_str()).await;
+            (StatusCode::OK, Json(json!({
+                "clientSecret": response.client_secret,
+                "status": response.status,
+                "publishableKey": &state.stripepubtoken.stripepubtoken
+            })))
+        },
+        Err(e) => servererror(e),
+    }
+}
+
 pub async fn pay(State(state): State<AppState>, req: Json<Payment>) -> impl IntoResponse {
 
     let client = Client::new(&state.stripetoken.stripetoken);
@@ -168,6 +286,207 @@ pub async fn pay(State(state): State<AppState>, req: Json<Payment>) -> impl Into
 
 
 
+// Stripe-Signature is "t=<unix time>,v1=<hex hmac>[,v1=...]" over "<t>.<payload>"
+fn verifystripesignature(payload: &str, header: &str, secret: &str) -> bool {
+    let mut timestamp: Option<i64> = None;
+    let mut signatures: Vec<&str> = Vec::new();
+    for part in header.split(',') {
+        match part.split_once('=') {
+            Some(("t", value)) => timestamp = value.parse().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+    let timestamp = match timestamp {
+        Some(timestamp) if (Utc::now().timestamp() - timestamp).abs() <= WEBHOOK_TOLERANCE_SECONDS => timestamp,
+        _ => return false,
+    };
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+    signatures.iter().any(|signature| match hex::decode(signature) {
+        Ok(signature) => mac.clone().verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    })
+}
+
+// Refunds part of an order's payment. The amount is in pennies; returns the refund id.
+pub async fn refundpayment(state: &AppState, paymentintentid: &str, amount: i64, returnid: uuid::Uuid) -> Result<String, String> {
+    let client = Client::new(&state.stripetoken.stripetoken);
+    let paymentintent = paymentintentid.parse::<stripe::PaymentIntentId>().map_err(|e| e.to_string())?;
+    let mut params = CreateRefund::new();
+    params.payment_intent = Some(paymentintent);
+    params.amount = Some(amount);
+    params.metadata = Some([("returnid".to_string(), returnid.to_string())].iter().cloned().collect());
+    Refund::create(&client, params)
+        .await
+        .map(|refund| refund.id.to_string())
+        .map_err(|e| e.to_string())
+}
+
+// Refunds the whole payment for a cancelled order; returns the refund id. The
+// idempotency key keeps a redelivered webhook from refunding it twice.
+async fn refundcancelledorder(state: &AppState, orderid: i64, paymentintentid: &str) -> Result<String, String> {
+    let client = Client::new(&state.stripetoken.stripetoken)
+        .with_strategy(RequestStrategy::Idempotent(format!("refund-{}", paymentintentid)));
+    let paymentintent = paymentintentid.parse::<stripe::PaymentIntentId>().map_err(|e| e.to_string())?;
+    let mut params = CreateRefund::new();
+    params.payment_intent = Some(paymentintent);
+    params.metadata = Some([("orderid".to_string(), orderid.to_string())].iter().cloned().collect());
+    Refund::create(&client, params)
+        .await
+        .map(|refund| refund.id.to_string())
+        .map_err(|e| e.to_string())
+}
+
+// Refunds the whole payment for an order cancelled because its stock sold out.
+// If Stripe refuses, admins are asked to refund by hand.
+async fn refundlapsedorder(state: &AppState, orderid: i64, paymentintentid: &str, userid: Option<uuid::Uuid>) {
+    match refundcancelledorder(state, orderid, paymentintentid).await {
+        Ok(refundid) => println!("order {} was paid after its stock sold out, refunded as {}", orderid, refundid),
+        Err(e) => notifications::adminalert(
+            state,
+            format!("Refund needed for order {}", orderid),
+            format!("Order {} was paid after its stock reservation lapsed and the stock has since sold, so it was cancelled. The automatic refund of payment {} failed: {}", orderid, paymentintentid, e),
+            json!({ "event": "payment.refundfailed", "orderid": orderid, "paymentintentid": paymentintentid }),
+        ),
+    }
+    if let Some(userid) = userid {
+        notifications::customeremail(state, userid, format!("Your order {} has been cancelled", orderid), format!(
+            "Sorry, by the time your payment for order {} came through some of the items had sold out, so we have cancelled the order and refunded your payment in full.\n\nRefunds usually take 5 to 10 working days to appear.",
+            orderid));
+    }
+}
+
+// amount is what Stripe received, in pennies. A payment that doesn't match the
+// order total in GBP leaves the order unpaid and is flagged to admins.
+async fn markorderpaid(state: &AppState, orderid: i64, paymentintentid: &str, amount: i64, currency: &str) -> Result<bool, sqlx::Error> {
+    let mut tx = state.database.db.begin().await?;
+    let order = sqlx::query_as::<_, (String, BigDecimal, Option<uuid::Uuid>)>("SELECT status, total, userid FROM orderdet WHERE orderid = $1 FOR UPDATE")
+        .bind(orderid)
+        .fetch_optional(&mut tx)
+        .await?;
+    let (total, userid) = match order {
+        Some((status, total, userid)) if status == "pending" || status == "expired" => (total, userid),
+        // Cancelled by an admin while the customer was paying: give the money back
+        Some((status, _, _)) if status == "cancelled" => {
+            tx.rollback().await?;
+            let outcome = match refundcancelledorder(state, orderid, paymentintentid).await {
+                Ok(refundid) => format!("It has been refunded in full as {}.", refundid),
+                Err(e) => format!("The automatic refund failed, so it needs refunding by hand: {}", e),
+            };
+            notifications::adminalert(
+                state,
+                format!("Payment received for cancelled order {}", orderid),
+                format!("Payment {} came through after order {} was cancelled. {}", paymentintentid, orderid, outcome),
+                json!({ "event": "payment.cancelledorder", "orderid": orderid, "paymentintentid": paymentintentid }),
+            );
+            return Ok(true)
+        },
+        // Unknown order or webhook redelivery for an order that is already paid
+        _ => {
+            tx.rollback().await?;
+            return Ok(false)
+        }
+    };
+    if amount != pennies(&total) || !currency.eq_ignore_ascii_case("gbp") {
+        tx.rollback().await?;
+        println!("payment {} for order {} was {} {}, expected {} gbp", paymentintentid, orderid, amount, currency, pennies(&total));
+        notifications::adminalert(
+            state,
+            format!("Payment mismatch on order {}", orderid),
+            format!("Payment {} received {} {} (in pennies) but order {} totals {} GBP. The order has not been marked paid.", paymentintentid, amount, currency, orderid, total.with_scale(2)),
+            json!({ "event": "payment.mismatch", "orderid": orderid, "paymentintentid": paymentintentid, "amount": amount, "currency": currency }),
+        );
+        return Ok(false)
+    }
+    // Paid after the stock hold ran out and the stock has gone since: give the money back
+    if !inventory::lapsedstillavailable(&mut tx, orderid).await? {
+        sqlx::query("UPDATE orderdet SET status = 'cancelled', paymentintentid = $1 WHERE orderid = $2")
+            .bind(paymentintentid)
+            .bind(orderid)
+            .execute(&mut tx)
+            .await?;
+        inventory::releasereservations(&mut tx, orderid).await?;
+        webhooks::orderevent(&mut tx, "order.cancelled", orderid).await?;
+        tx.commit().await?;
+        refundlapsedorder(state, orderid, paymentintentid, userid).await;
+        return Ok(true)
+    }
+    let productids = inventory::commitreservations(&mut tx, orderid).await?;
+    sqlx::query("UPDATE orderdet SET status = 'paid', paymentintentid = $1, paid_at = now() WHERE orderid = $2")
+        .bind(paymentintentid)
+        .bind(orderid)
+        .execute(&mut tx)
+        .await?;
+    webhooks::orderevent(&mut tx, "order.paid", orderid).await?;
+    tx.commit().await?;
+    if let Err(e) = notifications::lowstockcheck(state, &productids).await {
+        println!("low stock check failed for order {}: {:?}", orderid, e);
+    }
+    Ok(true)
+}
+
+async fn cancelorder(state: &AppState, orderid: i64) -> Result<bool, sqlx::Error> {
+    let mut tx = state.database.db.begin().await?;
+    let cancelled = sqlx::query("UPDATE orderdet SET status = 'cancelled' WHERE orderid = $1 AND status IN ('pending', 'expired')")
+        .bind(orderid)
+        .execute(&mut tx)
+        .await?;
+    if cancelled.rows_affected() > 0 {
+        inventory::releasereservations(&mut tx, orderid).await?;
+        webhooks::orderevent(&mut tx, "order.cancelled", orderid).await?;
+    }
+    tx.commit().await?;
+    Ok(cancelled.rows_affected() > 0)
+}
+
+pub async fn stripewebhook(State(state): State<AppState>, headers: HeaderMap, body: String) -> impl IntoResponse {
+    let secret = match &state.stripewebhooksecret.stripewebhooksecret {
+        Some(secret) => secret,
+        None => return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({
+            "status": "error",
+            "message": "Stripe webhooks are not configured",
+        }))),
+    };
+    let signature = headers.get("Stripe-Signature").and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if !verifystripesignature(&body, signature, secret) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Invalid signature",
+        })))
+    }
+    let event: serde_json::Value = match serde_json::from_str(&body) {
+        Ok(event) => event,
+        Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Invalid payload",
+            "error": e.to_string(),
+        }))),
+    };
+    let object = &event["data"]["object"];
+    let orderid = object["metadata"]["orderid"].as_str().and_then(|orderid| orderid.parse::<i64>().ok());
+    let paymentintentid = object["id"].as_str().unwrap_or_default();
+    let amount = object["amount_received"].as_i64().unwrap_or(0);
+    let currency = object["currency"].as_str().unwrap_or_default();
+    let response = match (event["type"].as_str(), orderid) {
+        (Some("payment_intent.succeeded"), Some(orderid)) => markorderpaid(&state, orderid, paymentintentid, amount, currency).await,
+        (Some("payment_intent.canceled"), Some(orderid)) => cancelorder(&state, orderid).await,
+        _ => Ok(false),
+    };
+    match response {
+        Ok(handled) => (StatusCode::OK, Json(json!({
+            "received": true,
+            "handled": handled
+        }))),
+        // Non-2xx makes Stripe retry the delivery later
+        Err(e) => servererror(e),
+    }
+}
+
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct HandleStripePaymentBody {
     pub cancel_uri: String,
@@ -206,3 +525,49 @@ pub struct HandleStripePaymentBody {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "whsec_test";
+    const PAYLOAD: &str = "{\"type\":\"payment_intent.succeeded\"}";
+
+    fn signature(secret: &str, timestamp: i64, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", timestamp, payload).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    #[test]
+    fn stripe_signature_is_accepted() {
+        let now = Utc::now().timestamp();
+        let header = format!("t={},v1={}", now, signature(SECRET, now, PAYLOAD));
+        assert!(verifystripesignature(PAYLOAD, &header, SECRET));
+    }
+
+    #[test]
+    fn any_matching_stripe_signature_is_accepted() {
+        let now = Utc::now().timestamp();
+        let header = format!("t={},v1={},v1={}", now, signature("whsec_old", now, PAYLOAD), signature(SECRET, now, PAYLOAD));
+        assert!(verifystripesignature(PAYLOAD, &header, SECRET));
+    }
+
+    #[test]
+    fn stripe_signature_for_other_payload_or_secret_is_rejected() {
+        let now = Utc::now().timestamp();
+        let header = format!("t={},v1={}", now, signature(SECRET, now, PAYLOAD));
+        assert!(!verifystripesignature("{\"type\":\"charge.refunded\"}", &header, SECRET));
+        assert!(!verifystripesignature(PAYLOAD, &header, "whsec_other"));
+    }
+
+    #[test]
+    fn stale_or_malformed_stripe_signature_is_rejected() {
+        let stale = Utc::now().timestamp() - WEBHOOK_TOLERANCE_SECONDS - 1;
+        assert!(!verifystripesignature(PAYLOAD, &format!("t={},v1={}", stale, signature(SECRET, stale, PAYLOAD)), SECRET));
+        let now = Utc::now().timestamp();
+        assert!(!verifystripesignature(PAYLOAD, &format!("v1={}", signature(SECRET, now, PAYLOAD)), SECRET));
+        assert!(!verifystripesignature(PAYLOAD, &format!("t={},v1=not-hex", now), SECRET));
+        assert!(!verifystripesignature(PAYLOAD, "", SECRET));
+    }
+}
@@ -0,0 +1,404 @@
+use bigdecimal::{BigDecimal, ToPrimitive};
+use uuid::Uuid;
+
+// Order arithmetic with no database access. Amounts are in pounds and rounded
+// to the penny, half up.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+
+pub enum DiscountKind {
+    Percentage,
+    Fixed,
+    FreeShipping,
+}
+
+impl DiscountKind {
+    pub fn from_str(kind: &str) -> Option<DiscountKind> {
+        match kind {
+            "percentage" => Some(DiscountKind::Percentage),
+            "fixed" => Some(DiscountKind::Fixed),
+            "freeshipping" => Some(DiscountKind::FreeShipping),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiscountKind::Percentage => "percentage",
+            DiscountKind::Fixed => "fixed",
+            DiscountKind::FreeShipping => "freeshipping",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+
+pub enum TaxClass {
+    Standard,
+    Reduced,
+    Zero,
+}
+
+impl TaxClass {
+    pub fn from_str(taxclass: &str) -> Option<TaxClass> {
+        match taxclass {
+            "standard" => Some(TaxClass::Standard),
+            "reduced" => Some(TaxClass::Reduced),
+            "zero" => Some(TaxClass::Zero),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TaxClass::Standard => "standard",
+            TaxClass::Reduced => "reduced",
+            TaxClass::Zero => "zero",
+        }
+    }
+
+    // UK VAT rates as a percentage
+    pub fn rate(&self) -> BigDecimal {
+        match self {
+            TaxClass::Standard => BigDecimal::from(20),
+            TaxClass::Reduced => BigDecimal::from(5),
+            TaxClass::Zero => BigDecimal::from(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+
+pub enum RateKind {
+    Flat,
+    Weight,
+    FreeAbove,
+}
+
+impl RateKind {
+    pub fn from_str(kind: &str) -> Option<RateKind> {
+        match kind {
+            "flat" => Some(RateKind::Flat),
+            "weight" => Some(RateKind::Weight),
+            "freeabove" => Some(RateKind::FreeAbove),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RateKind::Flat => "flat",
+            RateKind::Weight => "weight",
+            RateKind::FreeAbove => "freeabove",
+        }
+    }
+}
+
+pub struct ShippingRate {
+    pub kind: RateKind,
+    pub baserate: BigDecimal,
+    pub perkg: BigDecimal,
+    pub freeabove: Option<BigDecimal>
+}
+
+pub struct DiscountRule {
+    pub kind: DiscountKind,
+    pub value: BigDecimal,
+    pub productids: Vec<Uuid>,
+    pub categories: Vec<String>
+}
+
+#[derive(Debug, Clone)]
+
+pub struct PricedLine {
+    pub productid: Uuid,
+    pub category: String,
+    pub linetotal: BigDecimal,
+    pub discount: BigDecimal,
+    pub taxclass: TaxClass,
+    pub netamount: BigDecimal,
+    pub taxamount: BigDecimal
+}
+
+// Prices are stored as text. Only plain non-negative decimals such as "9.99" are
+// prices; anything else is None.
+pub fn parseprice(price: &str) -> Option<BigDecimal> {
+    let price = price.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (whole, fraction) = price.split_once('.').unwrap_or((price, "0"));
+    if whole.is_empty() || fraction.is_empty() || !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    price.parse::<BigDecimal>().ok()
+}
+
+// The same check in SQL, for reading a price column as NUMERIC. A bare cast of
+// text that isn't a number aborts the statement and its transaction.
+pub fn pricesql(price: &str) -> String {
+    format!("CASE WHEN {0} ~ '^\\s*[0-9]+(\\.[0-9]+)?\\s*$' THEN ({0})::NUMERIC END", price)
+}
+
+pub fn roundmoney(amount: &BigDecimal) -> BigDecimal {
+    let pennies = amount * BigDecimal::from(100);
+    let half = BigDecimal::new(5.into(), 1);
+    let pennies = if pennies < BigDecimal::from(0) { pennies - half } else { pennies + half };
+    pennies.with_scale(0) / BigDecimal::from(100)
+}
+
+impl DiscountRule {
+    // A code with no product or category restrictions applies to every line
+    pub fn applies(&self, line: &PricedLine) -> bool {
+        (self.productids.is_empty() && self.categories.is_empty())
+            || self.productids.contains(&line.productid)
+            || self.categories.contains(&line.category)
+    }
+}
+
+fn topennies(amount: &BigDecimal) -> i64 {
+    (roundmoney(amount) * BigDecimal::from(100)).to_i64().unwrap_or(0)
+}
+
+// Sets the discount on each line and returns the order discount. A fixed amount
+// is shared across the eligible lines in proportion to their value: each line
+// gets its share rounded down to the penny and the pennies left over go to the
+// lines that lost most to rounding. No line is discounted by more than it costs.
+pub fn allocatediscount(rule: &DiscountRule, lines: &mut [PricedLine]) -> BigDecimal {
+    let zero = BigDecimal::from(0);
+    let eligible: Vec<usize> = (0..lines.len()).filter(|&index| rule.applies(&lines[index])).collect();
+    match rule.kind {
+        DiscountKind::FreeShipping => {},
+        DiscountKind::Percentage => {
+            let percent = rule.value.clone().min(BigDecimal::from(100));
+            for &index in &eligible {
+                lines[index].discount = roundmoney(&(&lines[index].linetotal * &percent / BigDecimal::from(100)));
+            }
+        },
+        DiscountKind::Fixed => {
+            let pennies: Vec<i128> = eligible.iter().map(|&index| topennies(&lines[index].linetotal).max(0) as i128).collect();
+            let totalpennies: i128 = pennies.iter().sum();
+            let amount = (topennies(&rule.value) as i128).clamp(0, totalpennies);
+            if totalpennies > 0 {
+                let mut shares: Vec<i128> = pennies.iter().map(|linepennies| amount * linepennies / totalpennies).collect();
+                let mut leftover = amount - shares.iter().sum::<i128>();
+                // Stable, so equal remainders favour the earlier line
+                let mut byremainder: Vec<usize> = (0..pennies.len()).collect();
+                byremainder.sort_by_key(|&position| std::cmp::Reverse(amount * pennies[position] % totalpennies));
+                for position in byremainder {
+                    if leftover == 0 {
+                        break
+                    }
+                    if shares[position] < pennies[position] {
+                        shares[position] += 1;
+                        leftover -= 1;
+                    }
+                }
+                for (position, &index) in eligible.iter().enumerate() {
+                    lines[index].discount = BigDecimal::from(shares[position] as i64) / BigDecimal::from(100);
+                }
+            }
+        },
+    }
+    lines.iter().fold(zero, |total, line| total + &line.discount)
+}
+
+// (net, tax) for an amount. With inclusive prices the tax is taken out of the
+// amount; otherwise it is added on top.
+pub fn taxon(amount: &BigDecimal, taxclass: TaxClass, pricesincludevat: bool) -> (BigDecimal, BigDecimal) {
+    let rate = taxclass.rate();
+    if pricesincludevat {
+        let tax = roundmoney(&(amount * &rate / (BigDecimal::from(100) + &rate)));
+        (amount - &tax, tax)
+    } else {
+        (amount.clone(), roundmoney(&(amount * &rate / BigDecimal::from(100))))
+    }
+}
+
+// Works out VAT on each line after its discount and returns the order's net and
+// tax totals. Tax is rounded per line.
+pub fn applytax(lines: &mut [PricedLine], pricesincludevat: bool) -> (BigDecimal, BigDecimal) {
+    let zero = BigDecimal::from(0);
+    for line in lines.iter_mut() {
+        let (netamount, taxamount) = taxon(&(&line.linetotal - &line.discount), line.taxclass, pricesincludevat);
+        line.netamount = netamount;
+        line.taxamount = taxamount;
+    }
+    lines.iter().fold((zero.clone(), zero), |(net, tax), line| (net + &line.netamount, tax + &line.taxamount))
+}
+
+// Weight rates charge for each started kilogram
+pub fn shippingcost(rate: &ShippingRate, basket: &BigDecimal, weightgrams: i64) -> BigDecimal {
+    match rate.kind {
+        RateKind::Flat => rate.baserate.clone(),
+        RateKind::Weight => {
+            let kilos = (weightgrams.max(0) + 999) / 1000;
+            &rate.baserate + &rate.perkg * BigDecimal::from(kilos)
+        },
+        RateKind::FreeAbove => match &rate.freeabove {
+            Some(freeabove) if basket >= freeabove => BigDecimal::from(0),
+            _ => rate.baserate.clone(),
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn money(amount: &str) -> BigDecimal {
+        BigDecimal::from_str(amount).unwrap()
+    }
+
+    fn line(linetotal: &str, category: &str) -> PricedLine {
+        PricedLine {
+            productid: Uuid::nil(),
+            category: category.to_string(),
+            linetotal: money(linetotal),
+            discount: BigDecimal::from(0),
+            taxclass: TaxClass::Standard,
+            netamount: BigDecimal::from(0),
+            taxamount: BigDecimal::from(0)
+        }
+    }
+
+    fn fixed(value: &str, categories: Vec<&str>) -> DiscountRule {
+        DiscountRule {
+            kind: DiscountKind::Fixed,
+            value: money(value),
+            productids: Vec::new(),
+            categories: categories.into_iter().map(str::to_string).collect()
+        }
+    }
+
+    #[test]
+    fn fixed_discount_is_shared_in_proportion() {
+        let mut lines = vec![line("30.00", "shoes"), line("70.00", "shoes")];
+        let total = allocatediscount(&fixed("10", vec![]), &mut lines);
+        assert_eq!(total, money("10"));
+        assert_eq!(lines[0].discount, money("3"));
+        assert_eq!(lines[1].discount, money("7"));
+    }
+
+    #[test]
+    fn fixed_discount_leftover_pennies_never_go_negative() {
+        let mut lines: Vec<PricedLine> = (0..10).map(|_| line("1.00", "shoes")).collect();
+        let total = allocatediscount(&fixed("0.05", vec![]), &mut lines);
+        assert_eq!(total, money("0.05"));
+        assert!(lines.iter().all(|line| line.discount >= BigDecimal::from(0) && line.discount <= money("0.01")));
+        assert_eq!(lines.iter().filter(|line| line.discount == money("0.01")).count(), 5);
+    }
+
+    #[test]
+    fn fixed_discount_pennies_go_to_the_largest_remainders() {
+        let mut lines = vec![line("1.00", "shoes"), line("1.00", "shoes"), line("1.00", "shoes")];
+        let total = allocatediscount(&fixed("0.10", vec![]), &mut lines);
+        assert_eq!(total, money("0.10"));
+        assert_eq!(lines[0].discount, money("0.04"));
+        assert_eq!(lines[1].discount, money("0.03"));
+        assert_eq!(lines[2].discount, money("0.03"));
+    }
+
+    #[test]
+    fn fixed_discount_is_capped_at_the_eligible_lines() {
+        let mut lines = vec![line("4.99", "shoes"), line("20.00", "hats")];
+        let total = allocatediscount(&fixed("50", vec!["shoes"]), &mut lines);
+        assert_eq!(total, money("4.99"));
+        assert_eq!(lines[0].discount, money("4.99"));
+        assert_eq!(lines[1].discount, BigDecimal::from(0));
+    }
+
+    #[test]
+    fn fixed_discount_with_nothing_eligible_is_zero() {
+        let mut lines = vec![line("10.00", "hats")];
+        assert_eq!(allocatediscount(&fixed("5", vec!["shoes"]), &mut lines), BigDecimal::from(0));
+    }
+
+    #[test]
+    fn prices_must_be_plain_decimals() {
+        assert_eq!(parseprice("9.99"), Some(money("9.99")));
+        assert_eq!(parseprice(" 12 "), Some(money("12")));
+        assert_eq!(parseprice("call us"), None);
+        assert_eq!(parseprice("-1.00"), None);
+        assert_eq!(parseprice("1e3"), None);
+        assert_eq!(parseprice(".5"), None);
+        assert_eq!(parseprice("5."), None);
+        assert_eq!(parseprice(""), None);
+    }
+
+    #[test]
+    fn percentage_discount_is_rounded_per_line() {
+        let rule = DiscountRule { kind: DiscountKind::Percentage, value: money("15"), productids: Vec::new(), categories: vec!["shoes".to_string()] };
+        let mut lines = vec![line("19.99", "shoes"), line("10.00", "hats")];
+        assert_eq!(allocatediscount(&rule, &mut lines), money("3.00"));
+        assert_eq!(lines[0].discount, money("3.00"));
+        assert_eq!(lines[1].discount, BigDecimal::from(0));
+    }
+
+    #[test]
+    fn percentage_discount_is_capped_at_the_line() {
+        let rule = DiscountRule { kind: DiscountKind::Percentage, value: money("150"), productids: Vec::new(), categories: Vec::new() };
+        let mut lines = vec![line("12.50", "shoes")];
+        assert_eq!(allocatediscount(&rule, &mut lines), money("12.50"));
+    }
+
+    #[test]
+    fn free_shipping_discounts_no_lines() {
+        let rule = DiscountRule { kind: DiscountKind::FreeShipping, value: BigDecimal::from(0), productids: Vec::new(), categories: Vec::new() };
+        let mut lines = vec![line("12.50", "shoes")];
+        assert_eq!(allocatediscount(&rule, &mut lines), BigDecimal::from(0));
+    }
+
+    #[test]
+    fn vat_is_taken_out_of_inclusive_prices() {
+        let mut lines = vec![line("12.00", "shoes"), line("10.50", "children"), line("9.99", "books")];
+        lines[1].taxclass = TaxClass::Reduced;
+        lines[2].taxclass = TaxClass::Zero;
+        let (net, tax) = applytax(&mut lines, true);
+        assert_eq!((lines[0].netamount.clone(), lines[0].taxamount.clone()), (money("10.00"), money("2.00")));
+        assert_eq!((lines[1].netamount.clone(), lines[1].taxamount.clone()), (money("10.00"), money("0.50")));
+        assert_eq!((lines[2].netamount.clone(), lines[2].taxamount.clone()), (money("9.99"), BigDecimal::from(0)));
+        assert_eq!((net, tax), (money("29.99"), money("2.50")));
+    }
+
+    #[test]
+    fn vat_is_added_to_exclusive_prices() {
+        let mut lines = vec![line("10.00", "shoes"), line("0.99", "shoes")];
+        let (net, tax) = applytax(&mut lines, false);
+        assert_eq!(lines[0].taxamount, money("2.00"));
+        assert_eq!(lines[1].taxamount, money("0.20"));
+        assert_eq!((net, tax), (money("10.99"), money("2.20")));
+    }
+
+    #[test]
+    fn vat_is_charged_on_the_discounted_line() {
+        let mut lines = vec![line("24.00", "shoes"), line("6.00", "books")];
+        lines[0].discount = money("4.00");
+        lines[1].taxclass = TaxClass::Zero;
+        let (net, tax) = applytax(&mut lines, true);
+        assert_eq!((lines[0].netamount.clone(), lines[0].taxamount.clone()), (money("16.67"), money("3.33")));
+        assert_eq!((net, tax), (money("22.67"), money("3.33")));
+    }
+
+    fn rate(kind: RateKind, freeabove: Option<&str>) -> ShippingRate {
+        ShippingRate { kind, baserate: money("3.00"), perkg: money("1.50"), freeabove: freeabove.map(money) }
+    }
+
+    #[test]
+    fn flat_shipping_ignores_basket_and_weight() {
+        assert_eq!(shippingcost(&rate(RateKind::Flat, None), &money("500"), 20000), money("3.00"));
+    }
+
+    #[test]
+    fn weight_shipping_charges_each_started_kilogram() {
+        assert_eq!(shippingcost(&rate(RateKind::Weight, None), &money("10"), 0), money("3.00"));
+        assert_eq!(shippingcost(&rate(RateKind::Weight, None), &money("10"), 1000), money("4.50"));
+        assert_eq!(shippingcost(&rate(RateKind::Weight, None), &money("10"), 1001), money("6.00"));
+    }
+
+    #[test]
+    fn shipping_is_free_from_the_threshold() {
+        assert_eq!(shippingcost(&rate(RateKind::FreeAbove, Some("50")), &money("49.99"), 0), money("3.00"));
+        assert_eq!(shippingcost(&rate(RateKind::FreeAbove, Some("50")), &money("50.00"), 0), BigDecimal::from(0));
+        assert_eq!(shippingcost(&rate(RateKind::FreeAbove, None), &money("500"), 0), money("3.00"));
+    }
+}
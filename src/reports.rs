@@ -0,0 +1,223 @@
+use bigdecimal::BigDecimal;
+use serde::Serialize;
+use sqlx::{self, FromRow, Pool, Postgres};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+
+pub enum Interval {
+    Day,
+    Week,
+    Month,
+}
+
+impl Interval {
+    pub fn from_str(interval: &str) -> Option<Interval> {
+        match interval {
+            "day" => Some(Interval::Day),
+            "week" => Some(Interval::Week),
+            "month" => Some(Interval::Month),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Interval::Day => "day",
+            Interval::Week => "week",
+            Interval::Month => "month",
+        }
+    }
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct RevenueRow {
+    pub period: chrono::NaiveDate,
+    pub orders: i64,
+    pub revenue: BigDecimal,
+    pub nettotal: BigDecimal,
+    pub taxtotal: BigDecimal,
+    pub discounttotal: BigDecimal,
+    pub shippingcost: BigDecimal,
+    pub refunds: BigDecimal,
+    pub averageordervalue: Option<BigDecimal>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct ProductSalesRow {
+    pub productid: Uuid,
+    pub variantid: Option<Uuid>,
+    pub prodname: String,
+    pub sku: String,
+    pub units: i64,
+    pub revenue: BigDecimal
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct CustomerRow {
+    pub period: chrono::NaiveDate,
+    pub customers: i64,
+    pub newcustomers: i64,
+    pub returningcustomers: i64
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct ConversionRow {
+    pub period: chrono::NaiveDate,
+    pub carts: i64,
+    pub checkouts: i64,
+    pub paidorders: i64,
+    pub conversionrate: Option<BigDecimal>
+}
+
+// One row per interval between $2 and $3 inclusive. The first and last periods
+// are clipped to the range, so startday and endday (exclusive) are what to filter on.
+const PERIODS_SQL: &str = "periods AS (
+    SELECT series.period::date AS period,
+    GREATEST(series.period, $2::date::timestamp)::date AS startday,
+    LEAST(series.period + ('1 ' || $1)::interval, $3::date::timestamp + interval '1 day')::date AS endday
+    FROM generate_series(date_trunc($1, $2::date::timestamp), $3::date::timestamp, ('1 ' || $1)::interval) AS series(period)
+)";
+
+
+// The daily views are defined once in the report views migration. The
+// materialized copies trade freshness for speed.
+fn source(materialized: bool, view: &str) -> String {
+    if materialized {
+        view.to_string()
+    } else {
+        format!("{}live", view)
+    }
+}
+
+// Refunds fall on the day they were made: return refunds when approved, and
+// orders refunded by hand when marked refunded.
+pub async fn revenue(db: &Pool<Postgres>, materialized: bool, interval: Interval, from: chrono::NaiveDate, to: chrono::NaiveDate) -> Result<Vec<RevenueRow>, sqlx::Error> {
+    sqlx::query_as::<_, RevenueRow>(&format!(
+        "WITH {},
+        refunds AS (
+            SELECT day, SUM(amount) AS refunds FROM (
+                SELECT decided_at::date AS day, refundamount AS amount
+                FROM returns WHERE refundstatus = 'succeeded'
+                UNION ALL
+                SELECT refunded_at::date, refundamount
+                FROM orderdet WHERE refundamount IS NOT NULL
+            ) refunded
+            GROUP BY 1
+        )
+        SELECT periods.period,
+        COALESCE(SUM(daily.orders), 0)::BIGINT AS orders,
+        COALESCE(SUM(daily.revenue), 0) AS revenue,
+        COALESCE(SUM(daily.nettotal), 0) AS nettotal,
+        COALESCE(SUM(daily.taxtotal), 0) AS taxtotal,
+        COALESCE(SUM(daily.discounttotal), 0) AS discounttotal,
+        COALESCE(SUM(daily.shippingcost), 0) AS shippingcost,
+        COALESCE((SELECT SUM(refunds.refunds) FROM refunds
+            WHERE refunds.day >= periods.startday AND refunds.day < periods.endday), 0) AS refunds,
+        ROUND(SUM(daily.revenue) / NULLIF(SUM(daily.orders), 0), 2) AS averageordervalue
+        FROM periods
+        LEFT JOIN {} daily ON daily.day >= periods.startday AND daily.day < periods.endday
+        GROUP BY periods.period, periods.startday, periods.endday
+        ORDER BY periods.period", PERIODS_SQL, source(materialized, "reportdailysales")))
+        .bind(interval.as_str())
+        .bind(from)
+        .bind(to)
+        .fetch_all(db)
+        .await
+}
+
+pub async fn topproducts(db: &Pool<Postgres>, materialized: bool, byrevenue: bool, from: chrono::NaiveDate, to: chrono::NaiveDate, limit: i64) -> Result<Vec<ProductSalesRow>, sqlx::Error> {
+    sqlx::query_as::<_, ProductSalesRow>(&format!(
+        "SELECT daily.productid, daily.variantid, products.prodname,
+        COALESCE(productvariants.sku, products.prodsku) AS sku,
+        SUM(daily.units)::BIGINT AS units,
+        COALESCE(SUM(daily.revenue), 0) AS revenue
+        FROM {} daily
+        INNER JOIN products ON products.productid = daily.productid
+        LEFT JOIN productvariants ON productvariants.variantid = daily.variantid
+        WHERE daily.day >= $1 AND daily.day <= $2
+        GROUP BY daily.productid, daily.variantid, products.prodname, products.prodsku, productvariants.sku
+        ORDER BY {} DESC, products.prodname
+        LIMIT $3", source(materialized, "reportdailyproducts"), if byrevenue { "revenue" } else { "units" }))
+        .bind(from)
+        .bind(to)
+        .bind(limit)
+        .fetch_all(db)
+        .await
+}
+
+// A customer is new in the period of their first paid order and returning after
+// that. Guest orders count by guest until the guest registers.
+pub async fn customers(db: &Pool<Postgres>, interval: Interval, from: chrono::NaiveDate, to: chrono::NaiveDate) -> Result<Vec<CustomerRow>, sqlx::Error> {
+    sqlx::query_as::<_, CustomerRow>(&format!(
+        "WITH {},
+        sold AS (
+            SELECT COALESCE(userid, guestid) AS customer, COALESCE(paid_at, created_at)::date AS day
+            FROM orderdet
+            WHERE soldstatus(status) AND COALESCE(userid, guestid) IS NOT NULL
+        ),
+        firsts AS (
+            SELECT customer, MIN(day) AS firstday FROM sold GROUP BY customer
+        )
+        SELECT periods.period,
+        COUNT(DISTINCT sold.customer)::BIGINT AS customers,
+        COUNT(DISTINCT sold.customer) FILTER (WHERE firsts.firstday >= periods.startday)::BIGINT AS newcustomers,
+        COUNT(DISTINCT sold.customer) FILTER (WHERE firsts.firstday < periods.startday)::BIGINT AS returningcustomers
+        FROM periods
+        LEFT JOIN sold ON sold.day >= periods.startday AND sold.day < periods.endday
+        LEFT JOIN firsts ON firsts.customer = sold.customer
+        GROUP BY periods.period
+        ORDER BY periods.period", PERIODS_SQL))
+        .bind(interval.as_str())
+        .bind(from)
+        .bind(to)
+        .fetch_all(db)
+        .await
+}
+
+// Cart sessions started in each period and how many of them ended in a paid order
+pub async fn conversion(db: &Pool<Postgres>, interval: Interval, from: chrono::NaiveDate, to: chrono::NaiveDate) -> Result<Vec<ConversionRow>, sqlx::Error> {
+    sqlx::query_as::<_, ConversionRow>(&format!(
+        "WITH {}
+        SELECT periods.period,
+        COUNT(cartsessions.sessionid)::BIGINT AS carts,
+        COUNT(cartsessions.sessionid) FILTER (WHERE cartsessions.checkedout_at IS NOT NULL)::BIGINT AS checkouts,
+        COUNT(cartsessions.sessionid) FILTER (WHERE soldstatus(orderdet.status))::BIGINT AS paidorders,
+        ROUND(100.0 * COUNT(cartsessions.sessionid) FILTER (WHERE soldstatus(orderdet.status)) / NULLIF(COUNT(cartsessions.sessionid), 0), 2) AS conversionrate
+        FROM periods
+        LEFT JOIN cartsessions ON cartsessions.started_at::date >= periods.startday AND cartsessions.started_at::date < periods.endday
+        LEFT JOIN orderdet ON orderdet.orderid = cartsessions.orderid
+        GROUP BY periods.period
+        ORDER BY periods.period", PERIODS_SQL))
+        .bind(interval.as_str())
+        .bind(from)
+        .bind(to)
+        .fetch_all(db)
+        .await
+}
+
+pub async fn refreshviews(db: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query("REFRESH MATERIALIZED VIEW CONCURRENTLY reportdailysales")
+        .execute(db)
+        .await?;
+    sqlx::query("REFRESH MATERIALIZED VIEW CONCURRENTLY reportdailyproducts")
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub fn spawnrefresher(db: Pool<Postgres>, minutes: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(minutes.max(1) * 60));
+        loop {
+            interval.tick().await;
+            if let Err(e) = refreshviews(&db).await {
+                println!("report view refresh failed: {:?}", e);
+            }
+        }
+    });
+}
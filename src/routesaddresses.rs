@@ -0,0 +1,286 @@
+use axum::{Json, Extension, extract::{Path, State}, response::IntoResponse, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{self, FromRow, Pool, Postgres, Transaction};
+use uuid::Uuid;
+use crate::{AppState, checkout::ShippingAddress, customerrors::{badrequest, forbidden, servererror}, mware::ClaimsAccessToken, shipping::normaliseukpostcode};
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct Address {
+    addrid: Uuid,
+    userid: Uuid,
+    fullname: Option<String>,
+    label: Option<String>,
+    address: String,
+    city: String,
+    postcode: String,
+    defaultshipping: bool,
+    defaultbilling: bool,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct NewAddress {
+    fullname: Option<String>,
+    label: Option<String>,
+    address: String,
+    city: String,
+    postcode: String,
+    defaultshipping: Option<bool>,
+    defaultbilling: Option<bool>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct AddressUpdate {
+    fullname: Option<String>,
+    label: Option<String>,
+    address: Option<String>,
+    city: Option<String>,
+    postcode: Option<String>,
+    defaultshipping: Option<bool>,
+    defaultbilling: Option<bool>
+}
+
+
+fn addressnotfound() -> (StatusCode, Json<Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "status": "error",
+        "message": "Address not found",
+    })))
+}
+
+// The chosen address, or the user's default when no id is given. The recipient
+// falls back to the account name.
+pub async fn addressforcheckout(db: &Pool<Postgres>, userid: Uuid, addrid: Option<Uuid>, billing: bool) -> Result<Option<ShippingAddress>, sqlx::Error> {
+    sqlx::query_as::<_, ShippingAddress>(
+        "SELECT COALESCE(NULLIF(useraddr.fullname, ''), users.fullname) AS fullname, useraddr.address, useraddr.city, useraddr.postcode
+        FROM useraddr
+        INNER JOIN users ON users.usid = useraddr.userid
+        WHERE useraddr.userid = $1
+        AND CASE WHEN $2::uuid IS NOT NULL THEN useraddr.addrid = $2
+            WHEN $3 THEN useraddr.defaultbilling
+            ELSE useraddr.defaultshipping END")
+        .bind(userid)
+        .bind(addrid)
+        .bind(billing)
+        .fetch_optional(db)
+        .await
+}
+
+// Clears the flag on the user's other addresses so the partial unique indexes hold
+async fn cleardefaults(tx: &mut Transaction<'_, Postgres>, userid: Uuid, addrid: Uuid, shipping: bool, billing: bool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE useraddr SET
+        defaultshipping = defaultshipping AND NOT $3,
+        defaultbilling = defaultbilling AND NOT $4
+        WHERE userid = $1 AND addrid <> $2")
+        .bind(userid)
+        .bind(addrid)
+        .bind(shipping)
+        .bind(billing)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+
+//Fetch addresses route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchaddresseshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own addresses") {
+        return response
+    }
+    let response = sqlx::query_as::<_, Address>(
+        "SELECT * FROM useraddr WHERE userid = $1
+        ORDER BY defaultshipping DESC, defaultbilling DESC, created_at")
+        .bind(userid)
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(addresses) => (StatusCode::OK, Json(json!({
+            "addresses": addresses
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Create address route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn createaddresshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>, Json(req): Json<NewAddress>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own addresses") {
+        return response
+    }
+    if req.address.trim().is_empty() || req.city.trim().is_empty() {
+        return badrequest("address and city are required")
+    }
+    let postcode = match normaliseukpostcode(&req.postcode) {
+        Some(postcode) => postcode,
+        None => return badrequest("postcode is not a valid UK postcode"),
+    };
+    let addrid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    let mut tx = state.database.db.begin().await.unwrap();
+    // The first address is the default for both
+    let existing = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM useraddr WHERE userid = $1")
+        .bind(userid)
+        .fetch_one(&mut tx)
+        .await;
+    let first = match existing {
+        Ok((count,)) => count == 0,
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    };
+    let defaultshipping = first || req.defaultshipping.unwrap_or(false);
+    let defaultbilling = first || req.defaultbilling.unwrap_or(false);
+    if let Err(e) = cleardefaults(&mut tx, userid, addrid, defaultshipping, defaultbilling).await {
+        tx.rollback().await.unwrap();
+        return servererror(e)
+    }
+    let response = sqlx::query_as::<_, Address>(
+        "INSERT INTO useraddr (addrid, userid, fullname, label, address, city, postcode, defaultshipping, defaultbilling)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *")
+        .bind(addrid)
+        .bind(userid)
+        .bind(req.fullname.as_deref().map(str::trim))
+        .bind(req.label.as_deref().map(str::trim))
+        .bind(req.address.trim())
+        .bind(req.city.trim())
+        .bind(&postcode)
+        .bind(defaultshipping)
+        .bind(defaultbilling)
+        .fetch_one(&mut tx)
+        .await;
+    match response {
+        Ok(address) => {
+            tx.commit().await.unwrap();
+            (StatusCode::CREATED, Json(json!({
+                "status": "success",
+                "address": address
+            })))
+        },
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => {
+            tx.rollback().await.unwrap();
+            (StatusCode::NOT_FOUND, Json(json!({
+                "status": "error",
+                "message": "User not found",
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
+    }
+}
+
+
+//Update address route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn updateaddresshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((userid, addrid)): Path<(Uuid, Uuid)>, Json(req): Json<AddressUpdate>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own addresses") {
+        return response
+    }
+    let postcode = match req.postcode.as_deref().map(normaliseukpostcode) {
+        Some(None) => return badrequest("postcode is not a valid UK postcode"),
+        Some(postcode) => postcode,
+        None => None,
+    };
+    let mut tx = state.database.db.begin().await.unwrap();
+    let response = cleardefaults(&mut tx, userid, addrid, req.defaultshipping == Some(true), req.defaultbilling == Some(true)).await;
+    let response = match response {
+        Ok(_) => sqlx::query_as::<_, Address>(
+            "UPDATE useraddr SET
+            fullname = COALESCE($3, fullname),
+            label = COALESCE($4, label),
+            address = COALESCE(NULLIF($5, ''), address),
+            city = COALESCE(NULLIF($6, ''), city),
+            postcode = COALESCE($7, postcode),
+            defaultshipping = COALESCE($8, defaultshipping),
+            defaultbilling = COALESCE($9, defaultbilling)
+            WHERE userid = $1 AND addrid = $2 RETURNING *")
+            .bind(userid)
+            .bind(addrid)
+            .bind(req.fullname.as_deref().map(str::trim))
+            .bind(req.label.as_deref().map(str::trim))
+            .bind(req.address.as_deref().map(str::trim))
+            .bind(req.city.as_deref().map(str::trim))
+            .bind(&postcode)
+            .bind(req.defaultshipping)
+            .bind(req.defaultbilling)
+            .fetch_optional(&mut tx)
+            .await,
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(Some(address)) => {
+            tx.commit().await.unwrap();
+            (StatusCode::OK, Json(json!({
+                "status": "success",
+                "address": address
+            })))
+        },
+        Ok(None) => {
+            tx.rollback().await.unwrap();
+            addressnotfound()
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
+    }
+}
+
+
+//Delete address route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Orders keep their own copy of the address, so removing one never changes past orders.
+// If a default is removed the oldest remaining address takes its place.
+pub async fn deleteaddresshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((userid, addrid)): Path<(Uuid, Uuid)>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own addresses") {
+        return response
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    let deleted = sqlx::query_as::<_, (bool, bool)>(
+        "DELETE FROM useraddr WHERE userid = $1 AND addrid = $2 RETURNING defaultshipping, defaultbilling")
+        .bind(userid)
+        .bind(addrid)
+        .fetch_optional(&mut tx)
+        .await;
+    let response = match deleted {
+        Ok(Some((defaultshipping, defaultbilling))) if defaultshipping || defaultbilling => sqlx::query(
+            "UPDATE useraddr SET
+            defaultshipping = defaultshipping OR $2,
+            defaultbilling = defaultbilling OR $3
+            WHERE addrid = (SELECT addrid FROM useraddr WHERE userid = $1 ORDER BY created_at, addrid LIMIT 1)")
+            .bind(userid)
+            .bind(defaultshipping)
+            .bind(defaultbilling)
+            .execute(&mut tx)
+            .await
+            .map(|_| true),
+        Ok(Some(_)) => Ok(true),
+        Ok(None) => Ok(false),
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(true) => {
+            tx.commit().await.unwrap();
+            (StatusCode::OK, Json(json!({
+                "status": "success",
+                "message": "Address deleted"
+            })))
+        },
+        Ok(false) => {
+            tx.rollback().await.unwrap();
+            addressnotfound()
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
+    }
+}
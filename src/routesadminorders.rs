@@ -0,0 +1,358 @@
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
+use bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{self, FromRow, Pool, Postgres};
+use uuid::Uuid;
+use crate::{AppState, customerrors::{badrequest, servererror}, mware::ClaimsAccessToken, notifications, orderroutes::{fetchorderitems, fetchvatsummary}, orderstatus::{self, StatusChangeError}, paymentapi};
+
+#[derive(Deserialize, Debug)]
+
+pub struct AdminOrderQuery {
+    status: Option<String>,
+    from: Option<chrono::NaiveDate>,
+    to: Option<chrono::NaiveDate>,
+    customer: Option<String>,
+    mintotal: Option<BigDecimal>,
+    maxtotal: Option<BigDecimal>,
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct StatusChange {
+    status: String,
+    note: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct Shipment {
+    carrier: Option<String>,
+    trackingnumber: String,
+    note: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct NewOrderNote {
+    note: String
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct AdminOrderSummary {
+    orderid: i64,
+    status: String,
+    total: BigDecimal,
+    itemcount: i64,
+    userid: Option<Uuid>,
+    guest: bool,
+    email: Option<String>,
+    fullname: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    paid_at: Option<chrono::DateTime<chrono::Utc>>,
+    shipped_at: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct AdminOrder {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    summary: AdminOrderSummary,
+    subtotal: Option<BigDecimal>,
+    discountcode: Option<String>,
+    discounttotal: BigDecimal,
+    shippingmethod: Option<String>,
+    shippingcost: BigDecimal,
+    shipname: Option<String>,
+    shipaddress: Option<String>,
+    shipcity: Option<String>,
+    shippostcode: Option<String>,
+    billname: Option<String>,
+    billaddress: Option<String>,
+    billcity: Option<String>,
+    billpostcode: Option<String>,
+    carrier: Option<String>,
+    trackingnumber: Option<String>,
+    delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    paymentintentid: Option<String>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct OrderRefund {
+    returnid: Uuid,
+    status: String,
+    refundamount: Option<BigDecimal>,
+    refundstatus: Option<String>,
+    refundid: Option<String>,
+    refunderror: Option<String>,
+    decided_at: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct OrderNote {
+    noteid: Uuid,
+    author: Uuid,
+    note: String,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct StatusHistory {
+    fromstatus: String,
+    tostatus: String,
+    actor: Option<Uuid>,
+    note: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+const ADMIN_ORDER_SELECT: &str = "SELECT orderdet.orderid, orderdet.status, orderdet.total,
+    (SELECT COALESCE(SUM(quantity), 0) FROM listitems WHERE listitems.orderidretr = orderdet.orderid)::BIGINT AS itemcount,
+    orderdet.userid, orderdet.guestid IS NOT NULL AS guest,
+    COALESCE(users.email, guestcustomers.email) AS email,
+    COALESCE(users.fullname, guestcustomers.fullname) AS fullname,
+    orderdet.created_at, orderdet.paid_at, orderdet.shipped_at";
+
+const ADMIN_ORDER_JOINS: &str = "FROM orderdet
+    LEFT JOIN users ON users.usid = orderdet.userid
+    LEFT JOIN guestcustomers ON guestcustomers.guestid = orderdet.guestid";
+
+
+fn statuschangeerror(e: StatusChangeError, to: &str) -> (StatusCode, Json<Value>) {
+    match e {
+        StatusChangeError::NotFound => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Order not found",
+        }))),
+        StatusChangeError::NotAllowed { from } => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": format!("An order cannot move from {} to {}", from, to),
+        }))),
+        StatusChangeError::Database(e) => servererror(e),
+    }
+}
+
+async fn fetchadminorder(db: &Pool<Postgres>, orderid: i64) -> Result<Option<AdminOrder>, sqlx::Error> {
+    sqlx::query_as::<_, AdminOrder>(&format!(
+        "{}, orderdet.subtotal, orderdet.discountcode, orderdet.discounttotal, orderdet.shippingmethod, orderdet.shippingcost,
+        orderdet.shipname, orderdet.shipaddress, orderdet.shipcity, orderdet.shippostcode,
+        orderdet.billname, orderdet.billaddress, orderdet.billcity, orderdet.billpostcode,
+        orderdet.carrier, orderdet.trackingnumber, orderdet.delivered_at, orderdet.paymentintentid
+        {} WHERE orderdet.orderid = $1", ADMIN_ORDER_SELECT, ADMIN_ORDER_JOINS))
+        .bind(orderid)
+        .fetch_optional(db)
+        .await
+}
+
+fn statusemail(state: &AppState, orderid: i64, status: &str, tracking: Option<(&Option<String>, &str)>) {
+    let body = match (status, tracking) {
+        ("shipped", Some((Some(carrier), trackingnumber))) => format!("Your order {} is on its way with {}. Your tracking number is {}.", orderid, carrier, trackingnumber),
+        ("shipped", Some((None, trackingnumber))) => format!("Your order {} is on its way. Your tracking number is {}.", orderid, trackingnumber),
+        ("shipped", None) => format!("Your order {} is on its way.", orderid),
+        ("delivered", _) => format!("Your order {} has been delivered. If anything is not right you can request a return from your account.", orderid),
+        ("cancelled", _) => format!("Your order {} has been cancelled and no payment has been taken.", orderid),
+        _ => return,
+    };
+    notifications::orderemail(state, orderid, format!("Order {} update", orderid), body);
+}
+
+
+//Admin orders route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// The customer filter matches part of the name or email, or the exact user id
+pub async fn fetchadminordershandler(State(state): State<AppState>, Query(query): Query<AdminOrderQuery>) -> impl IntoResponse {
+    if matches!(&query.status, Some(status) if !orderstatus::STATUSES.contains(&status.as_str())) {
+        return badrequest("status is not a valid order status")
+    }
+    let customer = query.customer.as_deref().map(str::trim).filter(|customer| !customer.is_empty());
+    let response = sqlx::query_as::<_, AdminOrderSummary>(&format!(
+        "{} {}
+        WHERE ($1::text IS NULL OR orderdet.status = $1)
+        AND ($2::date IS NULL OR orderdet.created_at >= $2::date)
+        AND ($3::date IS NULL OR orderdet.created_at < $3::date + 1)
+        AND ($4::text IS NULL
+            OR orderdet.userid::text = $4
+            OR strpos(lower(COALESCE(users.email, guestcustomers.email)), lower($4)) > 0
+            OR strpos(lower(COALESCE(users.fullname, guestcustomers.fullname)), lower($4)) > 0)
+        AND ($5::numeric IS NULL OR orderdet.total >= $5)
+        AND ($6::numeric IS NULL OR orderdet.total <= $6)
+        ORDER BY orderdet.created_at DESC, orderdet.orderid DESC
+        LIMIT $7 OFFSET $8", ADMIN_ORDER_SELECT, ADMIN_ORDER_JOINS))
+        .bind(&query.status)
+        .bind(query.from)
+        .bind(query.to)
+        .bind(customer)
+        .bind(&query.mintotal)
+        .bind(&query.maxtotal)
+        .bind(query.limit.unwrap_or(50).clamp(1, 200))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(orders) => (StatusCode::OK, Json(json!({
+            "orders": orders
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn fetchadminorderhandler(State(state): State<AppState>, Path(orderid): Path<i64>) -> impl IntoResponse {
+    let db = &state.database.db;
+    let order = match fetchadminorder(db, orderid).await {
+        Ok(Some(order)) => order,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Order not found",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    let items = match fetchorderitems(db, orderid).await {
+        Ok(items) => items,
+        Err(e) => return servererror(e),
+    };
+    let vat = match fetchvatsummary(db, orderid).await {
+        Ok(vat) => vat,
+        Err(e) => return servererror(e),
+    };
+    let refunds = sqlx::query_as::<_, OrderRefund>(
+        "SELECT returnid, status, refundamount, refundstatus, refundid, refunderror, decided_at
+        FROM returns WHERE orderid = $1 AND refundstatus IS NOT NULL ORDER BY decided_at")
+        .bind(orderid)
+        .fetch_all(db)
+        .await;
+    let refunds = match refunds {
+        Ok(refunds) => refunds,
+        Err(e) => return servererror(e),
+    };
+    let notes = sqlx::query_as::<_, OrderNote>("SELECT noteid, author, note, created_at FROM ordernotes WHERE orderid = $1 ORDER BY created_at")
+        .bind(orderid)
+        .fetch_all(db)
+        .await;
+    let notes = match notes {
+        Ok(notes) => notes,
+        Err(e) => return servererror(e),
+    };
+    let history = sqlx::query_as::<_, StatusHistory>(
+        "SELECT fromstatus, tostatus, actor, note, created_at FROM orderstatushistory WHERE orderid = $1 ORDER BY created_at")
+        .bind(orderid)
+        .fetch_all(db)
+        .await;
+    let history = match history {
+        Ok(history) => history,
+        Err(e) => return servererror(e),
+    };
+    let payment = json!({
+        "paymentintentid": order.paymentintentid,
+        "paid_at": order.summary.paid_at,
+        "refunds": refunds
+    });
+    (StatusCode::OK, Json(json!({
+        "order": order,
+        "items": items,
+        "vat": vat,
+        "payment": payment,
+        "notes": notes,
+        "history": history
+    })))
+}
+
+
+//Order notes route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn addordernotehandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>, Json(req): Json<NewOrderNote>) -> impl IntoResponse {
+    if req.note.trim().is_empty() {
+        return badrequest("note cannot be empty")
+    }
+    let response = sqlx::query_as::<_, OrderNote>(
+        "INSERT INTO ordernotes (noteid, orderid, author, note) VALUES ($1, $2, $3, $4)
+        RETURNING noteid, author, note, created_at")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(orderid)
+        .bind(claims.sub)
+        .bind(req.note.trim())
+        .fetch_one(&state.database.db)
+        .await;
+    match response {
+        Ok(note) => (StatusCode::CREATED, Json(json!({
+            "status": "success",
+            "note": note
+        }))),
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Order not found",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Order status routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn changeorderstatushandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>, Json(req): Json<StatusChange>) -> impl IntoResponse {
+    if !orderstatus::STATUSES.contains(&req.status.as_str()) {
+        return badrequest("status is not a valid order status")
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    let note = req.note.as_deref().map(str::trim);
+    match orderstatus::changestatus(&mut tx, orderid, &req.status, claims.sub, note).await {
+        Ok(from) => {
+            tx.commit().await.unwrap();
+            if req.status == "cancelled" {
+                paymentapi::cancelorderpayment(&state, orderid).await;
+            }
+            statusemail(&state, orderid, &req.status, None);
+            (StatusCode::OK, Json(json!({
+                "status": "success",
+                "orderid": orderid,
+                "from": from,
+                "to": req.status
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            statuschangeerror(e, &req.status)
+        }
+    }
+}
+
+pub async fn shiporderhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>, Json(req): Json<Shipment>) -> impl IntoResponse {
+    let trackingnumber = req.trackingnumber.trim();
+    if trackingnumber.is_empty() {
+        return badrequest("trackingnumber is required")
+    }
+    let carrier = req.carrier.as_deref().map(str::trim).filter(|carrier| !carrier.is_empty()).map(str::to_string);
+    let mut tx = state.database.db.begin().await.unwrap();
+    // Tracking is set first so the shipped event carries it. Both roll back if the order cannot ship.
+    let response = match sqlx::query("UPDATE orderdet SET carrier = $2, trackingnumber = $3 WHERE orderid = $1")
+        .bind(orderid)
+        .bind(&carrier)
+        .bind(trackingnumber)
+        .execute(&mut tx)
+        .await {
+        Ok(_) => orderstatus::changestatus(&mut tx, orderid, "shipped", claims.sub, req.note.as_deref().map(str::trim)).await,
+        Err(e) => Err(StatusChangeError::Database(e)),
+    };
+    match response {
+        Ok(_) => {
+            tx.commit().await.unwrap();
+            statusemail(&state, orderid, "shipped", Some((&carrier, trackingnumber)));
+            match fetchadminorder(&state.database.db, orderid).await {
+                Ok(order) => (StatusCode::OK, Json(json!({
+                    "status": "success",
+                    "order": order
+                }))),
+                Err(e) => servererror(e),
+            }
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            statuschangeerror(e, "shipped")
+        }
+    }
+}
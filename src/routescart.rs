@@ -0,0 +1,395 @@
+use axum::{Json, Extension, extract::{Path, State}, response::IntoResponse, http::{HeaderMap, StatusCode}};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::{AppState, customerrors::servererror, routesaddresses::addressforcheckout, shipping::normaliseukpostcode, cart::{self, Cart, CartOwner, CART_LINE_CONFLICT}, checkout::{self, CheckoutError, Customer, ShippingAddress}, discounts::DiscountError, mware::ClaimsAccessToken, pricing::parseprice};
+
+// Guests are identified by this header; the token is issued on their first add
+pub const CART_TOKEN_HEADER: &str = "x-cart-token";
+
+#[derive(Deserialize, Debug)]
+
+pub struct CartItemAdd {
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    quantity: i32
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct CartItemUpdate {
+    quantity: i32
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct CheckoutRequest {
+    shippingmethodid: Uuid,
+    shipto: Option<ShippingAddress>,
+    addressid: Option<Uuid>,
+    billingaddressid: Option<Uuid>,
+    discountcode: Option<String>
+}
+
+
+pub fn carttoken(headers: &HeaderMap) -> Option<String> {
+    headers.get(CART_TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+pub fn cartowner(claims: &Option<Extension<ClaimsAccessToken>>, headers: &HeaderMap) -> Option<CartOwner> {
+    match claims {
+        Some(Extension(claims)) => Some(CartOwner::User(claims.sub)),
+        None => carttoken(headers).map(CartOwner::Guest),
+    }
+}
+
+// Every cart response is revalidated. Price changes stay flagged until confirmed.
+async fn cartresponse(state: &AppState, status: StatusCode, cartid: Option<Uuid>, owner: &Option<CartOwner>) -> (StatusCode, Json<Value>) {
+    let cart = match cartid {
+        Some(cartid) => cart::loadcart(&state.database.db, cartid).await,
+        None => Ok(Cart::empty()),
+    };
+    let cart = match cart {
+        Ok(cart) => cart,
+        Err(e) => return servererror(e),
+    };
+    let carttoken = match owner {
+        Some(CartOwner::Guest(token)) => Some(token.as_str()),
+        _ => None,
+    };
+    (status, Json(json!({
+        "cart": cart,
+        "carttoken": carttoken
+    })))
+}
+
+
+//Cart routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchcarthandler(State(state): State<AppState>, claims: Option<Extension<ClaimsAccessToken>>, headers: HeaderMap) -> impl IntoResponse {
+    let owner = cartowner(&claims, &headers);
+    let cartid = match &owner {
+        Some(owner) => cart::findcart(&state.database.db, owner).await,
+        None => Ok(None),
+    };
+    match cartid {
+        Ok(cartid) => cartresponse(&state, StatusCode::OK, cartid, &owner).await,
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn additemhandler(State(state): State<AppState>, claims: Option<Extension<ClaimsAccessToken>>, headers: HeaderMap, Json(req): Json<CartItemAdd>) -> impl IntoResponse {
+    if req.quantity <= 0 {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Quantity must be at least 1",
+        })))
+    }
+    let owner = cartowner(&claims, &headers)
+        .unwrap_or_else(|| CartOwner::Guest(uuid::Uuid::new_v4().simple().to_string()));
+    // Products that have variants must be added by variant
+    let product = sqlx::query_as::<_, (String, bool, Option<String>)>(
+        "SELECT products.price,
+        EXISTS(SELECT 1 FROM productvariants WHERE productvariants.productid = products.productid),
+        (SELECT price FROM productvariants WHERE productvariants.productid = products.productid AND productvariants.variantid = $2)
+        FROM products
+        WHERE products.productid = $1 AND products.status = 'active'")
+        .bind(req.productid)
+        .bind(req.variantid)
+        .fetch_optional(&state.database.db)
+        .await;
+    let price = match (product, req.variantid) {
+        (Ok(Some((_, true, Some(variantprice)))), Some(_)) => variantprice,
+        (Ok(Some((price, false, _))), None) => price,
+        (Ok(Some(_)), _) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "A valid variantid is required for this product",
+        }))),
+        (Ok(None), _) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Product not found",
+        }))),
+        (Err(e), _) => return servererror(e),
+    };
+    if parseprice(&price).is_none() {
+        return (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "This product is not available to buy right now",
+        })))
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    let cartid = match cart::findorcreatecart(&mut tx, &owner).await {
+        Ok(cartid) => cartid,
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    };
+    let response = sqlx::query(&format!(
+        "INSERT INTO cartitems (cartitemid, cartid, productid, variantid, quantity, addedprice)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT {} DO UPDATE SET quantity = cartitems.quantity + EXCLUDED.quantity, addedprice = EXCLUDED.addedprice", CART_LINE_CONFLICT))
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(cartid)
+        .bind(req.productid)
+        .bind(req.variantid)
+        .bind(req.quantity)
+        .bind(price)
+        .execute(&mut tx)
+        .await;
+    let response = match response {
+        Ok(_) => cart::startsession(&mut tx, cartid).await,
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(_) => {
+            tx.commit().await.unwrap();
+            cartresponse(&state, StatusCode::OK, Some(cartid), &Some(owner)).await
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
+    }
+}
+
+// A quantity of zero removes the line
+pub async fn updateitemhandler(State(state): State<AppState>, claims: Option<Extension<ClaimsAccessToken>>, headers: HeaderMap, Path(cartitemid): Path<Uuid>, Json(req): Json<CartItemUpdate>) -> impl IntoResponse {
+    if req.quantity < 0 {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Quantity cannot be negative",
+        })))
+    }
+    let owner = cartowner(&claims, &headers);
+    let cartid = match &owner {
+        Some(owner) => cart::findcart(&state.database.db, owner).await,
+        None => Ok(None),
+    };
+    let cartid = match cartid {
+        Ok(Some(cartid)) => cartid,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Cart item not found",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    let response = if req.quantity == 0 {
+        sqlx::query("DELETE FROM cartitems WHERE cartitemid = $1 AND cartid = $2")
+            .bind(cartitemid)
+            .bind(cartid)
+            .execute(&state.database.db)
+            .await
+    } else {
+        sqlx::query("UPDATE cartitems SET quantity = $1 WHERE cartitemid = $2 AND cartid = $3")
+            .bind(req.quantity)
+            .bind(cartitemid)
+            .bind(cartid)
+            .execute(&state.database.db)
+            .await
+    };
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Cart item not found",
+        }))),
+        Ok(_) => cartresponse(&state, StatusCode::OK, Some(cartid), &owner).await,
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn removeitemhandler(State(state): State<AppState>, claims: Option<Extension<ClaimsAccessToken>>, headers: HeaderMap, Path(cartitemid): Path<Uuid>) -> impl IntoResponse {
+    let owner = cartowner(&claims, &headers);
+    let cartid = match &owner {
+        Some(owner) => cart::findcart(&state.database.db, owner).await,
+        None => Ok(None),
+    };
+    let cartid = match cartid {
+        Ok(Some(cartid)) => cartid,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Cart item not found",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    let response = sqlx::query("DELETE FROM cartitems WHERE cartitemid = $1 AND cartid = $2")
+        .bind(cartitemid)
+        .bind(cartid)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Cart item not found",
+        }))),
+        Ok(_) => cartresponse(&state, StatusCode::OK, Some(cartid), &owner).await,
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn clearcarthandler(State(state): State<AppState>, claims: Option<Extension<ClaimsAccessToken>>, headers: HeaderMap) -> impl IntoResponse {
+    let owner = cartowner(&claims, &headers);
+    let cartid = match &owner {
+        Some(owner) => cart::findcart(&state.database.db, owner).await,
+        None => Ok(None),
+    };
+    let cartid = match cartid {
+        Ok(cartid) => cartid,
+        Err(e) => return servererror(e),
+    };
+    if let Some(cartid) = cartid {
+        let response = sqlx::query("DELETE FROM cartitems WHERE cartid = $1")
+            .bind(cartid)
+            .execute(&state.database.db)
+            .await;
+        if let Err(e) = response {
+            return servererror(e)
+        }
+    }
+    cartresponse(&state, StatusCode::OK, cartid, &owner).await
+}
+
+pub async fn acknowledgepriceshandler(State(state): State<AppState>, claims: Option<Extension<ClaimsAccessToken>>, headers: HeaderMap) -> impl IntoResponse {
+    let owner = cartowner(&claims, &headers);
+    let cartid = match &owner {
+        Some(owner) => cart::findcart(&state.database.db, owner).await,
+        None => Ok(None),
+    };
+    let cartid = match cartid {
+        Ok(cartid) => cartid,
+        Err(e) => return servererror(e),
+    };
+    if let Some(cartid) = cartid {
+        if let Err(e) = cart::acknowledgeprices(&state.database.db, cartid).await {
+            return servererror(e)
+        }
+    }
+    cartresponse(&state, StatusCode::OK, cartid, &owner).await
+}
+
+
+//Checkout route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Creates a pending order from the cart and reserves its stock. The client then
+// creates a payment intent for the returned orderid.
+pub async fn checkouthandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Json(req): Json<CheckoutRequest>) -> impl IntoResponse {
+    let cartid = match cart::findcart(&state.database.db, &CartOwner::User(claims.sub)).await {
+        Ok(Some(cartid)) => cartid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Cart is empty",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    // An address in the request wins, then the chosen address book entry, then the default
+    let shipto = match req.shipto {
+        Some(shipto) => Ok(Some(shipto)),
+        None => addressforcheckout(&state.database.db, claims.sub, req.addressid, false).await,
+    };
+    let shipto = match shipto {
+        Ok(Some(shipto)) if ![&shipto.fullname, &shipto.address, &shipto.city, &shipto.postcode].iter().any(|field| field.trim().is_empty()) => shipto,
+        Ok(Some(_)) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "fullname, address, city and postcode are required",
+        }))),
+        Ok(None) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "A shipping address is required",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    if normaliseukpostcode(&shipto.postcode).is_none() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "postcode is not a valid UK postcode",
+        })))
+    }
+    // Billing defaults to the default billing address and then to the shipping address
+    let billto = match addressforcheckout(&state.database.db, claims.sub, req.billingaddressid, true).await {
+        Ok(billto) => billto,
+        Err(e) => return servererror(e),
+    };
+    if req.billingaddressid.is_some() && billto.is_none() {
+        return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Billing address not found",
+        })))
+    }
+    let billto = billto.as_ref().unwrap_or(&shipto);
+    let mut tx = state.database.db.begin().await.unwrap();
+    let order = checkout::placeorder(&mut tx, cartid, &Customer::User(claims.sub), &shipto, req.shippingmethodid, req.discountcode.as_deref(), state.tax.pricesincludevat).await;
+    let order = match order {
+        Ok(order) => sqlx::query("UPDATE orderdet SET billname = $1, billaddress = $2, billcity = $3, billpostcode = $4 WHERE orderid = $5")
+            .bind(billto.fullname.trim())
+            .bind(billto.address.trim())
+            .bind(billto.city.trim())
+            .bind(billto.postcode.trim().to_uppercase())
+            .bind(order.orderid)
+            .execute(&mut tx)
+            .await
+            .map(|_| order)
+            .map_err(CheckoutError::Database),
+        Err(e) => Err(e),
+    };
+    match order {
+        Ok(order) => {
+            tx.commit().await.unwrap();
+            (StatusCode::CREATED, Json(json!({
+                "status": "success",
+                "orderid": order.orderid,
+                "subtotal": order.subtotal,
+                "discounttotal": order.discounttotal,
+                "shippingcost": order.shippingcost,
+                "nettotal": order.nettotal,
+                "taxtotal": order.taxtotal,
+                "total": order.total,
+                "reservedminutes": crate::inventory::RESERVATION_TTL_MINUTES
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            checkouterror(e)
+        }
+    }
+}
+
+pub fn checkouterror(e: CheckoutError) -> (StatusCode, Json<Value>) {
+    match e {
+        CheckoutError::Empty => (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Cart is empty",
+        }))),
+        CheckoutError::Unavailable { productid, variantid } => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "A product in the cart is no longer available",
+            "productid": productid,
+            "variantid": variantid,
+        }))),
+        CheckoutError::PriceChanged { productid, variantid } => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "Prices in the cart have changed and need to be confirmed",
+            "productid": productid,
+            "variantid": variantid,
+        }))),
+        CheckoutError::Insufficient { productid, variantid, available } => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "Not enough stock",
+            "productid": productid,
+            "variantid": variantid,
+            "availabletosell": available,
+        }))),
+        CheckoutError::Discount(e) => (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({
+            "status": "error",
+            "message": e.message(),
+            "minbasket": match e { DiscountError::MinimumNotMet { minbasket } => Some(minbasket), _ => None },
+        }))),
+        CheckoutError::Shipping(e) => (StatusCode::UNPROCESSABLE_ENTITY, Json(json!({
+            "status": "error",
+            "message": e.message(),
+        }))),
+        CheckoutError::Database(e) => servererror(e),
+    }
+}
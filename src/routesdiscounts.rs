@@ -0,0 +1,162 @@
+use axum::{Json, extract::{Path, State}, response::IntoResponse, http::StatusCode};
+use bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow};
+use uuid::Uuid;
+use crate::{AppState, customerrors::{badrequest, servererror}, discounts::DiscountCode, pricing::DiscountKind};
+
+#[derive(Deserialize, Debug)]
+
+pub struct NewDiscountCode {
+    code: String,
+    kind: String,
+    value: Option<BigDecimal>,
+    starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    maxuses: Option<i32>,
+    maxusespercustomer: Option<i32>,
+    minbasket: Option<BigDecimal>,
+    productids: Option<Vec<Uuid>>,
+    categories: Option<Vec<String>>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct DiscountCodeUpdate {
+    active: Option<bool>,
+    starts_at: Option<chrono::DateTime<chrono::Utc>>,
+    ends_at: Option<chrono::DateTime<chrono::Utc>>,
+    maxuses: Option<i32>,
+    maxusespercustomer: Option<i32>,
+    minbasket: Option<BigDecimal>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct DiscountCodeUsage {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    discount: DiscountCode,
+    uses: i64,
+    discounted: BigDecimal
+}
+
+const DISCOUNT_USAGE_SQL: &str = "SELECT discountcodes.*,
+    COUNT(orderdet.orderid) AS uses,
+    COALESCE(SUM(discountredemptions.amount) FILTER (WHERE orderdet.orderid IS NOT NULL), 0) AS discounted
+    FROM discountcodes
+    LEFT JOIN discountredemptions ON discountredemptions.codeid = discountcodes.codeid
+    LEFT JOIN orderdet ON orderdet.orderid = discountredemptions.orderid AND orderdet.status NOT IN ('expired', 'cancelled')";
+
+
+
+//Create discount code route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn creatediscounthandler(State(state): State<AppState>, Json(req): Json<NewDiscountCode>) -> impl IntoResponse {
+    let code = req.code.trim().to_uppercase();
+    if code.is_empty() || code.contains(char::is_whitespace) {
+        return badrequest("code cannot be empty or contain spaces")
+    }
+    let kind = match DiscountKind::from_str(&req.kind) {
+        Some(kind) => kind,
+        None => return badrequest("kind must be one of percentage, fixed, freeshipping"),
+    };
+    let value = req.value.unwrap_or_else(|| BigDecimal::from(0));
+    if value < BigDecimal::from(0) {
+        return badrequest("value cannot be negative")
+    }
+    if kind == DiscountKind::Percentage && value > BigDecimal::from(100) {
+        return badrequest("a percentage discount cannot exceed 100")
+    }
+    if kind != DiscountKind::FreeShipping && value == BigDecimal::from(0) {
+        return badrequest("value is required")
+    }
+    if matches!((req.starts_at, req.ends_at), (Some(starts_at), Some(ends_at)) if ends_at <= starts_at) {
+        return badrequest("ends_at must be after starts_at")
+    }
+    if matches!(req.maxuses, Some(maxuses) if maxuses < 1) || matches!(req.maxusespercustomer, Some(maxuses) if maxuses < 1) {
+        return badrequest("usage limits must be at least 1")
+    }
+    let response = sqlx::query_as::<_, DiscountCode>(
+        "INSERT INTO discountcodes (codeid, code, kind, value, starts_at, ends_at, maxuses, maxusespercustomer, minbasket, productids, categories)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING *")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(&code)
+        .bind(kind.as_str())
+        .bind(&value)
+        .bind(req.starts_at)
+        .bind(req.ends_at)
+        .bind(req.maxuses)
+        .bind(req.maxusespercustomer)
+        .bind(&req.minbasket)
+        .bind(req.productids.unwrap_or_default())
+        .bind(req.categories.unwrap_or_default())
+        .fetch_one(&state.database.db)
+        .await;
+    match response {
+        Ok(discount) => (StatusCode::CREATED, Json(json!({
+            "status": "success",
+            "discount": discount
+        }))),
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "A discount code with this name already exists",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Fetch discount codes route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchdiscountshandler(State(state): State<AppState>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, DiscountCodeUsage>(&format!(
+        "{} GROUP BY discountcodes.codeid ORDER BY discountcodes.created_at DESC", DISCOUNT_USAGE_SQL))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(discounts) => (StatusCode::OK, Json(json!({
+            "discounts": discounts
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Update discount code route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn updatediscounthandler(State(state): State<AppState>, Path(codeid): Path<Uuid>, Json(req): Json<DiscountCodeUpdate>) -> impl IntoResponse {
+    if matches!(req.maxuses, Some(maxuses) if maxuses < 1) || matches!(req.maxusespercustomer, Some(maxuses) if maxuses < 1) {
+        return badrequest("usage limits must be at least 1")
+    }
+    let response = sqlx::query_as::<_, DiscountCode>(
+        "UPDATE discountcodes SET
+        active = COALESCE($2, active),
+        starts_at = COALESCE($3, starts_at),
+        ends_at = COALESCE($4, ends_at),
+        maxuses = COALESCE($5, maxuses),
+        maxusespercustomer = COALESCE($6, maxusespercustomer),
+        minbasket = COALESCE($7, minbasket)
+        WHERE codeid = $1 RETURNING *")
+        .bind(codeid)
+        .bind(req.active)
+        .bind(req.starts_at)
+        .bind(req.ends_at)
+        .bind(req.maxuses)
+        .bind(req.maxusespercustomer)
+        .bind(&req.minbasket)
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some(discount)) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "discount": discount
+        }))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Discount code not found",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
@@ -0,0 +1,303 @@
+use axum::{Json, Extension, extract::{ConnectInfo, Path, State}, response::{IntoResponse, Response}, http::{HeaderMap, StatusCode}};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::{self, FromRow};
+use std::net::SocketAddr;
+use uuid::Uuid;
+use crate::{AppState, cart::{self, CartOwner}, checkout::{self, Customer, ShippingAddress}, customerrors::servererror, inventory, loginsecurity, mailer, mware::ClaimsAccessToken, orderroutes::{fetchorderitems, fetchvatsummary}, routescart::{carttoken, checkouterror}, shipping::normaliseukpostcode};
+
+#[derive(Deserialize, Debug)]
+
+pub struct GuestCheckout {
+    email: String,
+    shippingmethodid: Uuid,
+    discountcode: Option<String>,
+    #[serde(flatten)]
+    shipto: ShippingAddress
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct GuestLookup {
+    email: String
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct GuestClaim {
+    lookuptoken: String
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct GuestOrder {
+    pub orderid: i64,
+    pub status: String,
+    pub total: bigdecimal::BigDecimal,
+    pub email: String,
+    pub fullname: String,
+    pub shipname: Option<String>,
+    pub shipaddress: Option<String>,
+    pub shipcity: Option<String>,
+    pub shippostcode: Option<String>,
+    pub shippingmethod: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>
+}
+
+const GUEST_ORDER_SQL: &str = "SELECT orderdet.orderid, orderdet.status, orderdet.total, guestcustomers.email, guestcustomers.fullname,
+    orderdet.shipname, orderdet.shipaddress, orderdet.shipcity, orderdet.shippostcode, orderdet.shippingmethod, orderdet.created_at
+    FROM orderdet
+    INNER JOIN guestcustomers ON guestcustomers.guestid = orderdet.guestid";
+
+// Lookups allowed per window from one IP and for one email address
+const LOOKUP_WINDOW_MINUTES: i32 = 60;
+const LOOKUP_IP_LIMIT: i32 = 10;
+const LOOKUP_EMAIL_LIMIT: i32 = 3;
+
+
+fn newlookuptoken() -> String {
+    format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+pub fn hashlookuptoken(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub async fn findguestorder(state: &AppState, token: &str) -> Result<Option<GuestOrder>, sqlx::Error> {
+    sqlx::query_as::<_, GuestOrder>(&format!(
+        "{} WHERE orderdet.lookuptokenhash = $1
+        OR orderdet.orderid = (SELECT orderid FROM guestlookuptokens WHERE tokenhash = $1)", GUEST_ORDER_SQL))
+        .bind(hashlookuptoken(token))
+        .fetch_optional(&state.database.db)
+        .await
+}
+
+// Counts a lookup against the key. Once it is over the limit, returns the seconds
+// until the window ends.
+async fn lookupthrottle(state: &AppState, throttlekey: &str, limit: i32) -> Result<Option<i64>, sqlx::Error> {
+    let (attempts, windowstart) = sqlx::query_as::<_, (i32, chrono::DateTime<chrono::Utc>)>(
+        "INSERT INTO guestlookupattempts (throttlekey, attempts) VALUES ($1, 1)
+        ON CONFLICT (throttlekey) DO UPDATE SET
+        attempts = CASE WHEN guestlookupattempts.windowstart < now() - make_interval(mins => $2) THEN 1 ELSE guestlookupattempts.attempts + 1 END,
+        windowstart = CASE WHEN guestlookupattempts.windowstart < now() - make_interval(mins => $2) THEN now() ELSE guestlookupattempts.windowstart END
+        RETURNING attempts, windowstart")
+        .bind(throttlekey)
+        .bind(LOOKUP_WINDOW_MINUTES)
+        .fetch_one(&state.database.db)
+        .await?;
+    let resetat = windowstart + chrono::Duration::minutes(LOOKUP_WINDOW_MINUTES as i64);
+    Ok(if attempts > limit { Some((resetat - chrono::Utc::now()).num_seconds().max(1)) } else { None })
+}
+
+fn sendlookupemail(state: &AppState, email: String, fullname: String, links: Vec<(i64, String)>) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let lines: Vec<String> = links.iter()
+            .map(|(orderid, token)| format!("Order {}: {}/orders/lookup/{}", orderid, state.mailer.siteurl, token))
+            .collect();
+        let body = format!("Hi {},\n\nUse these links to check on your orders:\n\n{}\n", fullname, lines.join("\n"));
+        if let Err(e) = mailer::sendmail(&state.mailer, &email, "Your order details", body).await {
+            println!("order lookup email failed: {}", e);
+        }
+    });
+}
+
+
+//Guest checkout route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn guestcheckouthandler(State(state): State<AppState>, headers: HeaderMap, Json(req): Json<GuestCheckout>) -> impl IntoResponse {
+    if req.email.trim().parse::<lettre::Address>().is_err() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "A valid email address is required",
+        })))
+    }
+    if [&req.shipto.fullname, &req.shipto.address, &req.shipto.city, &req.shipto.postcode].iter().any(|field| field.trim().is_empty()) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "fullname, address, city and postcode are required",
+        })))
+    }
+    if normaliseukpostcode(&req.shipto.postcode).is_none() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "postcode is not a valid UK postcode",
+        })))
+    }
+    let cartid = match carttoken(&headers) {
+        Some(token) => cart::findcart(&state.database.db, &CartOwner::Guest(token)).await,
+        None => Ok(None),
+    };
+    let cartid = match cartid {
+        Ok(Some(cartid)) => cartid,
+        Ok(None) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Cart is empty",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    let email = req.email.trim().to_string();
+    let mut tx = state.database.db.begin().await.unwrap();
+    let guest = sqlx::query_as::<_, (Uuid,)>(
+        "INSERT INTO guestcustomers (guestid, email, fullname) VALUES ($1, $2, $3)
+        ON CONFLICT ((lower(email))) DO UPDATE SET fullname = EXCLUDED.fullname
+        RETURNING guestid")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(&email)
+        .bind(req.shipto.fullname.trim())
+        .fetch_one(&mut tx)
+        .await;
+    let guestid = match guest {
+        Ok((guestid,)) => guestid,
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    };
+    let order = match checkout::placeorder(&mut tx, cartid, &Customer::Guest(guestid), &req.shipto, req.shippingmethodid, req.discountcode.as_deref(), state.tax.pricesincludevat).await {
+        Ok(order) => order,
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return checkouterror(e)
+        }
+    };
+    let lookuptoken = newlookuptoken();
+    let response = sqlx::query("UPDATE orderdet SET lookuptokenhash = $1 WHERE orderid = $2")
+        .bind(hashlookuptoken(&lookuptoken))
+        .bind(order.orderid)
+        .execute(&mut tx)
+        .await;
+    if let Err(e) = response {
+        tx.rollback().await.unwrap();
+        return servererror(e)
+    }
+    tx.commit().await.unwrap();
+    sendlookupemail(&state, email, req.shipto.fullname.trim().to_string(), vec![(order.orderid, lookuptoken.clone())]);
+    (StatusCode::CREATED, Json(json!({
+        "status": "success",
+        "orderid": order.orderid,
+        "subtotal": order.subtotal,
+        "discounttotal": order.discounttotal,
+        "shippingcost": order.shippingcost,
+        "nettotal": order.nettotal,
+        "taxtotal": order.taxtotal,
+        "total": order.total,
+        "lookuptoken": lookuptoken,
+        "reservedminutes": inventory::RESERVATION_TTL_MINUTES
+    })))
+}
+
+
+//Guest order lookup routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn guestorderhandler(State(state): State<AppState>, Path(token): Path<String>) -> impl IntoResponse {
+    let order = match findguestorder(&state, &token).await {
+        Ok(Some(order)) => order,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Order not found",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    let response = match fetchorderitems(&state.database.db, order.orderid).await {
+        Ok(items) => fetchvatsummary(&state.database.db, order.orderid).await.map(|vat| (items, vat)),
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok((items, vat)) => (StatusCode::OK, Json(json!({
+            "order": order,
+            "items": items,
+            "vat": vat
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+// Only a hash of each token is stored, so fresh links are issued and emailed.
+// Links sent before keep working. The response is the same whether or not the
+// email has orders.
+pub async fn guestorderlookuphandler(State(state): State<AppState>, headers: HeaderMap, ConnectInfo(addr): ConnectInfo<SocketAddr>, Json(req): Json<GuestLookup>) -> Response {
+    let email = req.email.trim().to_lowercase();
+    let ip = loginsecurity::clientip(&state.login, &headers, addr);
+    let throttled = match lookupthrottle(&state, &format!("ip:{}", ip), LOOKUP_IP_LIMIT).await {
+        Ok(None) => lookupthrottle(&state, &format!("email:{}", email), LOOKUP_EMAIL_LIMIT).await,
+        response => response,
+    };
+    match throttled {
+        Ok(None) => {},
+        Ok(Some(retryafter)) => {
+            let mut headers = HeaderMap::new();
+            headers.insert("Retry-After", retryafter.to_string().parse().unwrap());
+            return (StatusCode::TOO_MANY_REQUESTS, headers, Json(json!({
+                "status": "error",
+                "message": "Too many order lookups, please try again later",
+                "retry_after": retryafter,
+            }))).into_response()
+        },
+        Err(e) => return servererror(e).into_response(),
+    }
+    let orders = sqlx::query_as::<_, GuestOrder>(&format!(
+        "{} WHERE lower(guestcustomers.email) = $1 AND orderdet.userid IS NULL
+        ORDER BY orderdet.created_at DESC LIMIT 20", GUEST_ORDER_SQL))
+        .bind(&email)
+        .fetch_all(&state.database.db)
+        .await;
+    let orders = match orders {
+        Ok(orders) => orders,
+        Err(e) => return servererror(e).into_response(),
+    };
+    if let Some(first) = orders.first() {
+        let mut links = Vec::with_capacity(orders.len());
+        for order in &orders {
+            let token = newlookuptoken();
+            let response = sqlx::query("INSERT INTO guestlookuptokens (tokenhash, orderid) VALUES ($1, $2)")
+                .bind(hashlookuptoken(&token))
+                .bind(order.orderid)
+                .execute(&state.database.db)
+                .await;
+            if let Err(e) = response {
+                return servererror(e).into_response()
+            }
+            links.push((order.orderid, token));
+        }
+        sendlookupemail(&state, first.email.clone(), first.fullname.clone(), links);
+    }
+    (StatusCode::OK, Json(json!({
+        "status": "success",
+        "message": "If there are orders for this email, a link to each has been sent"
+    }))).into_response()
+}
+
+
+//Claim guest order route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Moves a guest order into the signed-in account. Only the order's lookup
+// token proves it was placed by this customer; a matching email is not enough.
+pub async fn claimguestorderhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Json(req): Json<GuestClaim>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, (i64,)>(
+        "WITH claimed AS (
+            UPDATE orderdet SET userid = $1, lookuptokenhash = NULL
+            WHERE userid IS NULL
+            AND (lookuptokenhash = $2 OR orderid = (SELECT orderid FROM guestlookuptokens WHERE tokenhash = $2))
+            RETURNING orderid
+        ), revoked AS (
+            DELETE FROM guestlookuptokens WHERE orderid IN (SELECT orderid FROM claimed)
+        )
+        SELECT orderid FROM claimed")
+        .bind(claims.sub)
+        .bind(hashlookuptoken(req.lookuptoken.trim()))
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some((orderid,))) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "orderid": orderid
+        }))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Order not found",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
@@ -0,0 +1,367 @@
+use axum::{Json, extract::{Multipart, Path, Query, State}, response::IntoResponse, http::StatusCode};
+use image::{codecs::{jpeg::JpegEncoder, webp::WebPEncoder}, imageops::FilterType, ColorType, DynamicImage, ImageEncoder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow, Pool, Postgres};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::{AppState, customerrors::servererror};
+
+const THUMBNAIL_SIZE: u32 = 320;
+const LARGE_SIZE: u32 = 1600;
+const VARIANT_FILES: [&str; 3] = ["thumb.jpg", "large.webp", "thumb.webp"];
+
+#[derive(Serialize, Deserialize, FromRow, Debug, Clone)]
+
+pub struct GalleryImage {
+    pub imageid: Uuid,
+    pub productid: Uuid,
+    pub variantid: Option<Uuid>,
+    pub position: i32,
+    pub original: String,
+    pub thumbnail: Option<String>,
+    pub webp: Option<String>,
+    pub thumbwebp: Option<String>
+}
+
+#[derive(FromRow, Debug)]
+
+struct GalleryKey {
+    storagekey: Option<String>,
+    original: String
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ImageUpload {
+    variantid: Option<Uuid>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct GalleryOrder {
+    imageids: Vec<Uuid>
+}
+
+struct ProcessedImage {
+    extension: &'static str,
+    contenttype: &'static str,
+    thumbnail: Vec<u8>,
+    webp: Vec<u8>,
+    thumbwebp: Vec<u8>
+}
+
+fn encodejpeg(img: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+    let rgb = img.to_rgb8();
+    let mut buf = Vec::new();
+    JpegEncoder::new_with_quality(&mut buf, 85).write_image(rgb.as_raw(), rgb.width(), rgb.height(), ColorType::Rgb8)?;
+    Ok(buf)
+}
+
+fn encodewebp(img: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+    let rgba = img.to_rgba8();
+    let mut buf = Vec::new();
+    WebPEncoder::new_lossless(&mut buf).write_image(rgba.as_raw(), rgba.width(), rgba.height(), ColorType::Rgba8)?;
+    Ok(buf)
+}
+
+fn processimage(bytes: &[u8]) -> Result<ProcessedImage, image::ImageError> {
+    let format = image::guess_format(bytes)?;
+    let img = image::load_from_memory_with_format(bytes, format)?;
+    let thumb = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    let large = if img.width() > LARGE_SIZE || img.height() > LARGE_SIZE {
+        img.resize(LARGE_SIZE, LARGE_SIZE, FilterType::Lanczos3)
+    } else {
+        img
+    };
+    Ok(ProcessedImage {
+        extension: format.extensions_str().first().copied().unwrap_or("img"),
+        contenttype: format.to_mime_type(),
+        thumbnail: encodejpeg(&thumb)?,
+        webp: encodewebp(&large)?,
+        thumbwebp: encodewebp(&thumb)?
+    })
+}
+
+pub async fn fetchgallery(db: &Pool<Postgres>, productids: &[Uuid]) -> Result<HashMap<Uuid, Vec<GalleryImage>>, sqlx::Error> {
+    let images = sqlx::query_as::<_, GalleryImage>(
+        "SELECT imageid, productid, variantid, position, original, thumbnail, webp, thumbwebp
+        FROM productgallery
+        WHERE productid = ANY($1)
+        ORDER BY productid, position")
+        .bind(productids)
+        .fetch_all(db)
+        .await?;
+    let mut gallery: HashMap<Uuid, Vec<GalleryImage>> = HashMap::new();
+    for image in images {
+        gallery.entry(image.productid).or_default().push(image);
+    }
+    Ok(gallery)
+}
+
+async fn removeblobs(state: &AppState, storagekey: &str, original: &str) {
+    let mut keys = vec![format!("{}/{}", storagekey, original)];
+    keys.extend(VARIANT_FILES.iter().map(|file| format!("{}/{}", storagekey, file)));
+    for key in keys {
+        if let Err(e) = state.blobstore.store.delete(&key).await {
+            println!("blob cleanup failed for {}: {}", key, e);
+        }
+    }
+}
+
+
+//Upload product images route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn uploadproductimages(State(state): State<AppState>, Path(productid): Path<Uuid>, Query(query): Query<ImageUpload>, mut multipart: Multipart) -> impl IntoResponse {
+    // A variantid that isn't one of the product's is not found rather than ignored
+    let exists = sqlx::query(
+        "SELECT products.productid FROM products
+        LEFT JOIN productvariants ON productvariants.productid = products.productid AND productvariants.variantid = $2
+        WHERE products.productid = $1
+        AND ($2::uuid IS NULL OR productvariants.variantid IS NOT NULL)")
+        .bind(productid)
+        .bind(query.variantid)
+        .fetch_optional(&state.database.db)
+        .await;
+    match exists {
+        Ok(Some(_)) => {},
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": if query.variantid.is_some() { "Product or variant not found" } else { "Product not found" },
+        }))),
+        Err(e) => return servererror(e),
+    }
+
+    let mut uploaded: Vec<GalleryImage> = Vec::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({
+                "status": "error",
+                "message": "Invalid multipart body",
+                "error": e.to_string(),
+                "images": uploaded,
+            }))),
+        };
+        let filename = field.file_name().unwrap_or_default().to_string();
+        if filename.is_empty() {
+            continue;
+        }
+        let bytes = match field.bytes().await {
+            Ok(bytes) => bytes.to_vec(),
+            Err(e) => return (StatusCode::BAD_REQUEST, Json(json!({
+                "status": "error",
+                "message": "Could not read upload",
+                "error": e.to_string(),
+                "images": uploaded,
+            }))),
+        };
+        let original = bytes.clone();
+        let processed = match tokio::task::spawn_blocking(move || processimage(&bytes)).await {
+            Ok(Ok(processed)) => processed,
+            Ok(Err(e)) => return (StatusCode::BAD_REQUEST, Json(json!({
+                "status": "error",
+                "message": format!("{} is not a supported image", filename),
+                "error": e.to_string(),
+                "images": uploaded,
+            }))),
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                "status": "error",
+                "message": "Something went wrong",
+                "error": e.to_string(),
+                "images": uploaded,
+            }))),
+        };
+
+        let imageid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+        let storagekey = format!("products/{}/{}", productid, imageid);
+        let originalfile = format!("original.{}", processed.extension);
+        let stored = async {
+            let original = state.blobstore.store.put(&format!("{}/{}", storagekey, originalfile), original, processed.contenttype).await?;
+            let thumbnail = state.blobstore.store.put(&format!("{}/thumb.jpg", storagekey), processed.thumbnail, "image/jpeg").await?;
+            let webp = state.blobstore.store.put(&format!("{}/large.webp", storagekey), processed.webp, "image/webp").await?;
+            let thumbwebp = state.blobstore.store.put(&format!("{}/thumb.webp", storagekey), processed.thumbwebp, "image/webp").await?;
+            Ok::<_, crate::blobstore::BlobError>((original, thumbnail, webp, thumbwebp))
+        }.await;
+        let (originalurl, thumbnail, webp, thumbwebp) = match stored {
+            Ok(urls) => urls,
+            Err(e) => {
+                removeblobs(&state, &storagekey, &originalfile).await;
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                    "status": "error",
+                    "message": "Could not store image",
+                    "error": e.to_string(),
+                    "images": uploaded,
+                })))
+            }
+        };
+
+        // Concurrent uploads to the same product take the next position one at a time
+        let mut tx = state.database.db.begin().await.unwrap();
+        let locked = sqlx::query("SELECT productid FROM products WHERE productid = $1 FOR UPDATE")
+            .bind(productid)
+            .execute(&mut tx)
+            .await;
+        let response = match locked {
+            Ok(_) => sqlx::query_as::<_, GalleryImage>(
+                "INSERT INTO productgallery (imageid, productid, variantid, position, original, thumbnail, webp, thumbwebp, storagekey)
+                VALUES ($1, $2, $8, (SELECT COALESCE(MAX(position) + 1, 0) FROM productgallery WHERE productid = $2), $3, $4, $5, $6, $7)
+                RETURNING imageid, productid, variantid, position, original, thumbnail, webp, thumbwebp")
+                .bind(imageid)
+                .bind(productid)
+                .bind(&originalurl)
+                .bind(&thumbnail)
+                .bind(&webp)
+                .bind(&thumbwebp)
+                .bind(&storagekey)
+                .bind(query.variantid)
+                .fetch_one(&mut tx)
+                .await,
+            Err(e) => Err(e),
+        };
+        match response {
+            Ok(image) => {
+                tx.commit().await.unwrap();
+                uploaded.push(image)
+            },
+            Err(e) => {
+                tx.rollback().await.unwrap();
+                removeblobs(&state, &storagekey, &originalfile).await;
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+                    "status": "error",
+                    "message": "Something went wrong",
+                    "error": e.to_string(),
+                    "images": uploaded,
+                })))
+            }
+        }
+    }
+
+    if uploaded.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "No image files in upload",
+        })))
+    }
+    (StatusCode::CREATED, Json(json!({
+        "images": uploaded
+    })))
+}
+
+
+//Delete product image route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn deleteproductimage(State(state): State<AppState>, Path((productid, imageid)): Path<(Uuid, Uuid)>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, GalleryKey>(
+        "DELETE FROM productgallery WHERE productid = $1 AND imageid = $2 RETURNING storagekey, original")
+        .bind(productid)
+        .bind(imageid)
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some(image)) => {
+            // Legacy rows point at externally hosted files and have no storage key
+            if let Some(storagekey) = image.storagekey {
+                let originalfile = image.original.rsplit('/').next().unwrap_or_default();
+                removeblobs(&state, &storagekey, originalfile).await;
+            }
+            (StatusCode::OK, Json(json!({
+                "image": "deleted"
+            })))
+        },
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Image not found",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Reorder product images route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn reorderproductimages(State(state): State<AppState>, Path(productid): Path<Uuid>, Json(req): Json<GalleryOrder>) -> impl IntoResponse {
+    let mut tx = state.database.db.begin().await.unwrap();
+    let current = sqlx::query_as::<_, (Uuid,)>(
+        "SELECT imageid FROM productgallery WHERE productid = $1 FOR UPDATE")
+        .bind(productid)
+        .fetch_all(&mut tx)
+        .await;
+    let current = match current {
+        Ok(rows) => rows.into_iter().map(|(imageid,)| imageid).collect::<Vec<Uuid>>(),
+        Err(e) => return servererror(e),
+    };
+    let mut requested = req.imageids.clone();
+    requested.sort();
+    requested.dedup();
+    let mut existing = current.clone();
+    existing.sort();
+    if requested != existing || requested.len() != req.imageids.len() {
+        tx.rollback().await.unwrap();
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "imageids must list every image of the product exactly once",
+        })))
+    }
+
+    let response = sqlx::query(
+        "UPDATE productgallery
+        SET position = ordering.position - 1
+        FROM UNNEST($1::uuid[]) WITH ORDINALITY AS ordering(imageid, position)
+        WHERE productgallery.imageid = ordering.imageid
+        AND productgallery.productid = $2")
+        .bind(&req.imageids)
+        .bind(productid)
+        .execute(&mut tx)
+        .await;
+    match response {
+        Ok(_) => {
+            tx.commit().await.unwrap();
+            (StatusCode::OK, Json(json!({
+                "updated": "success"
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height)).write_to(&mut Cursor::new(&mut buf), ImageFormat::Png).unwrap();
+        buf
+    }
+
+    #[test]
+    fn upload_is_resized_into_jpeg_and_webp_variants() {
+        let processed = processimage(&png(2000, 1000)).unwrap();
+        assert_eq!((processed.extension, processed.contenttype), ("png", "image/png"));
+        let thumbnail = image::load_from_memory_with_format(&processed.thumbnail, ImageFormat::Jpeg).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+        assert_eq!(image::guess_format(&processed.webp).unwrap(), ImageFormat::WebP);
+        assert_eq!(image::guess_format(&processed.thumbwebp).unwrap(), ImageFormat::WebP);
+    }
+
+    #[test]
+    fn large_webp_is_capped_and_never_enlarged() {
+        let large = image::load_from_memory_with_format(&processimage(&png(2400, 1200)).unwrap().webp, ImageFormat::WebP).unwrap();
+        assert_eq!((large.width(), large.height()), (LARGE_SIZE, LARGE_SIZE / 2));
+        let small = image::load_from_memory_with_format(&processimage(&png(100, 50)).unwrap().webp, ImageFormat::WebP).unwrap();
+        assert_eq!((small.width(), small.height()), (100, 50));
+    }
+
+    #[test]
+    fn non_image_upload_is_rejected() {
+        assert!(processimage(b"<html><body>not an image</body></html>").is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use axum::{Json, Extension, body::StreamBody, extract::{Query, State}, response::{IntoResponse, Response}, http::{header, HeaderMap, StatusCode}};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, Acquire, FromRow, Postgres, Transaction};
+use uuid::Uuid;
+use crate::{AppState, inventory::{self, StockMovement, StockReason}, mware::ClaimsAccessToken, notifications, pricing::parseprice, webhooks};
+
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct ImportRow {
+    prodsku: String,
+    prodname: Option<String>,
+    proddescr: Option<String>,
+    category: Option<String>,
+    price: Option<String>,
+    availableqty: Option<i64>,
+    reorderthreshold: Option<i64>,
+    // Image URLs separated by '|', in gallery order
+    images: Option<String>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct ExportRow {
+    prodsku: String,
+    prodname: String,
+    proddescr: String,
+    category: String,
+    price: String,
+    availableqty: i64,
+    reorderthreshold: Option<i64>,
+    images: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ImportQuery {
+    dryrun: Option<bool>,
+    format: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ExportQuery {
+    format: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+
+pub struct RowReport {
+    row: usize,
+    prodsku: Option<String>,
+    action: &'static str,
+    errors: Vec<String>
+}
+
+#[derive(PartialEq, Debug)]
+
+enum Format {
+    Csv,
+    JsonLines
+}
+
+const EXPORT_SQL: &str = "
+    SELECT products.prodsku, products.prodname, products.proddescr, products.category, products.price,
+    products.availableqty, products.reorderthreshold,
+    (SELECT string_agg(productgallery.original, '|' ORDER BY productgallery.position)
+        FROM productgallery
+        WHERE productgallery.productid = products.productid AND productgallery.variantid IS NULL) AS images
+    FROM products
+    ORDER BY products.prodsku";
+
+
+fn requestformat(format: Option<&str>, headers: &HeaderMap) -> Option<Format> {
+    let contenttype = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    match format {
+        Some("csv") => Some(Format::Csv),
+        Some("jsonl") => Some(Format::JsonLines),
+        Some(_) => None,
+        None if contenttype.starts_with("text/csv") => Some(Format::Csv),
+        None if contenttype.starts_with("application/x-ndjson") || contenttype.starts_with("application/jsonl") || contenttype.starts_with("application/json") => Some(Format::JsonLines),
+        None => None,
+    }
+}
+
+fn nonempty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|value| !value.is_empty())
+}
+
+fn imageurls(images: &str) -> Vec<&str> {
+    images.split('|').map(str::trim).filter(|url| !url.is_empty()).collect()
+}
+
+fn parserows(format: &Format, body: &str) -> Vec<Result<ImportRow, String>> {
+    match format {
+        Format::Csv => csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body.as_bytes())
+            .deserialize::<ImportRow>()
+            .map(|row| row.map_err(|e| e.to_string()))
+            .collect(),
+        Format::JsonLines => body
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str::<ImportRow>(line).map_err(|e| e.to_string()))
+            .collect(),
+    }
+}
+
+fn validaterow(row: &ImportRow) -> Vec<String> {
+    let mut errors = Vec::new();
+    if row.prodsku.trim().is_empty() {
+        errors.push("prodsku is required".to_string());
+    }
+    if let Some(price) = nonempty(&row.price) {
+        if parseprice(price).is_none() {
+            errors.push(format!("price '{}' must be a decimal amount such as 9.99", price));
+        }
+    }
+    if matches!(row.availableqty, Some(availableqty) if availableqty < 0) {
+        errors.push("availableqty cannot be negative".to_string());
+    }
+    if matches!(row.reorderthreshold, Some(threshold) if threshold < 0) {
+        errors.push("reorderthreshold cannot be negative".to_string());
+    }
+    if let Some(images) = &row.images {
+        for url in imageurls(images) {
+            if !(url.starts_with("https://") || url.starts_with("http://") || url.starts_with('/')) {
+                errors.push(format!("image '{}' must be an absolute URL or path", url));
+            }
+        }
+    }
+    errors
+}
+
+// Imported images are plain URLs with no storage key. They replace the previously
+// imported ones and go in front of any uploaded images, keeping their order.
+// Variant images are left where they are.
+async fn replaceimportedimages(tx: &mut Transaction<'_, Postgres>, productid: Uuid, urls: &[&str]) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM productgallery WHERE productid = $1 AND storagekey IS NULL AND variantid IS NULL")
+        .bind(productid)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE productgallery SET position = position + $2 WHERE productid = $1 AND variantid IS NULL")
+        .bind(productid)
+        .bind(urls.len() as i32)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO productgallery (imageid, productid, position, original)
+        SELECT gen_random_uuid(), $1, (images.ordinality - 1)::INT, images.url
+        FROM UNNEST($2::TEXT[]) WITH ORDINALITY AS images(url, ordinality)")
+        .bind(productid)
+        .bind(urls)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+// Upserts one row by prodsku. Returns the action taken, the product and the stock delta.
+async fn importrow(tx: &mut Transaction<'_, Postgres>, row: &ImportRow, actor: Uuid) -> Result<(&'static str, Uuid, i64), String> {
+    let prodsku = row.prodsku.trim();
+    if let Some(category) = nonempty(&row.category) {
+        sqlx::query("INSERT INTO prodcategory (descr) SELECT $1 WHERE NOT EXISTS (SELECT 1 FROM prodcategory WHERE descr = $1)")
+            .bind(category)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    let existing = sqlx::query_as::<_, (Uuid, i64)>("SELECT productid, availableqty FROM products WHERE prodsku = $1 FOR UPDATE")
+        .bind(prodsku)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (action, productid, onhand) = match existing {
+        Some((productid, onhand)) => {
+            sqlx::query(
+                "UPDATE products SET
+                prodname = COALESCE($1, prodname),
+                proddescr = COALESCE($2, proddescr),
+                category = COALESCE($3, category),
+                price = COALESCE($4, price),
+                reorderthreshold = COALESCE($5, reorderthreshold)
+                WHERE productid = $6")
+                .bind(nonempty(&row.prodname))
+                .bind(nonempty(&row.proddescr))
+                .bind(nonempty(&row.category))
+                .bind(nonempty(&row.price))
+                .bind(row.reorderthreshold)
+                .bind(productid)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+            ("updated", productid, onhand)
+        },
+        None => {
+            let (prodname, category, price) = match (nonempty(&row.prodname), nonempty(&row.category), nonempty(&row.price)) {
+                (Some(prodname), Some(category), Some(price)) => (prodname, category, price),
+                _ => return Err("prodname, category and price are required for a new product".to_string()),
+            };
+            let productid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+            sqlx::query(
+                "INSERT INTO products (productid, prodname, proddescr, prodsku, category, availableqty, price, reorderthreshold, created_at)
+                VALUES ($1, $2, $3, $4, $5, 0, $6, $7, $8)")
+                .bind(productid)
+                .bind(prodname)
+                .bind(nonempty(&row.proddescr).unwrap_or(""))
+                .bind(prodsku)
+                .bind(category)
+                .bind(price)
+                .bind(row.reorderthreshold)
+                .bind(chrono::Utc::now())
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| e.to_string())?;
+            ("created", productid, 0)
+        },
+    };
+    let delta = row.availableqty.map_or(0, |availableqty| availableqty - onhand);
+    if delta != 0 {
+        inventory::recordmovement(tx, StockMovement {
+            productid,
+            variantid: None,
+            reason: if action == "created" { StockReason::Restock } else { StockReason::Adjustment },
+            delta,
+            actor: Some(actor),
+            orderid: None,
+            note: Some("product import")
+        }).await.map_err(|e| e.to_string())?;
+    }
+    if let Some(images) = &row.images {
+        replaceimportedimages(tx, productid, &imageurls(images)).await.map_err(|e| e.to_string())?;
+    }
+    webhooks::productupdated(tx, productid).await.map_err(|e| e.to_string())?;
+    Ok((action, productid, delta))
+}
+
+
+//Product import route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Rows upsert by prodsku into products and prodcategory. Images go to the product
+// gallery (productgallery), which replaced the four-column productimages table;
+// nothing is written to productimages_legacy.
+// Every row runs in its own savepoint so one bad row does not undo the others.
+// A dry run executes the whole import and rolls it back, so database errors are
+// reported as well as validation errors.
+pub async fn importproductshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Query(query): Query<ImportQuery>, headers: HeaderMap, body: String) -> impl IntoResponse {
+    let format = match requestformat(query.format.as_deref(), &headers) {
+        Some(format) => format,
+        None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Send text/csv or application/x-ndjson, or set format=csv|jsonl",
+        }))),
+    };
+    let dryrun = query.dryrun.unwrap_or(false);
+    let rows = parserows(&format, &body);
+    let mut tx = state.database.db.begin().await.unwrap();
+    let mut reports = Vec::with_capacity(rows.len());
+    let mut restocked = Vec::new();
+    for (index, row) in rows.into_iter().enumerate() {
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                reports.push(RowReport { row: index + 1, prodsku: None, action: "error", errors: vec![e] });
+                continue;
+            }
+        };
+        let errors = validaterow(&row);
+        if !errors.is_empty() {
+            reports.push(RowReport { row: index + 1, prodsku: Some(row.prodsku), action: "error", errors });
+            continue;
+        }
+        let mut savepoint = match (&mut tx).begin().await {
+            Ok(savepoint) => savepoint,
+            Err(e) => {
+                reports.push(RowReport { row: index + 1, prodsku: Some(row.prodsku), action: "error", errors: vec![e.to_string()] });
+                continue;
+            }
+        };
+        match importrow(&mut savepoint, &row, claims.sub).await {
+            Ok((action, productid, delta)) => {
+                savepoint.commit().await.unwrap();
+                if delta > 0 {
+                    restocked.push(productid);
+                }
+                reports.push(RowReport { row: index + 1, prodsku: Some(row.prodsku), action, errors: Vec::new() });
+            },
+            Err(e) => {
+                savepoint.rollback().await.unwrap();
+                reports.push(RowReport { row: index + 1, prodsku: Some(row.prodsku), action: "error", errors: vec![e] });
+            }
+        }
+    }
+    if dryrun {
+        tx.rollback().await.unwrap();
+    } else {
+        tx.commit().await.unwrap();
+        for productid in restocked {
+            if let Err(e) = notifications::restockcheck(&state, productid).await {
+                println!("restock notifications failed for {}: {:?}", productid, e);
+            }
+        }
+    }
+    let count = |action: &str| reports.iter().filter(|report| report.action == action).count();
+    (StatusCode::OK, Json(json!({
+        "status": if dryrun { "dryrun" } else { "success" },
+        "rows": reports.len(),
+        "created": count("created"),
+        "updated": count("updated"),
+        "failed": count("error"),
+        "report": reports
+    })))
+}
+
+
+//Product export route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Rows are streamed straight from the cursor in the same columns the import accepts.
+pub async fn exportproductshandler(State(state): State<AppState>, Query(query): Query<ExportQuery>) -> Response {
+    let format = match query.format.as_deref() {
+        None | Some("csv") => Format::Csv,
+        Some("jsonl") => Format::JsonLines,
+        Some(_) => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "format must be csv or jsonl",
+        }))).into_response(),
+    };
+    let (contenttype, filename) = match format {
+        Format::Csv => ("text/csv", "attachment; filename=\"products.csv\""),
+        Format::JsonLines => ("application/x-ndjson", "attachment; filename=\"products.jsonl\""),
+    };
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Result<Vec<u8>, String>>(32);
+    let db = state.database.db.clone();
+    tokio::spawn(async move {
+        let mut rows = sqlx::query_as::<_, ExportRow>(EXPORT_SQL).fetch(&db);
+        let mut first = true;
+        while let Some(row) = rows.next().await {
+            let chunk = row.map_err(|e| e.to_string()).and_then(|row| match format {
+                Format::Csv => {
+                    let mut writer = csv::WriterBuilder::new().has_headers(first).from_writer(Vec::new());
+                    writer.serialize(&row).map_err(|e| e.to_string())?;
+                    writer.into_inner().map_err(|e| e.to_string())
+                },
+                Format::JsonLines => serde_json::to_vec(&row).map(|mut line| { line.push(b'\n'); line }).map_err(|e| e.to_string()),
+            });
+            first = false;
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let body = StreamBody::new(futures::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    }));
+    (StatusCode::OK, [(header::CONTENT_TYPE, contenttype), (header::CONTENT_DISPOSITION, filename)], body).into_response()
+}
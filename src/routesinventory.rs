@@ -0,0 +1,218 @@
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow};
+use uuid::Uuid;
+use crate::{AppState, customerrors::servererror, inventory::{self, StockMovement, StockReason}, mware::ClaimsAccessToken, notifications, webhooks};
+
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct StockAdjustment {
+    variantid: Option<Uuid>,
+    reason: String,
+    delta: i64,
+    orderid: Option<i64>,
+    note: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct MovementQuery {
+    variantid: Option<Uuid>,
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct MovementRow {
+    movementid: Uuid,
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    reason: String,
+    delta: i64,
+    actor: Option<Uuid>,
+    orderid: Option<i64>,
+    note: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct Discrepancy {
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    availableqty: i64,
+    ledgerqty: i64
+}
+
+const DISCREPANCY_SQL: &str = "
+    SELECT products.productid, NULL::uuid AS variantid, products.availableqty,
+    COALESCE((SELECT SUM(delta) FROM stock_movements WHERE stock_movements.productid = products.productid AND stock_movements.variantid IS NULL), 0)::BIGINT AS ledgerqty
+    FROM products
+    UNION ALL
+    SELECT productvariants.productid, productvariants.variantid, productvariants.availableqty,
+    COALESCE((SELECT SUM(delta) FROM stock_movements WHERE stock_movements.variantid = productvariants.variantid), 0)::BIGINT AS ledgerqty
+    FROM productvariants";
+
+
+//Adjust stock route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn adjuststockhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(productid): Path<Uuid>, Json(req): Json<StockAdjustment>) -> impl IntoResponse {
+    let reason = match StockReason::from_str(&req.reason) {
+        Some(StockReason::Sale) | None => return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "reason must be one of restock, adjustment, return",
+        }))),
+        Some(reason) => reason,
+    };
+    if req.delta == 0 {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "delta cannot be zero",
+        })))
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    let onhand = match req.variantid {
+        Some(variantid) => sqlx::query_as::<_, (i64,)>(
+            "SELECT availableqty FROM productvariants WHERE productid = $1 AND variantid = $2 FOR UPDATE")
+            .bind(productid)
+            .bind(variantid)
+            .fetch_optional(&mut tx)
+            .await,
+        None => sqlx::query_as::<_, (i64,)>(
+            "SELECT availableqty FROM products WHERE productid = $1 FOR UPDATE")
+            .bind(productid)
+            .fetch_optional(&mut tx)
+            .await,
+    };
+    match onhand {
+        Ok(Some((onhand,))) if onhand + req.delta < 0 => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::BAD_REQUEST, Json(json!({
+                "status": "error",
+                "message": "Adjustment would make stock negative",
+                "availableqty": onhand,
+            })))
+        },
+        Ok(Some(_)) => {},
+        Ok(None) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::NOT_FOUND, Json(json!({
+                "status": "error",
+                "message": "Product not found",
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    }
+    let response = inventory::recordmovement(&mut tx, StockMovement {
+        productid,
+        variantid: req.variantid,
+        reason,
+        delta: req.delta,
+        actor: Some(claims.sub),
+        orderid: req.orderid,
+        note: req.note.as_deref()
+    }).await;
+    let response = match response {
+        Ok(availableqty) => webhooks::productupdated(&mut tx, productid).await.map(|_| availableqty),
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(availableqty) => {
+            tx.commit().await.unwrap();
+            if req.delta > 0 {
+                if let Err(e) = notifications::restockcheck(&state, productid).await {
+                    println!("restock notifications failed for {}: {:?}", productid, e);
+                }
+            }
+            (StatusCode::OK, Json(json!({
+                "status": "success",
+                "availableqty": availableqty
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
+    }
+}
+
+
+//Stock movement history route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn stockmovementshandler(State(state): State<AppState>, Path(productid): Path<Uuid>, Query(query): Query<MovementQuery>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, MovementRow>(
+        "SELECT movementid, productid, variantid, reason, delta, actor, orderid, note, created_at
+        FROM stock_movements
+        WHERE productid = $1
+        AND ($2::uuid IS NULL OR variantid = $2)
+        ORDER BY created_at DESC
+        LIMIT $3 OFFSET $4")
+        .bind(productid)
+        .bind(query.variantid)
+        .bind(query.limit.unwrap_or(100).clamp(1, 1000))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(movements) => (StatusCode::OK, Json(json!({
+            "movements": movements
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Stock reconciliation routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn stockdiscrepancieshandler(State(state): State<AppState>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, Discrepancy>(&format!(
+        "SELECT * FROM ({}) stock WHERE availableqty <> ledgerqty", DISCREPANCY_SQL))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(discrepancies) => (StatusCode::OK, Json(json!({
+            "discrepancies": discrepancies
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+async fn reconcile(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<u64, sqlx::Error> {
+    let products = sqlx::query(&format!(
+        "UPDATE products SET availableqty = stock.ledgerqty
+        FROM ({}) stock
+        WHERE stock.variantid IS NULL AND stock.productid = products.productid AND products.availableqty <> stock.ledgerqty", DISCREPANCY_SQL))
+        .execute(&mut *tx)
+        .await?;
+    let variants = sqlx::query(&format!(
+        "UPDATE productvariants SET availableqty = stock.ledgerqty
+        FROM ({}) stock
+        WHERE stock.variantid = productvariants.variantid AND productvariants.availableqty <> stock.ledgerqty", DISCREPANCY_SQL))
+        .execute(&mut *tx)
+        .await?;
+    Ok(products.rows_affected() + variants.rows_affected())
+}
+
+// The ledger is the source of truth: any on-hand figure changed outside of it is
+// reset to the sum of its movements.
+pub async fn reconcilestockhandler(State(state): State<AppState>) -> impl IntoResponse {
+    let mut tx = state.database.db.begin().await.unwrap();
+    match reconcile(&mut tx).await {
+        Ok(reconciled) => {
+            tx.commit().await.unwrap();
+            (StatusCode::OK, Json(json!({
+                "status": "success",
+                "reconciled": reconciled
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
+    }
+}
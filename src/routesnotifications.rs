@@ -0,0 +1,100 @@
+use axum::{Json, Extension, extract::{Path, State}, response::IntoResponse, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow};
+use uuid::Uuid;
+use crate::{AppState, customerrors::{forbidden, servererror}, favouritealerts, mware::ClaimsAccessToken};
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct NotificationPreferences {
+    pricedrops: bool,
+    backinstock: bool,
+    updated_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct PreferencesUpdate {
+    pricedrops: Option<bool>,
+    backinstock: Option<bool>
+}
+
+
+
+//Fetch notification preferences route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchpreferenceshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own notifications") {
+        return response
+    }
+    if let Err(e) = favouritealerts::ensurepreferences(&state.database.db, userid).await {
+        return servererror(e)
+    }
+    let response = sqlx::query_as::<_, NotificationPreferences>(
+        "SELECT pricedrops, backinstock, updated_at FROM notificationpreferences WHERE userid = $1")
+        .bind(userid)
+        .fetch_one(&state.database.db)
+        .await;
+    match response {
+        Ok(preferences) => (StatusCode::OK, Json(json!({
+            "preferences": preferences
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Update notification preferences route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn updatepreferenceshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>, Json(req): Json<PreferencesUpdate>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own notifications") {
+        return response
+    }
+    if let Err(e) = favouritealerts::ensurepreferences(&state.database.db, userid).await {
+        return servererror(e)
+    }
+    let response = sqlx::query_as::<_, NotificationPreferences>(
+        "UPDATE notificationpreferences SET
+        pricedrops = COALESCE($2, pricedrops),
+        backinstock = COALESCE($3, backinstock),
+        updated_at = now()
+        WHERE userid = $1
+        RETURNING pricedrops, backinstock, updated_at")
+        .bind(userid)
+        .bind(req.pricedrops)
+        .bind(req.backinstock)
+        .fetch_one(&state.database.db)
+        .await;
+    match response {
+        Ok(preferences) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "preferences": preferences
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Unsubscribe route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Linked from alert emails, so it works without logging in and turns off every alert
+pub async fn unsubscribehandler(State(state): State<AppState>, Path(token): Path<String>) -> impl IntoResponse {
+    let response = sqlx::query(
+        "UPDATE notificationpreferences SET pricedrops = false, backinstock = false, updated_at = now()
+        WHERE unsubscribetoken = $1")
+        .bind(&token)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Unsubscribe link not found",
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "message": "You will no longer receive price drop or back in stock emails for your favourites",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
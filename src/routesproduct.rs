@@ -2,8 +2,8 @@ use axum_macros::debug_handler;
 use serde::{Deserialize, Serialize};
 use sqlx::{self, FromRow};
 use uuid::Uuid;
-use crate::AppState;
-use axum::{Json, extract::{Path, State}, response::IntoResponse, http::StatusCode};
+use crate::{AppState, customerrors::{forbidden, servererror}, favouritealerts, inventory::{self, StockMovement, StockReason, HELD_PRODUCT_SQL}, mware::ClaimsAccessToken, notifications, pricing::{parseprice, TaxClass}, routesimages::{fetchgallery, GalleryImage}, routesreviews::{AVERAGE_RATING_SQL, REVIEW_COUNT_SQL}, routesvariants::fetchvariantmatrix, webhooks};
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
 use serde_json::json;
 
 #[derive(Serialize, FromRow, Debug)]
@@ -15,13 +15,20 @@ struct Products {
     prodsku: String,
     descr: String,
     availableqty: i64,
+    availabletosell: i64,
     price: String,
-    imageone: String,
-    imagetwo: String,
-    imagethree: String,
-    imagefour: String,
+    averagerating: Option<f64>,
+    reviewcount: i64,
     created_at: chrono::DateTime<chrono::Utc>
 }
+
+#[derive(Serialize, Debug)]
+
+struct ProductWithImages {
+    #[serde(flatten)]
+    product: Products,
+    images: Vec<GalleryImage>
+}
 #[derive(Serialize, Deserialize, FromRow, Debug)]
 
 pub struct ProductUpdate {
@@ -30,29 +37,53 @@ pub struct ProductUpdate {
     prodsku: Option<String>,
     category: Option<Uuid>,
     availableqty: Option<i64>,
-    price: Option<String>
+    price: Option<String>,
+    reorderthreshold: Option<i64>,
+    status: Option<String>,
+    taxclass: Option<String>,
+    weightgrams: Option<i32>
 }
-#[derive(Serialize, Deserialize, FromRow, Debug)]
+#[derive(Serialize, FromRow, Debug)]
 
-struct FavProducts {
+struct ArchivedProducts {
     productid : Uuid,
     prodname: String,
+    prodsku: String,
+    descr: String,
+    availableqty: i64,
     price: String,
-    imagetwo: String
+    archived_at: Option<chrono::DateTime<chrono::Utc>>
+}
+#[derive(Deserialize, Debug)]
+
+pub struct StockNotifyVariant {
+    variantid: Option<Uuid>
 }
 
 
+async fn withimages(state: &AppState, products: Vec<Products>) -> Result<Vec<ProductWithImages>, sqlx::Error> {
+    let productids: Vec<Uuid> = products.iter().map(|product| product.productid).collect();
+    let mut gallery = fetchgallery(&state.database.db, &productids).await?;
+    Ok(products.into_iter().map(|product| ProductWithImages {
+        images: gallery.remove(&product.productid).unwrap_or_default(),
+        product
+    }).collect())
+}
+
 pub async fn fetchproductshandler(State(state): State<AppState>)-> impl IntoResponse {
-    let response = sqlx::query_as::<_, Products>(
-        "SELECT products.productid, products.prodname, products.proddescr, products.prodsku, products.availableqty, products.price, products.created_at, prodcategory.descr, productimages.imageone, productimages.imagetwo, productimages.imagethree, productimages.imagefour
+    let response = sqlx::query_as::<_, Products>(&format!(
+        "SELECT products.productid, products.prodname, products.proddescr, products.prodsku, products.availableqty, products.availableqty - {} AS availabletosell, products.price, {} AS averagerating, {} AS reviewcount, products.created_at, prodcategory.descr
         FROM products
         INNER JOIN prodcategory 
         ON products.category  = prodcategory.descr
-        INNER JOIN productimages
-        ON products.prodsku = productimages.prodskuid"
+        WHERE products.status = 'active'", HELD_PRODUCT_SQL, AVERAGE_RATING_SQL, REVIEW_COUNT_SQL)
 )
     .fetch_all(&state.database.db)
     .await;
+    let response = match response {
+        Ok(products) => withimages(&state, products).await,
+        Err(e) => Err(e),
+    };
     match response {
         Ok(products) => (StatusCode::OK , Json(json!({
             "products": products
@@ -68,22 +99,29 @@ pub async fn fetchproductshandler(State(state): State<AppState>)-> impl IntoResp
 
 #[debug_handler]
 pub async fn fetchproducthandler (State(state): State<AppState>, Path(productid): Path<Uuid>) -> impl IntoResponse {
-let response = sqlx::query_as::<_, Products>(
+let response = sqlx::query_as::<_, Products>(&format!(
 "SELECT 
-products.productid, products.prodname, products.proddescr, products.prodsku, products.availableqty, products.price, products.created_at, prodcategory.descr, productimages.imageone, productimages.imagetwo, productimages.imagethree, productimages.imagefour
+products.productid, products.prodname, products.proddescr, products.prodsku, products.availableqty, products.availableqty - {} AS availabletosell, products.price, {} AS averagerating, {} AS reviewcount, products.created_at, prodcategory.descr
 FROM products
 INNER JOIN prodcategory 
 ON products.category  = prodcategory.descr
-INNER JOIN productimages
-ON products.prodsku = productimages.prodskuid
-where productid = $1"
+where productid = $1 AND products.status = 'active'", HELD_PRODUCT_SQL, AVERAGE_RATING_SQL, REVIEW_COUNT_SQL)
 )
     .bind(productid)
     .fetch_all(&state.database.db)
     .await;
+    let response = match response {
+        Ok(product) => withimages(&state, product).await,
+        Err(e) => Err(e),
+    };
+    let response = match response {
+        Ok(product) => fetchvariantmatrix(&state.database.db, productid).await.map(|matrix| (product, matrix)),
+        Err(e) => Err(e),
+    };
     match response {
-        Ok(product) => (StatusCode::OK , Json(json!({
-            "product": product
+        Ok((product, matrix)) => (StatusCode::OK , Json(json!({
+            "product": product,
+            "variants": matrix
         }))),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
             "status": "error",
@@ -94,16 +132,39 @@ where productid = $1"
 }
 
 
+// Queues product.updated for a status change and settles the transaction
+async fn productchanged(mut tx: sqlx::Transaction<'_, sqlx::Postgres>, response: Result<Option<(Uuid,)>, sqlx::Error>) -> Result<Option<(Uuid,)>, sqlx::Error> {
+    let response = match response {
+        Ok(Some((productid,))) => webhooks::productupdated(&mut tx, productid).await.map(|_| Some((productid,))),
+        response => response,
+    };
+    match response {
+        Ok(Some(product)) => tx.commit().await.map(|_| Some(product)),
+        response => {
+            tx.rollback().await.unwrap();
+            response
+        }
+    }
+}
+
+// Products are archived rather than deleted so past orders still resolve them
 #[debug_handler]
 pub async fn deleteproducthandler (State(state): State<AppState>, Path(productid): Path<Uuid>) -> impl IntoResponse {
-let response = sqlx::query_as::<_, Products>(
-    "DELETE FROM products where productid = $1 ")
+let mut tx = state.database.db.begin().await.unwrap();
+let response = sqlx::query_as::<_, (Uuid,)>(
+    "UPDATE products SET status = 'archived', archived_at = COALESCE(archived_at, now())
+    where productid = $1 RETURNING productid")
     .bind(productid)
-    .fetch_optional(&state.database.db)
+    .fetch_optional(&mut tx)
     .await;
+    let response = productchanged(tx, response).await;
     match response {
-        Ok(_) => (StatusCode::OK , Json(json!({
-            "product": "deleted"
+        Ok(Some(_)) => (StatusCode::OK , Json(json!({
+            "product": "archived"
+        }))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Product not found",
         }))),
         Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
             "status": "error",
@@ -115,126 +176,215 @@ let response = sqlx::query_as::<_, Products>(
 
 
 #[debug_handler]
-pub async fn updateproducthandler(State(state): State<AppState>, Path(productid): Path<Uuid>, Json(req): Json<ProductUpdate>) -> impl IntoResponse {
-let response = sqlx::query_as::<_, ProductUpdate>(
+pub async fn updateproducthandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(productid): Path<Uuid>, Json(req): Json<ProductUpdate>) -> impl IntoResponse {
+if !matches!(req.status.as_deref(), None | Some("draft") | Some("active") | Some("archived")) {
+    return (StatusCode::BAD_REQUEST, Json(json!({
+        "status": "error",
+        "message": "status must be one of draft, active, archived",
+    })))
+}
+if matches!(req.taxclass.as_deref(), Some(taxclass) if TaxClass::from_str(taxclass).is_none()) {
+    return (StatusCode::BAD_REQUEST, Json(json!({
+        "status": "error",
+        "message": "taxclass must be one of standard, reduced, zero",
+    })))
+}
+if matches!(req.availableqty, Some(availableqty) if availableqty < 0) {
+    return (StatusCode::BAD_REQUEST, Json(json!({
+        "status": "error",
+        "message": "availableqty cannot be negative",
+    })))
+}
+if matches!(req.weightgrams, Some(weightgrams) if weightgrams < 0) {
+    return (StatusCode::BAD_REQUEST, Json(json!({
+        "status": "error",
+        "message": "weightgrams cannot be negative",
+    })))
+}
+if matches!(req.price.as_deref(), Some(price) if !price.trim().is_empty() && parseprice(price).is_none()) {
+    return (StatusCode::BAD_REQUEST, Json(json!({
+        "status": "error",
+        "message": "price must be a decimal amount such as 9.99",
+    })))
+}
+let mut tx = state.database.db.begin().await.unwrap();
+// Price and stock before the edit, to tell who to alert about a drop or restock
+let before = favouritealerts::productstate(&mut tx, productid).await;
+let response = sqlx::query_as::<_, (i64,)>(
     "
     UPDATE products 
     SET
     prodname = COALESCE(NULLIF($1, ''), prodname),
     proddescr = COALESCE(NULLIF($2, ''), proddescr),
     prodsku = COALESCE(NULLIF($3, ''), prodsku),
-    availableqty = COALESCE(NULLIF($4, 0), availableqty),
-    price = COALESCE(NULLIF($5, ''), price),
+    price = COALESCE(NULLIF($4, ''), price),
     category = (select descr from prodcategory where descr = 
-    COALESCE(NULLIF($6, ''), category))
-    WHERE productid = $7
+    COALESCE(NULLIF($5, ''), category)),
+    reorderthreshold = COALESCE($7, reorderthreshold),
+    status = COALESCE($8, status),
+    archived_at = CASE WHEN COALESCE($8, status) = 'archived' THEN COALESCE(archived_at, now()) END,
+    taxclass = COALESCE($9, taxclass),
+    weightgrams = COALESCE($10, weightgrams)
+    WHERE productid = $6
+    RETURNING availableqty
 ")
 
     .bind(&req.prodname)
     .bind(&req.proddescr)
     .bind(&req.prodsku)
-    .bind(&req.availableqty)
     .bind(&req.price)
-    .bind(&req.category)
+    .bind(req.category)
     .bind(productid)
-    .fetch_optional(&state.database.db)
+    .bind(req.reorderthreshold)
+    .bind(&req.status)
+    .bind(&req.taxclass)
+    .bind(req.weightgrams)
+    .fetch_optional(&mut tx)
     .await;
-    match response {
-        Ok(_) => (StatusCode::OK , Json(json!({
-            "updated": "success"
-        }))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+    if let Ok(None) = response {
+        tx.rollback().await.unwrap();
+        return (StatusCode::NOT_FOUND, Json(json!({
             "status": "error",
-            "message": "Something went wrong",
-            "error": e.to_string(),
-        }))),
+            "message": "Product not found",
+        })))
+    }
+    let restocked = matches!((&response, req.availableqty), (Ok(Some((onhand,))), Some(availableqty)) if availableqty > *onhand);
+    // A stock figure from the admin form is recorded as an adjustment in the ledger
+    let response = match (response, req.availableqty) {
+        (Ok(Some((onhand,))), Some(availableqty)) if availableqty != onhand => inventory::recordmovement(&mut tx, StockMovement {
+            productid,
+            variantid: None,
+            reason: StockReason::Adjustment,
+            delta: availableqty - onhand,
+            actor: Some(claims.sub),
+            orderid: None,
+            note: Some("product update")
+        }).await.map(|_| ()),
+        (Ok(_), _) => Ok(()),
+        (Err(e), _) => Err(e),
+    };
+    let response = match response {
+        Ok(_) => webhooks::productupdated(&mut tx, productid).await,
+        Err(e) => Err(e),
+    };
+    let response = match (response, before) {
+        (Ok(_), Ok(Some(before))) => favouritealerts::queuealerts(&mut tx, productid, &before).await,
+        (Ok(_), Ok(None)) => Ok(()),
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    };
+    match response {
+        Ok(_) => {
+            tx.commit().await.unwrap();
+            if restocked {
+                if let Err(e) = notifications::restockcheck(&state, productid).await {
+                    println!("restock notifications failed for {}: {:?}", productid, e);
+                }
+            }
+            (StatusCode::OK , Json(json!({
+                "updated": "success"
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            servererror(e)
+        }
     }
 }
 
 
 
 
-pub async fn addfavouriteitems(State(state): State<AppState>, Path((userid, productid)): Path<(Uuid, Uuid)>) -> impl IntoResponse {
-    let favid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()); 
+pub async fn subscribebackinstock(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((userid, productid)): Path<(Uuid, Uuid)>, Query(query): Query<StockNotifyVariant>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own stock notifications") {
+        return response
+    }
+    let subid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    // A variantid that isn't one of the product's is not found rather than ignored
     let response = sqlx::query(
-        "
-        INSERT INTO favourites(favid, userid, productid)
-        VALUES ($1, $2, $3)
-        ")
-        .bind(favid)
+        "INSERT INTO stocksubscriptions (subid, userid, productid, variantid, created_at)
+        SELECT $1, $2, products.productid, productvariants.variantid, $5
+        FROM products
+        LEFT JOIN productvariants ON productvariants.productid = products.productid AND productvariants.variantid = $4
+        WHERE products.productid = $3
+        AND ($4::uuid IS NULL OR productvariants.variantid IS NOT NULL)
+        ON CONFLICT (userid, productid, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid))
+        DO UPDATE SET notified_at = NULL")
+        .bind(subid)
         .bind(userid)
         .bind(productid)
-        .fetch_optional(&state.database.db)
+        .bind(query.variantid)
+        .bind(chrono::Utc::now())
+        .execute(&state.database.db)
         .await;
-        match response {
-            Ok(_) => (StatusCode::OK , Json(json!({
-                "favourite": "added"
-            }))),
-            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
-                "status": "error",
-                "message": "Something went wrong",
-                "error": e.to_string(),
-            }))),
-        }
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": if query.variantid.is_some() { "Product or variant not found" } else { "Product not found" },
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "stocknotify": "subscribed"
+        }))),
+        Err(e) => servererror(e),
     }
+}
 
-    pub async fn fetchfavouriteitems(State(state): State<AppState>, Path(usid): Path<Uuid>) -> impl IntoResponse {
-        let response = sqlx::query_as::<_, FavProducts>(
-            "SELECT products.productid, products.prodname, products.price, productimages.imagetwo
-            FROM products
-            INNER JOIN prodcategory 
-            ON products.category  = prodcategory.descr
-            INNER JOIN productimages
-            ON products.prodsku = productimages.prodskuid
-            INNER JOIN favourites
-            ON products.productid = favourites.productid
-            where favourites.userid = $1"
-
-        )
-            .bind(usid)
-            .fetch_all(&state.database.db)
-            .await;
-            match response {
-                Ok(product) => (StatusCode::OK , Json(json!({
-                    "productlistres": product
-                }))),
-                Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
-                    "status": "error",
-                    "message": "Something went wrong",
-                    "error": e.to_string(),
-                }))),
-            }
-        }
-
-
+pub async fn unsubscribebackinstock(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((userid, productid)): Path<(Uuid, Uuid)>, Query(query): Query<StockNotifyVariant>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own stock notifications") {
+        return response
+    }
+    let response = sqlx::query(
+        "DELETE FROM stocksubscriptions WHERE userid = $1 AND productid = $2 AND variantid IS NOT DISTINCT FROM $3")
+        .bind(userid)
+        .bind(productid)
+        .bind(query.variantid)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Subscription not found",
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "stocknotify": "unsubscribed"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
 
-        #[debug_handler]
-        // path with userid and productid
 
+pub async fn fetcharchivedproductshandler(State(state): State<AppState>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, ArchivedProducts>(
+        "SELECT products.productid, products.prodname, products.prodsku, products.availableqty, products.price, products.archived_at, products.category AS descr
+        FROM products
+        WHERE products.status = 'archived'
+        ORDER BY products.archived_at DESC")
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(products) => (StatusCode::OK, Json(json!({
+            "products": products
+        }))),
+        Err(e) => servererror(e),
+    }
+}
 
-pub async fn deletefavorite(State(state): State<AppState>, Path((userid, productid)): Path<(Uuid, Uuid)>) -> impl IntoResponse {
-let response = sqlx::query(
-    "delete from favourites
-    where userid = $1
-    AND productid  = $2
-    ")
-    .bind(userid)
-    .bind(productid)
-    .fetch_optional(&state.database.db)
-    .await;
+pub async fn restoreproducthandler(State(state): State<AppState>, Path(productid): Path<Uuid>) -> impl IntoResponse {
+    let mut tx = state.database.db.begin().await.unwrap();
+    let response = sqlx::query_as::<_, (Uuid,)>(
+        "UPDATE products SET status = 'active', archived_at = NULL
+        WHERE productid = $1 AND status = 'archived' RETURNING productid")
+        .bind(productid)
+        .fetch_optional(&mut tx)
+        .await;
+    let response = productchanged(tx, response).await;
     match response {
-        Ok(_) => (StatusCode::OK , Json(json!({
-            "favourite": "deleted"
+        Ok(Some(_)) => (StatusCode::OK, Json(json!({
+            "product": "restored"
         }))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
             "status": "error",
-            "message": "Something went wrong",
-            "error": e.to_string(),
+            "message": "Archived product not found",
         }))),
+        Err(e) => servererror(e),
     }
 }
-
- 
-
-
- 
\ No newline at end of file
@@ -0,0 +1,164 @@
+use axum::{Json, extract::{Query, State}, response::{IntoResponse, Response}, http::{header, StatusCode}};
+use bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::{AppState, customerrors::{badrequest, servererror}, reports::{self, Interval}};
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReportQuery {
+    interval: Option<String>,
+    from: Option<chrono::NaiveDate>,
+    to: Option<chrono::NaiveDate>,
+    sort: Option<String>,
+    limit: Option<i64>,
+    format: Option<String>
+}
+
+struct ReportRange {
+    interval: Interval,
+    from: chrono::NaiveDate,
+    to: chrono::NaiveDate,
+    csv: bool
+}
+
+
+// Defaults to the last 30 days by day, as JSON
+fn reportrange(query: &ReportQuery) -> Result<ReportRange, &'static str> {
+    let interval = match query.interval.as_deref() {
+        None => Interval::Day,
+        Some(interval) => Interval::from_str(interval).ok_or("interval must be one of day, week, month")?,
+    };
+    let to = query.to.unwrap_or_else(|| chrono::Utc::now().date_naive());
+    let from = query.from.unwrap_or(to - chrono::Duration::days(29));
+    if from > to {
+        return Err("from must not be after to")
+    }
+    if to - from > chrono::Duration::days(3660) {
+        return Err("reports cover at most ten years")
+    }
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(_) => return Err("format must be json or csv"),
+    };
+    Ok(ReportRange { interval, from, to, csv })
+}
+
+fn reportresponse<T: Serialize>(range: &ReportRange, name: &str, rows: Vec<T>, extra: Value) -> Response {
+    if range.csv {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        let body = rows.iter()
+            .try_for_each(|row| writer.serialize(row))
+            .map_err(|e| e.to_string())
+            .and_then(|_| writer.into_inner().map_err(|e| e.to_string()));
+        return match body {
+            Ok(body) => {
+                let filename = format!("attachment; filename=\"{}-{}-{}.csv\"", name, range.from, range.to);
+                (StatusCode::OK, [(header::CONTENT_TYPE, "text/csv".to_string()), (header::CONTENT_DISPOSITION, filename)], body).into_response()
+            },
+            Err(e) => servererror(e).into_response(),
+        }
+    }
+    let mut body = json!({
+        "interval": range.interval.as_str(),
+        "from": range.from,
+        "to": range.to,
+        "rows": rows
+    });
+    if let (Some(body), Value::Object(extra)) = (body.as_object_mut(), extra) {
+        body.extend(extra);
+    }
+    (StatusCode::OK, Json(body)).into_response()
+}
+
+
+//Sales report routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn revenuereporthandler(State(state): State<AppState>, Query(query): Query<ReportQuery>) -> Response {
+    let range = match reportrange(&query) {
+        Ok(range) => range,
+        Err(message) => return badrequest(message).into_response(),
+    };
+    match reports::revenue(&state.database.db, state.reports.materialized, range.interval, range.from, range.to).await {
+        Ok(rows) => {
+            let orders: i64 = rows.iter().map(|row| row.orders).sum();
+            let revenue: BigDecimal = rows.iter().map(|row| &row.revenue).sum();
+            let refunds: BigDecimal = rows.iter().map(|row| &row.refunds).sum();
+            let averageordervalue = if orders > 0 { Some((&revenue / BigDecimal::from(orders)).round(2)) } else { None };
+            let totals = json!({
+                "totals": {
+                    "orders": orders,
+                    "revenue": revenue,
+                    "refunds": refunds,
+                    "netrevenue": &revenue - &refunds,
+                    "averageordervalue": averageordervalue
+                }
+            });
+            reportresponse(&range, &format!("revenue-{}", range.interval.as_str()), rows, totals)
+        },
+        Err(e) => servererror(e).into_response(),
+    }
+}
+
+// Best sellers by units, or by revenue with sort=revenue
+pub async fn productsreporthandler(State(state): State<AppState>, Query(query): Query<ReportQuery>) -> Response {
+    let range = match reportrange(&query) {
+        Ok(range) => range,
+        Err(message) => return badrequest(message).into_response(),
+    };
+    let byrevenue = match query.sort.as_deref() {
+        None | Some("units") => false,
+        Some("revenue") => true,
+        Some(_) => return badrequest("sort must be units or revenue").into_response(),
+    };
+    let limit = query.limit.unwrap_or(20).clamp(1, 500);
+    match reports::topproducts(&state.database.db, state.reports.materialized, byrevenue, range.from, range.to, limit).await {
+        Ok(rows) => reportresponse(&range, "top-products", rows, json!({ "sort": if byrevenue { "revenue" } else { "units" } })),
+        Err(e) => servererror(e).into_response(),
+    }
+}
+
+pub async fn customersreporthandler(State(state): State<AppState>, Query(query): Query<ReportQuery>) -> Response {
+    let range = match reportrange(&query) {
+        Ok(range) => range,
+        Err(message) => return badrequest(message).into_response(),
+    };
+    match reports::customers(&state.database.db, range.interval, range.from, range.to).await {
+        Ok(rows) => reportresponse(&range, &format!("customers-{}", range.interval.as_str()), rows, json!({})),
+        Err(e) => servererror(e).into_response(),
+    }
+}
+
+pub async fn conversionreporthandler(State(state): State<AppState>, Query(query): Query<ReportQuery>) -> Response {
+    let range = match reportrange(&query) {
+        Ok(range) => range,
+        Err(message) => return badrequest(message).into_response(),
+    };
+    match reports::conversion(&state.database.db, range.interval, range.from, range.to).await {
+        Ok(rows) => {
+            let carts: i64 = rows.iter().map(|row| row.carts).sum();
+            let paidorders: i64 = rows.iter().map(|row| row.paidorders).sum();
+            let conversionrate = if carts > 0 { Some((BigDecimal::from(paidorders * 100) / BigDecimal::from(carts)).round(2)) } else { None };
+            let totals = json!({
+                "totals": {
+                    "carts": carts,
+                    "paidorders": paidorders,
+                    "conversionrate": conversionrate
+                }
+            });
+            reportresponse(&range, &format!("conversion-{}", range.interval.as_str()), rows, totals)
+        },
+        Err(e) => servererror(e).into_response(),
+    }
+}
+
+pub async fn refreshreportshandler(State(state): State<AppState>) -> impl IntoResponse {
+    match reports::refreshviews(&state.database.db).await {
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "message": "Report views refreshed"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
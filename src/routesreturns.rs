@@ -0,0 +1,574 @@
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{self, FromRow, Pool, Postgres};
+use uuid::Uuid;
+use crate::{AppState, customerrors::{badrequest, servererror}, inventory::{self, StockMovement, StockReason}, mware::ClaimsAccessToken, notifications, paymentapi, webhooks};
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReturnLineRequest {
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    quantity: i32,
+    reason: String
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReturnRequest {
+    items: Vec<ReturnLineRequest>,
+    note: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReturnDecision {
+    note: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ItemDisposition {
+    returnitemid: Uuid,
+    disposition: String
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReturnReceipt {
+    disposition: Option<String>,
+    items: Option<Vec<ItemDisposition>>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReturnQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct ReturnRow {
+    returnid: Uuid,
+    orderid: i64,
+    userid: Uuid,
+    status: String,
+    note: Option<String>,
+    adminnote: Option<String>,
+    refundamount: Option<BigDecimal>,
+    refundstatus: Option<String>,
+    refundid: Option<String>,
+    refunderror: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    decided_at: Option<chrono::DateTime<chrono::Utc>>,
+    received_at: Option<chrono::DateTime<chrono::Utc>>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct ReturnItemRow {
+    returnitemid: Uuid,
+    returnid: Uuid,
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    prodname: String,
+    quantity: i32,
+    reason: String,
+    refundamount: Option<BigDecimal>,
+    disposition: Option<String>
+}
+
+#[derive(Serialize, Debug)]
+
+pub struct ReturnWithItems {
+    #[serde(flatten)]
+    ret: ReturnRow,
+    items: Vec<ReturnItemRow>
+}
+
+const RETURN_SELECT: &str = "SELECT returnid, orderid, userid, status, note, adminnote, refundamount, refundstatus, refundid, refunderror, created_at, decided_at, received_at FROM returns";
+
+// Units of a line that were bought and have not already been put on a return
+const RETURNABLE_SQL: &str = "SELECT
+    COALESCE((SELECT SUM(quantity) FROM listitems
+        WHERE orderidretr = $1 AND productid = $2 AND variantid IS NOT DISTINCT FROM $3), 0)::BIGINT
+    - COALESCE((SELECT SUM(returnitems.quantity) FROM returnitems
+        INNER JOIN returns ON returns.returnid = returnitems.returnid
+        WHERE returns.orderid = $1 AND returns.status <> 'rejected'
+        AND returnitems.productid = $2 AND returnitems.variantid IS NOT DISTINCT FROM $3), 0)::BIGINT";
+
+
+fn returnnotfound() -> (StatusCode, Json<Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "status": "error",
+        "message": "Return not found",
+    })))
+}
+
+async fn withitems(db: &Pool<Postgres>, returns: Vec<ReturnRow>) -> Result<Vec<ReturnWithItems>, sqlx::Error> {
+    let returnids: Vec<Uuid> = returns.iter().map(|ret| ret.returnid).collect();
+    let mut items = sqlx::query_as::<_, ReturnItemRow>(
+        "SELECT returnitems.returnitemid, returnitems.returnid, returnitems.productid, returnitems.variantid, products.prodname,
+        returnitems.quantity, returnitems.reason, returnitems.refundamount, returnitems.disposition
+        FROM returnitems
+        INNER JOIN products ON products.productid = returnitems.productid
+        WHERE returnitems.returnid = ANY($1)
+        ORDER BY products.prodname")
+        .bind(&returnids)
+        .fetch_all(db)
+        .await?;
+    Ok(returns.into_iter().map(|ret| {
+        let (mine, rest): (Vec<ReturnItemRow>, Vec<ReturnItemRow>) = items.drain(..).partition(|item| item.returnid == ret.returnid);
+        items = rest;
+        ReturnWithItems { ret, items: mine }
+    }).collect())
+}
+
+async fn loadreturn(db: &Pool<Postgres>, returnid: Uuid) -> Result<Option<ReturnWithItems>, sqlx::Error> {
+    let ret = sqlx::query_as::<_, ReturnRow>(&format!("{} WHERE returnid = $1", RETURN_SELECT))
+        .bind(returnid)
+        .fetch_optional(db)
+        .await?;
+    match ret {
+        Some(ret) => Ok(withitems(db, vec![ret]).await?.pop()),
+        None => Ok(None),
+    }
+}
+
+
+//Request return route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn createreturnhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(orderid): Path<i64>, Json(req): Json<ReturnRequest>) -> impl IntoResponse {
+    if req.items.is_empty() {
+        return badrequest("At least one item is required")
+    }
+    if req.items.iter().any(|item| item.quantity <= 0 || item.reason.trim().is_empty()) {
+        return badrequest("Every item needs a quantity of at least 1 and a reason")
+    }
+    for (index, item) in req.items.iter().enumerate() {
+        if req.items[..index].iter().any(|other| other.productid == item.productid && other.variantid == item.variantid) {
+            return badrequest("Each product can only appear once in a return")
+        }
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    let order = sqlx::query_as::<_, (Option<Uuid>, String)>("SELECT userid, status FROM orderdet WHERE orderid = $1 FOR UPDATE")
+        .bind(orderid)
+        .fetch_optional(&mut tx)
+        .await;
+    match order {
+        Ok(Some((Some(userid), status))) if userid == claims.sub => {
+            if status != "delivered" {
+                tx.rollback().await.unwrap();
+                return (StatusCode::CONFLICT, Json(json!({
+                    "status": "error",
+                    "message": "Returns can only be requested for delivered orders",
+                })))
+            }
+        },
+        Ok(_) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::NOT_FOUND, Json(json!({
+                "status": "error",
+                "message": "Order not found",
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    }
+    for item in &req.items {
+        let returnable = sqlx::query_as::<_, (i64,)>(RETURNABLE_SQL)
+            .bind(orderid)
+            .bind(item.productid)
+            .bind(item.variantid)
+            .fetch_one(&mut tx)
+            .await;
+        match returnable {
+            Ok((returnable,)) if item.quantity as i64 <= returnable => {},
+            Ok((returnable,)) => {
+                tx.rollback().await.unwrap();
+                return (StatusCode::CONFLICT, Json(json!({
+                    "status": "error",
+                    "message": "Quantity is more than can be returned",
+                    "productid": item.productid,
+                    "variantid": item.variantid,
+                    "returnable": returnable.max(0),
+                })))
+            },
+            Err(e) => {
+                tx.rollback().await.unwrap();
+                return servererror(e)
+            }
+        }
+    }
+    let returnid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    let response = sqlx::query("INSERT INTO returns (returnid, orderid, userid, note) VALUES ($1, $2, $3, $4)")
+        .bind(returnid)
+        .bind(orderid)
+        .bind(claims.sub)
+        .bind(req.note.as_deref().map(str::trim))
+        .execute(&mut tx)
+        .await;
+    if let Err(e) = response {
+        tx.rollback().await.unwrap();
+        return servererror(e)
+    }
+    for item in &req.items {
+        let response = sqlx::query(
+            "INSERT INTO returnitems (returnitemid, returnid, productid, variantid, quantity, reason) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+            .bind(returnid)
+            .bind(item.productid)
+            .bind(item.variantid)
+            .bind(item.quantity)
+            .bind(item.reason.trim())
+            .execute(&mut tx)
+            .await;
+        if let Err(e) = response {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    }
+    tx.commit().await.unwrap();
+    notifications::customeremail(&state, claims.sub, format!("Return request for order {}", orderid),
+        "We have received your return request and will let you know once it has been reviewed.".to_string());
+    notifications::adminalert(&state, format!("Return requested for order {}", orderid),
+        format!("A return has been requested for order {}. Return id: {}", orderid, returnid),
+        json!({ "event": "return.requested", "orderid": orderid, "returnid": returnid }));
+    match loadreturn(&state.database.db, returnid).await {
+        Ok(ret) => (StatusCode::CREATED, Json(json!({
+            "status": "success",
+            "return": ret
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Customer returns route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchmyreturnshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>) -> impl IntoResponse {
+    let returns = sqlx::query_as::<_, ReturnRow>(&format!("{} WHERE userid = $1 ORDER BY created_at DESC", RETURN_SELECT))
+        .bind(claims.sub)
+        .fetch_all(&state.database.db)
+        .await;
+    let returns = match returns {
+        Ok(returns) => withitems(&state.database.db, returns).await,
+        Err(e) => Err(e),
+    };
+    match returns {
+        Ok(returns) => (StatusCode::OK, Json(json!({
+            "returns": returns
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Admin returns routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchreturnshandler(State(state): State<AppState>, Query(query): Query<ReturnQuery>) -> impl IntoResponse {
+    let returns = sqlx::query_as::<_, ReturnRow>(&format!(
+        "{} WHERE ($1::text IS NULL OR status = $1) ORDER BY created_at DESC LIMIT $2 OFFSET $3", RETURN_SELECT))
+        .bind(&query.status)
+        .bind(query.limit.unwrap_or(50).clamp(1, 200))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.database.db)
+        .await;
+    let returns = match returns {
+        Ok(returns) => withitems(&state.database.db, returns).await,
+        Err(e) => Err(e),
+    };
+    match returns {
+        Ok(returns) => (StatusCode::OK, Json(json!({
+            "returns": returns
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+// Approving prices each line at what the customer paid for it, after discount and
+// including VAT, and refunds the total. Delivery is not refunded. A failed refund
+// leaves the return approved so approving again retries only the refund.
+pub async fn approvereturnhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(returnid): Path<Uuid>, Json(req): Json<ReturnDecision>) -> impl IntoResponse {
+    let mut tx = state.database.db.begin().await.unwrap();
+    let current = sqlx::query_as::<_, (String, Option<String>, i64, Uuid, Option<String>)>(
+        "SELECT returns.status, returns.refundstatus, returns.orderid, returns.userid, orderdet.paymentintentid
+        FROM returns
+        INNER JOIN orderdet ON orderdet.orderid = returns.orderid
+        WHERE returns.returnid = $1
+        FOR UPDATE OF returns")
+        .bind(returnid)
+        .fetch_optional(&mut tx)
+        .await;
+    let (orderid, userid, paymentintentid) = match current {
+        Ok(Some((status, refundstatus, orderid, userid, paymentintentid)))
+            if status == "requested" || (status == "approved" && refundstatus.as_deref() == Some("failed")) => (orderid, userid, paymentintentid),
+        Ok(Some(_)) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::CONFLICT, Json(json!({
+                "status": "error",
+                "message": "Return has already been decided",
+            })))
+        },
+        Ok(None) => {
+            tx.rollback().await.unwrap();
+            return returnnotfound()
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    };
+    let priced = sqlx::query(
+        "UPDATE returnitems SET refundamount = ROUND(returnitems.quantity * paid.unitprice, 2)
+        FROM (
+            SELECT listitems.productid, listitems.variantid,
+            SUM(COALESCE(listitems.netamount + listitems.taxamount, listitems.unitprice * listitems.quantity)) / NULLIF(SUM(listitems.quantity), 0) AS unitprice
+            FROM listitems
+            WHERE listitems.orderidretr = $2
+            GROUP BY listitems.productid, listitems.variantid
+        ) paid
+        WHERE returnitems.returnid = $1
+        AND paid.productid = returnitems.productid
+        AND paid.variantid IS NOT DISTINCT FROM returnitems.variantid")
+        .bind(returnid)
+        .bind(orderid)
+        .execute(&mut tx)
+        .await;
+    let approved = match priced {
+        Ok(_) => sqlx::query_as::<_, (BigDecimal,)>(
+            "UPDATE returns SET
+            status = 'approved',
+            adminnote = COALESCE($2, adminnote),
+            decided_by = $3,
+            decided_at = now(),
+            refundstatus = 'pending',
+            refunderror = NULL,
+            refundamount = (SELECT COALESCE(SUM(refundamount), 0) FROM returnitems WHERE returnid = $1)
+            WHERE returnid = $1
+            RETURNING refundamount")
+            .bind(returnid)
+            .bind(req.note.as_deref().map(str::trim))
+            .bind(claims.sub)
+            .fetch_one(&mut tx)
+            .await,
+        Err(e) => Err(e),
+    };
+    let refundamount = match approved {
+        Ok((refundamount,)) => refundamount,
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    };
+    tx.commit().await.unwrap();
+
+    // The card refund happens outside the transaction; its outcome is recorded after
+    let pennies = (&refundamount * BigDecimal::from(100)).round(0).to_i64().unwrap_or(0);
+    let refund = match &paymentintentid {
+        Some(paymentintentid) if pennies > 0 => paymentapi::refundpayment(&state, paymentintentid, pennies, returnid).await,
+        Some(_) => Err("Nothing to refund".to_string()),
+        None => Err("No card payment is recorded for this order".to_string()),
+    };
+    let recorded = match &refund {
+        Ok(refundid) => sqlx::query("UPDATE returns SET refundstatus = 'succeeded', refundid = $2 WHERE returnid = $1")
+            .bind(returnid)
+            .bind(refundid)
+            .execute(&state.database.db)
+            .await,
+        Err(e) => sqlx::query("UPDATE returns SET refundstatus = 'failed', refunderror = $2 WHERE returnid = $1")
+            .bind(returnid)
+            .bind(e)
+            .execute(&state.database.db)
+            .await,
+    };
+    if let Err(e) = recorded {
+        return servererror(e)
+    }
+    match &refund {
+        Ok(_) => {
+            // Everything bought has now been refunded
+            let fullyrefunded = sqlx::query(
+                "UPDATE orderdet SET status = 'refunded'
+                WHERE orderid = $1 AND status <> 'refunded' AND NOT EXISTS (
+                    SELECT 1 FROM listitems
+                    WHERE listitems.orderidretr = $1
+                    AND listitems.quantity > COALESCE((SELECT SUM(returnitems.quantity) FROM returnitems
+                        INNER JOIN returns ON returns.returnid = returnitems.returnid
+                        WHERE returns.orderid = $1 AND returns.refundstatus = 'succeeded'
+                        AND returnitems.productid = listitems.productid
+                        AND returnitems.variantid IS NOT DISTINCT FROM listitems.variantid), 0))")
+                .bind(orderid)
+                .execute(&state.database.db)
+                .await;
+            let fullyrefunded = match fullyrefunded {
+                Ok(updated) if updated.rows_affected() > 0 => webhooks::orderevent(&state.database.db, "order.refunded", orderid).await.map(|_| ()),
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = fullyrefunded {
+                println!("order {} refund status update failed: {:?}", orderid, e);
+            }
+            notifications::customeremail(&state, userid, format!("Your return for order {} has been approved", orderid),
+                format!("Your return has been approved and a refund of £{} has been issued to your card. Please send the items back to us.", refundamount.with_scale(2)));
+        },
+        Err(e) => notifications::adminalert(&state, format!("Refund failed for return {}", returnid),
+            format!("The refund for return {} on order {} failed: {}", returnid, orderid, e),
+            json!({ "event": "return.refundfailed", "orderid": orderid, "returnid": returnid, "error": e })),
+    }
+    let status = if refund.is_ok() { StatusCode::OK } else { StatusCode::BAD_GATEWAY };
+    match loadreturn(&state.database.db, returnid).await {
+        Ok(ret) => (status, Json(json!({
+            "status": if refund.is_ok() { "success" } else { "error" },
+            "message": if refund.is_ok() { "Return approved and refunded" } else { "Return approved but the refund failed" },
+            "return": ret
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn rejectreturnhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(returnid): Path<Uuid>, Json(req): Json<ReturnDecision>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, (i64, Uuid)>(
+        "UPDATE returns SET status = 'rejected', adminnote = COALESCE($2, adminnote), decided_by = $3, decided_at = now()
+        WHERE returnid = $1 AND status = 'requested'
+        RETURNING orderid, userid")
+        .bind(returnid)
+        .bind(req.note.as_deref().map(str::trim))
+        .bind(claims.sub)
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some((orderid, userid))) => {
+            let reason = req.note.as_deref().map(|note| format!("\n\n{}", note.trim())).unwrap_or_default();
+            notifications::customeremail(&state, userid, format!("Your return for order {}", orderid),
+                format!("Unfortunately we are unable to accept your return.{}", reason));
+        },
+        Ok(None) => return (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "Only requested returns can be rejected",
+        }))),
+        Err(e) => return servererror(e),
+    }
+    match loadreturn(&state.database.db, returnid).await {
+        Ok(ret) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "return": ret
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+// Goods that come back are either put back into stock through the ledger or written off
+pub async fn receivereturnhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(returnid): Path<Uuid>, Json(req): Json<ReturnReceipt>) -> impl IntoResponse {
+    let valid = |disposition: &str| matches!(disposition, "restock" | "writeoff");
+    if matches!(req.disposition.as_deref(), Some(disposition) if !valid(disposition))
+        || req.items.iter().flatten().any(|item| !valid(&item.disposition)) {
+        return badrequest("disposition must be one of restock, writeoff")
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    let current = sqlx::query_as::<_, (String, i64, Uuid)>("SELECT status, orderid, userid FROM returns WHERE returnid = $1 FOR UPDATE")
+        .bind(returnid)
+        .fetch_optional(&mut tx)
+        .await;
+    let (orderid, userid) = match current {
+        Ok(Some((status, orderid, userid))) if status == "approved" => (orderid, userid),
+        Ok(Some(_)) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::CONFLICT, Json(json!({
+                "status": "error",
+                "message": "Only approved returns can be received",
+            })))
+        },
+        Ok(None) => {
+            tx.rollback().await.unwrap();
+            return returnnotfound()
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    };
+    let items = sqlx::query_as::<_, (Uuid, Uuid, Option<Uuid>, i32)>("SELECT returnitemid, productid, variantid, quantity FROM returnitems WHERE returnid = $1")
+        .bind(returnid)
+        .fetch_all(&mut tx)
+        .await;
+    let items = match items {
+        Ok(items) => items,
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    };
+    let note = format!("return {}", returnid);
+    let mut restocked = Vec::new();
+    for (returnitemid, productid, variantid, quantity) in items {
+        let disposition = req.items.iter().flatten()
+            .find(|item| item.returnitemid == returnitemid)
+            .map(|item| item.disposition.as_str())
+            .or(req.disposition.as_deref());
+        let disposition = match disposition {
+            Some(disposition) => disposition,
+            None => {
+                tx.rollback().await.unwrap();
+                return badrequest("A disposition is required for every item")
+            }
+        };
+        if disposition == "restock" {
+            let movement = inventory::recordmovement(&mut tx, StockMovement {
+                productid,
+                variantid,
+                reason: StockReason::Return,
+                delta: quantity as i64,
+                actor: Some(claims.sub),
+                orderid: Some(orderid),
+                note: Some(&note)
+            }).await;
+            if let Err(e) = movement {
+                tx.rollback().await.unwrap();
+                return servererror(e)
+            }
+            // Restocking a variant can bring its product back too
+            if !restocked.contains(&productid) {
+                restocked.push(productid);
+            }
+        }
+        let response = sqlx::query("UPDATE returnitems SET disposition = $2 WHERE returnitemid = $1")
+            .bind(returnitemid)
+            .bind(disposition)
+            .execute(&mut tx)
+            .await;
+        if let Err(e) = response {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    }
+    let response = sqlx::query("UPDATE returns SET status = 'received', received_at = now() WHERE returnid = $1")
+        .bind(returnid)
+        .execute(&mut tx)
+        .await;
+    if let Err(e) = response {
+        tx.rollback().await.unwrap();
+        return servererror(e)
+    }
+    tx.commit().await.unwrap();
+    for productid in restocked {
+        if let Err(e) = notifications::restockcheck(&state, productid).await {
+            println!("restock notifications failed for {}: {:?}", productid, e);
+        }
+    }
+    notifications::customeremail(&state, userid, format!("We have received your return for order {}", orderid),
+        "The items you sent back have arrived and your return is now complete.".to_string());
+    match loadreturn(&state.database.db, returnid).await {
+        Ok(ret) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "return": ret
+        }))),
+        Err(e) => servererror(e),
+    }
+}
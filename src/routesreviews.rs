@@ -0,0 +1,260 @@
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow};
+use uuid::Uuid;
+use crate::{AppState, customerrors::servererror, mware::ClaimsAccessToken};
+
+// Rating aggregates over approved reviews, used inline by the product queries.
+pub const AVERAGE_RATING_SQL: &str = "(SELECT ROUND(AVG(productreviews.rating), 2)::FLOAT8 FROM productreviews
+    WHERE productreviews.productid = products.productid AND productreviews.status = 'approved')";
+pub const REVIEW_COUNT_SQL: &str = "(SELECT COUNT(*) FROM productreviews
+    WHERE productreviews.productid = products.productid AND productreviews.status = 'approved')";
+
+// Orders that never completed do not count as a purchase
+const PURCHASED_SQL: &str = "SELECT EXISTS(
+    SELECT 1 FROM listitems
+    INNER JOIN orderdet ON orderdet.orderid = listitems.orderidretr
+    WHERE orderdet.userid = $1 AND listitems.productid = $2
+    AND orderdet.status NOT IN ('pending', 'expired', 'cancelled'))";
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct Review {
+    reviewid: Uuid,
+    productid: Uuid,
+    userid: Uuid,
+    fullname: String,
+    rating: i16,
+    body: String,
+    status: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReviewCreate {
+    rating: i16,
+    body: String
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReviewUpdate {
+    rating: Option<i16>,
+    body: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReviewModeration {
+    status: String
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ReviewQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+const REVIEW_SELECT: &str = "SELECT productreviews.reviewid, productreviews.productid, productreviews.userid, users.fullname,
+    productreviews.rating, productreviews.body, productreviews.status, productreviews.created_at, productreviews.updated_at
+    FROM productreviews
+    INNER JOIN users ON users.usid = productreviews.userid";
+
+
+fn validatereview(rating: Option<i16>, body: Option<&str>) -> Option<&'static str> {
+    if matches!(rating, Some(rating) if !(1..=5).contains(&rating)) {
+        return Some("rating must be between 1 and 5")
+    }
+    if matches!(body, Some(body) if body.trim().is_empty()) {
+        return Some("review text cannot be empty")
+    }
+    None
+}
+
+
+//Public review routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchproductreviewshandler(State(state): State<AppState>, Path(productid): Path<Uuid>, Query(query): Query<ReviewQuery>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, Review>(&format!(
+        "{} WHERE productreviews.productid = $1 AND productreviews.status = 'approved'
+        ORDER BY productreviews.created_at DESC
+        LIMIT $2 OFFSET $3", REVIEW_SELECT))
+        .bind(productid)
+        .bind(query.limit.unwrap_or(20).clamp(1, 100))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(reviews) => (StatusCode::OK, Json(json!({
+            "reviews": reviews
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Customer review routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn createreviewhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(productid): Path<Uuid>, Json(req): Json<ReviewCreate>) -> impl IntoResponse {
+    if let Some(message) = validatereview(Some(req.rating), Some(&req.body)) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": message,
+        })))
+    }
+    let purchased = sqlx::query_as::<_, (bool,)>(PURCHASED_SQL)
+        .bind(claims.sub)
+        .bind(productid)
+        .fetch_one(&state.database.db)
+        .await;
+    match purchased {
+        Ok((true,)) => {},
+        Ok((false,)) => return (StatusCode::FORBIDDEN, Json(json!({
+            "status": "error",
+            "message": "Only customers who bought this product can review it",
+        }))),
+        Err(e) => return servererror(e),
+    }
+    let reviewid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    let response = sqlx::query(
+        "INSERT INTO productreviews (reviewid, productid, userid, rating, body)
+        VALUES ($1, $2, $3, $4, $5)")
+        .bind(reviewid)
+        .bind(productid)
+        .bind(claims.sub)
+        .bind(req.rating)
+        .bind(req.body.trim())
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(_) => (StatusCode::CREATED, Json(json!({
+            "status": "success",
+            "reviewid": reviewid,
+            "reviewstatus": "pending"
+        }))),
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "You have already reviewed this product",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn fetchmyreviewshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, Review>(&format!(
+        "{} WHERE productreviews.userid = $1 ORDER BY productreviews.created_at DESC", REVIEW_SELECT))
+        .bind(claims.sub)
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(reviews) => (StatusCode::OK, Json(json!({
+            "reviews": reviews
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+// Edited reviews go back into the moderation queue
+pub async fn updatereviewhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(reviewid): Path<Uuid>, Json(req): Json<ReviewUpdate>) -> impl IntoResponse {
+    if let Some(message) = validatereview(req.rating, req.body.as_deref()) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": message,
+        })))
+    }
+    let response = sqlx::query(
+        "UPDATE productreviews SET
+        rating = COALESCE($1, rating),
+        body = COALESCE($2, body),
+        status = 'pending',
+        moderated_by = NULL,
+        updated_at = now()
+        WHERE reviewid = $3 AND userid = $4")
+        .bind(req.rating)
+        .bind(req.body.as_deref().map(str::trim))
+        .bind(reviewid)
+        .bind(claims.sub)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Review not found",
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "updated": "success",
+            "reviewstatus": "pending"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn deletereviewhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(reviewid): Path<Uuid>) -> impl IntoResponse {
+    let response = sqlx::query("DELETE FROM productreviews WHERE reviewid = $1 AND userid = $2")
+        .bind(reviewid)
+        .bind(claims.sub)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Review not found",
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "review": "deleted"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Review moderation routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchreviewqueuehandler(State(state): State<AppState>, Query(query): Query<ReviewQuery>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, Review>(&format!(
+        "{} WHERE productreviews.status = $1
+        ORDER BY productreviews.updated_at
+        LIMIT $2 OFFSET $3", REVIEW_SELECT))
+        .bind(query.status.as_deref().unwrap_or("pending"))
+        .bind(query.limit.unwrap_or(50).clamp(1, 500))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(reviews) => (StatusCode::OK, Json(json!({
+            "reviews": reviews
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn moderatereviewhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(reviewid): Path<Uuid>, Json(req): Json<ReviewModeration>) -> impl IntoResponse {
+    if !matches!(req.status.as_str(), "approved" | "rejected" | "pending") {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "status must be one of approved, rejected, pending",
+        })))
+    }
+    let response = sqlx::query("UPDATE productreviews SET status = $1, moderated_by = $2 WHERE reviewid = $3")
+        .bind(&req.status)
+        .bind(claims.sub)
+        .bind(reviewid)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Review not found",
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "reviewstatus": req.status
+        }))),
+        Err(e) => servererror(e),
+    }
+}
@@ -0,0 +1,77 @@
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow};
+use uuid::Uuid;
+use crate::{AppState, customerrors::servererror, loginsecurity, mware::ClaimsAccessToken};
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct SecurityEvent {
+    eventid: Uuid,
+    userid: Option<Uuid>,
+    email: Option<String>,
+    kind: String,
+    ip: Option<String>,
+    actor: Option<Uuid>,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct SecurityEventQuery {
+    userid: Option<Uuid>,
+    kind: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+
+//Fetch security events route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchsecurityeventshandler(State(state): State<AppState>, Query(query): Query<SecurityEventQuery>) -> impl IntoResponse {
+    if !matches!(query.kind.as_deref(), None | Some("lockout") | Some("unlock") | Some("newip") | Some("iplockout")) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "kind must be one of lockout, unlock, newip, iplockout",
+        })))
+    }
+    let response = sqlx::query_as::<_, SecurityEvent>(
+        "SELECT securityevents.eventid, securityevents.userid, users.email, securityevents.kind, securityevents.ip,
+        securityevents.actor, securityevents.created_at
+        FROM securityevents
+        LEFT JOIN users ON users.usid = securityevents.userid
+        WHERE ($1::uuid IS NULL OR securityevents.userid = $1)
+        AND ($2::text IS NULL OR securityevents.kind = $2)
+        ORDER BY securityevents.created_at DESC
+        LIMIT $3 OFFSET $4")
+        .bind(query.userid)
+        .bind(&query.kind)
+        .bind(query.limit.unwrap_or(50).clamp(1, 200))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(events) => (StatusCode::OK, Json(json!({
+            "events": events
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Unlock account route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn unlockuserhandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>) -> impl IntoResponse {
+    match loginsecurity::unlock(&state, userid, claims.sub).await {
+        Ok(true) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "message": "Account unlocked",
+        }))),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "User not found",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
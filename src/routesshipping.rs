@@ -0,0 +1,178 @@
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::{HeaderMap, StatusCode}};
+use bigdecimal::BigDecimal;
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+use crate::{AppState, cart::{self, Cart}, customerrors::{badrequest, servererror}, mware::ClaimsAccessToken, pricing::RateKind, routescart::cartowner, shipping::{self, ShippingMethod}};
+
+#[derive(Deserialize, Debug)]
+
+pub struct ShippingQuery {
+    postcode: String
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct NewShippingMethod {
+    name: String,
+    description: Option<String>,
+    ratekind: String,
+    baserate: BigDecimal,
+    perkg: Option<BigDecimal>,
+    freeabove: Option<BigDecimal>,
+    maxweightgrams: Option<i32>,
+    postcodeprefixes: Option<Vec<String>>,
+    position: Option<i32>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct ShippingMethodUpdate {
+    name: Option<String>,
+    description: Option<String>,
+    baserate: Option<BigDecimal>,
+    perkg: Option<BigDecimal>,
+    freeabove: Option<BigDecimal>,
+    maxweightgrams: Option<i32>,
+    postcodeprefixes: Option<Vec<String>>,
+    active: Option<bool>,
+    position: Option<i32>
+}
+
+
+fn normaliseprefixes(prefixes: Option<Vec<String>>) -> Option<Vec<String>> {
+    prefixes.map(|prefixes| prefixes.iter()
+        .map(|prefix| shipping::compactpostcode(prefix))
+        .filter(|prefix| !prefix.is_empty())
+        .collect())
+}
+
+fn negative(amount: &Option<BigDecimal>) -> bool {
+    matches!(amount, Some(amount) if *amount < BigDecimal::from(0))
+}
+
+
+//Shipping options route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Methods and costs for the caller's current cart shipped to the given postcode
+pub async fn shippingoptionshandler(State(state): State<AppState>, claims: Option<Extension<ClaimsAccessToken>>, headers: HeaderMap, Query(query): Query<ShippingQuery>) -> impl IntoResponse {
+    if query.postcode.trim().is_empty() {
+        return badrequest("postcode is required")
+    }
+    let cartid = match cartowner(&claims, &headers) {
+        Some(owner) => cart::findcart(&state.database.db, &owner).await,
+        None => Ok(None),
+    };
+    let basket = match cartid {
+        Ok(Some(cartid)) => cart::loadcart(&state.database.db, cartid).await,
+        Ok(None) => Ok(Cart::empty()),
+        Err(e) => Err(e),
+    };
+    let basket = match basket {
+        Ok(basket) => basket,
+        Err(e) => return servererror(e),
+    };
+    match shipping::availablemethods(&state.database.db, &query.postcode, &basket.subtotal, basket.weightgrams).await {
+        Ok(methods) => (StatusCode::OK, Json(json!({
+            "subtotal": basket.subtotal,
+            "weightgrams": basket.weightgrams,
+            "methods": methods
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Admin shipping method routes ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchshippingmethodshandler(State(state): State<AppState>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, ShippingMethod>(
+        "SELECT * FROM shippingmethods ORDER BY position, created_at")
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(methods) => (StatusCode::OK, Json(json!({
+            "methods": methods
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn createshippingmethodhandler(State(state): State<AppState>, Json(req): Json<NewShippingMethod>) -> impl IntoResponse {
+    if req.name.trim().is_empty() {
+        return badrequest("name is required")
+    }
+    let ratekind = match RateKind::from_str(&req.ratekind) {
+        Some(ratekind) => ratekind,
+        None => return badrequest("ratekind must be one of flat, weight, freeabove"),
+    };
+    if ratekind == RateKind::FreeAbove && req.freeabove.is_none() {
+        return badrequest("freeabove is required for a freeabove rate")
+    }
+    if negative(&Some(req.baserate.clone())) || negative(&req.perkg) || negative(&req.freeabove) {
+        return badrequest("rates cannot be negative")
+    }
+    let response = sqlx::query_as::<_, ShippingMethod>(
+        "INSERT INTO shippingmethods (methodid, name, description, ratekind, baserate, perkg, freeabove, maxweightgrams, postcodeprefixes, position)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) RETURNING *")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(req.name.trim())
+        .bind(&req.description)
+        .bind(ratekind.as_str())
+        .bind(&req.baserate)
+        .bind(req.perkg.unwrap_or_else(|| BigDecimal::from(0)))
+        .bind(&req.freeabove)
+        .bind(req.maxweightgrams)
+        .bind(normaliseprefixes(req.postcodeprefixes).unwrap_or_default())
+        .bind(req.position.unwrap_or(0))
+        .fetch_one(&state.database.db)
+        .await;
+    match response {
+        Ok(method) => (StatusCode::CREATED, Json(json!({
+            "status": "success",
+            "method": method
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+pub async fn updateshippingmethodhandler(State(state): State<AppState>, Path(methodid): Path<Uuid>, Json(req): Json<ShippingMethodUpdate>) -> impl IntoResponse {
+    if negative(&req.baserate) || negative(&req.perkg) || negative(&req.freeabove) {
+        return badrequest("rates cannot be negative")
+    }
+    let response = sqlx::query_as::<_, ShippingMethod>(
+        "UPDATE shippingmethods SET
+        name = COALESCE(NULLIF($2, ''), name),
+        description = COALESCE($3, description),
+        baserate = COALESCE($4, baserate),
+        perkg = COALESCE($5, perkg),
+        freeabove = COALESCE($6, freeabove),
+        maxweightgrams = COALESCE($7, maxweightgrams),
+        postcodeprefixes = COALESCE($8, postcodeprefixes),
+        active = COALESCE($9, active),
+        position = COALESCE($10, position)
+        WHERE methodid = $1 RETURNING *")
+        .bind(methodid)
+        .bind(req.name.as_deref().map(str::trim))
+        .bind(&req.description)
+        .bind(&req.baserate)
+        .bind(&req.perkg)
+        .bind(&req.freeabove)
+        .bind(req.maxweightgrams)
+        .bind(normaliseprefixes(req.postcodeprefixes))
+        .bind(req.active)
+        .bind(req.position)
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some(method)) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "method": method
+        }))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Shipping method not found",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
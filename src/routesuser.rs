@@ -1,5 +1,5 @@
 use axum::{
-    extract::{State, Path},
+    extract::{State, Path, ConnectInfo},
     Json,
     response::IntoResponse,
     http::{StatusCode, HeaderMap},  
@@ -8,7 +8,8 @@ use serde::{Serialize, Deserialize};
 use sqlx::{self, FromRow};
 use uuid::Uuid;
 use serde_json::json;
-use crate::AppState;
+use crate::{AppState, cart, customerrors::badrequest, loginsecurity, routescart::CART_TOKEN_HEADER, shipping::normaliseukpostcode};
+use std::net::SocketAddr;
 use core::fmt;
 use std::borrow::Cow;
 use tower_cookies::{Cookie, Cookies};
@@ -33,9 +34,9 @@ struct User {
     mob_phone: String,
     email: String,
     created_at: chrono::DateTime<chrono::Utc>,
-    address: String,
-    city: String,
-    postcode: String
+    address: Option<String>,
+    city: Option<String>,
+    postcode: Option<String>
 }
 
 //User model for register
@@ -57,6 +58,9 @@ pub struct UserReg {
 pub struct UserLoginUuid{
     usid: Uuid,
     passwd: String,
+    failedlogins: i32,
+    lastfailedlogin_at: Option<chrono::DateTime<Utc>>,
+    lockeduntil: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, FromRow, Debug)]
@@ -165,7 +169,7 @@ pub struct UserLogin{
 pub async fn fetchusershandler(State(state): State<AppState>) -> impl IntoResponse {
     let response = sqlx::query_as::<_, User>("SELECT users.usid, users.fullname, users.username, users.dob, users.gender, users.mob_phone, users.email, users.created_at, useraddr.address, useraddr.city, useraddr.postcode
     FROM users
-    INNER JOIN useraddr ON users.usid = useraddr.userid")
+    LEFT JOIN useraddr ON users.usid = useraddr.userid AND useraddr.defaultshipping")
     .fetch_all(&state.database.db)
     .await;
     match response {
@@ -185,6 +189,10 @@ pub async fn fetchusershandler(State(state): State<AppState>) -> impl IntoRespon
 //reg route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
 
 pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl IntoResponse {
+    let postcode = match normaliseukpostcode(&req.postcode) {
+        Some(postcode) => postcode,
+        None => return badrequest("postcode is not a valid UK postcode")
+    };
     let usid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()); 
     let addrid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()); 
         let argon2 = Argon2::default();
@@ -208,12 +216,12 @@ pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl
             match response {
                 Ok( _ ) => {
                     let response = sqlx::query(
-                        "INSERT INTO useraddr (addrid, userid, address, city, postcode) VALUES ($1, $2, $3, $4, $5)")
+                        "INSERT INTO useraddr (addrid, userid, address, city, postcode, defaultshipping, defaultbilling) VALUES ($1, $2, $3, $4, $5, true, true)")
                         .bind(addrid)
                         .bind(usid)
                         .bind(&req.address)
                         .bind(&req.city)
-                        .bind(&req.postcode)
+                        .bind(postcode)
                         .execute(&mut tx)
                         .await;
 
@@ -282,7 +290,7 @@ pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl
 //login user route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
 
     
-    pub async fn loginuser(State(state): State<AppState>, cookies: Cookies, req: Json<UserLogin>) -> impl IntoResponse {
+    pub async fn loginuser(State(state): State<AppState>, cookies: Cookies, requestheaders: HeaderMap, ConnectInfo(addr): ConnectInfo<SocketAddr>, req: Json<UserLogin>) -> impl IntoResponse {
         let mut headers = HeaderMap::new();
     
         if req.passwd.is_empty() || req.email.is_empty(){
@@ -291,13 +299,37 @@ pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl
                 "message": "Email or password cannot be empty"
             })));
         }
+        let ip = loginsecurity::clientip(&state.login, &requestheaders, addr);
+        match loginsecurity::ipretryafter(&state, &ip).await {
+            Ok(Some(retryafter)) => return toomanyattempts(retryafter),
+            Ok(None) => {},
+            Err(e) => println!("login rate limit check failed for {}: {:?}", ip, e),
+        }
         let _cow = Cow::Borrowed("23505");
         let response =  sqlx::query_as::<_, UserLoginUuid>("SELECT * FROM users where email = $1", )
         .bind(&req.email)
-        .fetch_one(&state.database.db)
+        .fetch_optional(&state.database.db)
         .await;
         match response {
-            Ok(user) => {    
+            Ok(None) => {
+                match loginsecurity::emailretryafter(&state, &req.email).await {
+                    Ok(Some(retryafter)) => return toomanyattempts(retryafter),
+                    Ok(None) => {},
+                    Err(e) => println!("login rate limit check failed for {}: {:?}", ip, e),
+                }
+                if let Err(e) = loginsecurity::recordfailure(&state, None, &req.email, &ip).await {
+                    println!("failed login could not be recorded for {}: {:?}", ip, e);
+                }
+                (StatusCode::BAD_REQUEST, headers, Json(json!({
+                    "status": "error",
+                    "message": "Invalid email or password",
+                })))
+            }
+            Ok(Some(user)) => {    
+                // Locked accounts and attempts inside the back-off are turned away before the password is checked
+                if let Some(retryafter) = loginsecurity::accountretryafter(&state.login, user.failedlogins, user.lastfailedlogin_at, user.lockeduntil) {
+                    return toomanyattempts(retryafter)
+                }
                 let parsed_hash = PasswordHash::new(&user.passwd).unwrap();
                 let is_pass_valid = Argon2::default().verify_password(req.passwd.as_bytes(), &parsed_hash).is_ok();
                 if is_pass_valid {
@@ -307,7 +339,7 @@ pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl
                     let access_secret = &state.accesstoken.accesstoken.as_bytes();
                     let access_token = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &ClaimsAccessToken::new(user.usid, role_access),&EncodingKey::from_secret(access_secret)).unwrap();
                     let refresh_secret = &state.refreshtoken.refreshtoken.as_bytes();
-                    let refresh_stoken = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &ClaimsRefreshToken::new(user.usid, role_refresh),&EncodingKey::from_secret(refresh_secret.as_ref())).unwrap();
+                    let refresh_stoken = jsonwebtoken::encode(&Header::new(Algorithm::HS256), &ClaimsRefreshToken::new(user.usid, role_refresh),&EncodingKey::from_secret(refresh_secret)).unwrap();
                     // let bearertoken = format!("Bearer {}", access_token);
                     cookies.add(Cookie::build("Refresh Token", refresh_stoken.to_string())
                     .domain("axumtoyserver.shuttleapp.rs")
@@ -317,6 +349,15 @@ pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl
                     .finish());
                     
                     headers.insert("Authorization", access_token.parse().unwrap());
+                    if let Err(e) = loginsecurity::recordsuccess(&state, user.usid, &ip).await {
+                        println!("login could not be recorded for {}: {:?}", user.usid, e);
+                    }
+                    // Anything the customer put in their cart before logging in carries over
+                    if let Some(carttoken) = requestheaders.get(CART_TOKEN_HEADER).and_then(|value| value.to_str().ok()) {
+                        if let Err(e) = cart::mergeguestcart(&state.database.db, carttoken, user.usid).await {
+                            println!("guest cart merge failed for {}: {:?}", user.usid, e);
+                        }
+                    }
                     (StatusCode::OK, headers, Json(json!({
                         "status": "success",
                         "message": "User logged in successfully",
@@ -324,6 +365,9 @@ pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl
                         "refresh_token": refresh_stoken.to_string(),
                     })))
                 } else {
+                    if let Err(e) = loginsecurity::recordfailure(&state, Some(user.usid), &req.email, &ip).await {
+                        println!("failed login could not be recorded for {}: {:?}", user.usid, e);
+                    }
                     (StatusCode::BAD_REQUEST, headers, Json(json!({
                         "status": "error",
                         "message": "Invalid email or password",
@@ -352,6 +396,16 @@ pub async fn regroute(State(state): State<AppState>, req: Json<UserReg>) -> impl
         
     }
     
+fn toomanyattempts(retryafter: i64) -> (StatusCode, HeaderMap, Json<serde_json::Value>) {
+    let mut headers = HeaderMap::new();
+    headers.insert("Retry-After", retryafter.to_string().parse().unwrap());
+    (StatusCode::TOO_MANY_REQUESTS, headers, Json(json!({
+        "status": "error",
+        "message": "Too many failed sign-in attempts, please try again later",
+        "retry_after": retryafter,
+    })))
+}
+
 //Refresh token route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
 
 //Refresh token route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
@@ -412,7 +466,7 @@ pub async fn fetchsingleusershandler (State(state): State<AppState>, Path(usid):
     "SELECT 
     users.usid, users.fullname, users.username, users.dob, users.gender, users.mob_phone, users.email, users.created_at, useraddr.address, useraddr.city, useraddr.postcode
     FROM users
-    INNER JOIN useraddr ON users.usid = useraddr.userid
+    LEFT JOIN useraddr ON users.usid = useraddr.userid AND useraddr.defaultshipping
     WHERE users.usid = $1")
     .bind(usid)
     .fetch_all(&state.database.db)
@@ -435,6 +489,14 @@ pub async fn fetchsingleusershandler (State(state): State<AppState>, Path(usid):
 
 
 pub async fn updateuserhandler(State(state): State<AppState>, Path(usid): Path<Uuid>, req: Json<EditReg>) ->  impl IntoResponse {
+        // A missing or empty postcode keeps the current one
+        let postcode = match req.postcode.as_deref().filter(|postcode| !postcode.trim().is_empty()) {
+            Some(postcode) => match normaliseukpostcode(postcode) {
+                Some(postcode) => Some(postcode),
+                None => return badrequest("postcode is not a valid UK postcode")
+            },
+            None => None
+        };
         let argon2 = Argon2::default();
         let salt = SaltString::generate(&mut OsRng);
         let my_bytes = req.passwd.clone().unwrap();
@@ -477,12 +539,12 @@ pub async fn updateuserhandler(State(state): State<AppState>, Path(usid): Path<U
                         address = COALESCE(NULLIF($1, ''), address),
                         city = COALESCE(NULLIF($2, ''), city),
                         postcode = COALESCE(NULLIF($3, ''), postcode)
-                        WHERE userid = $4
+                        WHERE userid = $4 AND defaultshipping
                         "
                     )
                         .bind(&req.address)
                         .bind(&req.city)
-                        .bind(&req.postcode)
+                        .bind(postcode)
                         .bind(usid)
                         .execute(&mut tx)
                         .await;
@@ -0,0 +1,484 @@
+use axum::{Json, Extension, extract::{Path, State}, response::IntoResponse, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow, Pool, Postgres};
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::{AppState, customerrors::servererror, inventory::{self, StockMovement, StockReason, HELD_VARIANT_SQL}, mware::ClaimsAccessToken, notifications, pricing::parseprice, webhooks};
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct OptionValue {
+    valueid: Uuid,
+    #[serde(skip_serializing)]
+    optionid: Uuid,
+    value: String
+}
+
+#[derive(Serialize, Debug)]
+
+pub struct ProductOption {
+    optionid: Uuid,
+    name: String,
+    values: Vec<OptionValue>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct Variant {
+    variantid: Uuid,
+    sku: String,
+    price: String,
+    availableqty: i64,
+    availabletosell: i64,
+    valueids: Vec<Uuid>,
+    imageids: Vec<Uuid>
+}
+
+#[derive(Serialize, Debug)]
+
+pub struct VariantMatrix {
+    options: Vec<ProductOption>,
+    variants: Vec<Variant>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct OptionCreate {
+    name: String,
+    values: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct VariantCreate {
+    sku: String,
+    price: Option<String>,
+    availableqty: i64,
+    valueids: Vec<Uuid>,
+    imageids: Option<Vec<Uuid>>
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+
+pub struct VariantUpdate {
+    sku: Option<String>,
+    price: Option<String>,
+    availableqty: Option<i64>,
+    imageids: Option<Vec<Uuid>>
+}
+
+#[derive(FromRow, Debug)]
+
+struct OptionRow {
+    optionid: Uuid,
+    name: String
+}
+
+pub async fn fetchvariantmatrix(db: &Pool<Postgres>, productid: Uuid) -> Result<VariantMatrix, sqlx::Error> {
+    let options = sqlx::query_as::<_, OptionRow>(
+        "SELECT optionid, name FROM productoptions WHERE productid = $1 ORDER BY position, name")
+        .bind(productid)
+        .fetch_all(db)
+        .await?;
+    let values = sqlx::query_as::<_, OptionValue>(
+        "SELECT productoptionvalues.valueid, productoptionvalues.optionid, productoptionvalues.value
+        FROM productoptionvalues
+        INNER JOIN productoptions ON productoptionvalues.optionid = productoptions.optionid
+        WHERE productoptions.productid = $1
+        ORDER BY productoptionvalues.position, productoptionvalues.value")
+        .bind(productid)
+        .fetch_all(db)
+        .await?;
+    let mut grouped: HashMap<Uuid, Vec<OptionValue>> = HashMap::new();
+    for value in values {
+        grouped.entry(value.optionid).or_default().push(value);
+    }
+    let options = options.into_iter().map(|option| ProductOption {
+        values: grouped.remove(&option.optionid).unwrap_or_default(),
+        optionid: option.optionid,
+        name: option.name
+    }).collect();
+
+    let variants = sqlx::query_as::<_, Variant>(&format!(
+        "SELECT productvariants.variantid, productvariants.sku,
+        COALESCE(productvariants.price, products.price) AS price,
+        productvariants.availableqty,
+        productvariants.availableqty - {} AS availabletosell,
+        ARRAY(SELECT valueid FROM variantoptionvalues WHERE variantoptionvalues.variantid = productvariants.variantid) AS valueids,
+        ARRAY(SELECT imageid FROM productgallery WHERE productgallery.variantid = productvariants.variantid ORDER BY position) AS imageids
+        FROM productvariants
+        INNER JOIN products ON productvariants.productid = products.productid
+        WHERE productvariants.productid = $1
+        ORDER BY productvariants.created_at", HELD_VARIANT_SQL))
+        .bind(productid)
+        .fetch_all(db)
+        .await?;
+    Ok(VariantMatrix { options, variants })
+}
+
+async fn assignimages(tx: &mut sqlx::Transaction<'_, Postgres>, productid: Uuid, variantid: Uuid, imageids: &[Uuid]) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE productgallery SET variantid = NULL WHERE variantid = $1")
+        .bind(variantid)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE productgallery SET variantid = $1 WHERE productid = $2 AND imageid = ANY($3)")
+        .bind(variantid)
+        .bind(productid)
+        .bind(imageids)
+        .execute(&mut *tx)
+        .await?;
+    Ok(())
+}
+
+
+//Variant matrix route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchvariantshandler(State(state): State<AppState>, Path(productid): Path<Uuid>) -> impl IntoResponse {
+    match fetchvariantmatrix(&state.database.db, productid).await {
+        Ok(matrix) => (StatusCode::OK, Json(json!(matrix))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Create option route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn createoptionhandler(State(state): State<AppState>, Path(productid): Path<Uuid>, Json(req): Json<OptionCreate>) -> impl IntoResponse {
+    if req.name.trim().is_empty() || req.values.is_empty() {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Option name and at least one value are required",
+        })))
+    }
+    let optionid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    let mut tx = state.database.db.begin().await.unwrap();
+    // Existing variants would be left without a value for the new option
+    let variants = sqlx::query_as::<_, (i64,)>(
+        "SELECT (SELECT COUNT(*) FROM productvariants WHERE productid = $1)
+        FROM products WHERE productid = $1 FOR UPDATE")
+        .bind(productid)
+        .fetch_optional(&mut tx)
+        .await;
+    match variants {
+        Ok(Some((0,))) => {},
+        Ok(Some(_)) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::CONFLICT, Json(json!({
+                "status": "error",
+                "message": "Options cannot be added to a product that already has variants",
+            })))
+        },
+        Ok(None) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::NOT_FOUND, Json(json!({
+                "status": "error",
+                "message": "Product not found",
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    }
+    let response = sqlx::query(
+        "INSERT INTO productoptions (optionid, productid, name, position)
+        VALUES ($1, $2, $3, (SELECT COUNT(*) FROM productoptions WHERE productid = $2))")
+        .bind(optionid)
+        .bind(productid)
+        .bind(req.name.trim())
+        .execute(&mut tx)
+        .await;
+    if let Err(e) = response {
+        tx.rollback().await.unwrap();
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "Could not create option",
+            "error": e.to_string(),
+        })))
+    }
+    for (position, value) in req.values.iter().enumerate() {
+        let valueid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+        let response = sqlx::query(
+            "INSERT INTO productoptionvalues (valueid, optionid, value, position) VALUES ($1, $2, $3, $4)")
+            .bind(valueid)
+            .bind(optionid)
+            .bind(value.trim())
+            .bind(position as i32)
+            .execute(&mut tx)
+            .await;
+        if let Err(e) = response {
+            tx.rollback().await.unwrap();
+            return (StatusCode::BAD_REQUEST, Json(json!({
+                "status": "error",
+                "message": "Could not create option value",
+                "error": e.to_string(),
+            })))
+        }
+    }
+    tx.commit().await.unwrap();
+    (StatusCode::CREATED, Json(json!({
+        "optionid": optionid
+    })))
+}
+
+
+// An empty price means the variant sells at the product's price
+fn invalidprice(price: &Option<String>) -> Option<(StatusCode, Json<serde_json::Value>)> {
+    match price.as_deref() {
+        Some(price) if !price.trim().is_empty() && parseprice(price).is_none() => Some((StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "price must be a decimal amount such as 9.99",
+        })))),
+        _ => None,
+    }
+}
+
+
+//Create variant route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn createvarianthandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(productid): Path<Uuid>, Json(req): Json<VariantCreate>) -> impl IntoResponse {
+    if req.sku.trim().is_empty() || req.availableqty < 0 {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "A SKU and a non-negative availableqty are required",
+        })))
+    }
+    if let Some(response) = invalidprice(&req.price) {
+        return response
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    // Every value must belong to this product and each of its options needs exactly one value.
+    // The product row is locked so two requests can't create the same combination at once.
+    let locked = sqlx::query("SELECT productid FROM products WHERE productid = $1 FOR UPDATE")
+        .bind(productid)
+        .execute(&mut tx)
+        .await;
+    let check = match locked {
+        Ok(_) => sqlx::query_as::<_, (i64, i64, i64, bool)>(
+            "SELECT COUNT(*), COUNT(DISTINCT productoptions.optionid),
+            (SELECT COUNT(*) FROM productoptions WHERE productid = $1),
+            EXISTS (SELECT 1 FROM productvariants
+                WHERE productvariants.productid = $1
+                AND (SELECT COUNT(*) FROM variantoptionvalues
+                    WHERE variantoptionvalues.variantid = productvariants.variantid
+                    AND variantoptionvalues.valueid = ANY($2)) = cardinality($2))
+            FROM productoptionvalues
+            INNER JOIN productoptions ON productoptionvalues.optionid = productoptions.optionid
+            WHERE productoptions.productid = $1 AND productoptionvalues.valueid = ANY($2)")
+            .bind(productid)
+            .bind(&req.valueids)
+            .fetch_one(&mut tx)
+            .await,
+        Err(e) => Err(e),
+    };
+    match check {
+        Ok((found, options, productoptions, duplicate)) if found > 0 && found == req.valueids.len() as i64 && options == found && options == productoptions => {
+            if duplicate {
+                tx.rollback().await.unwrap();
+                return (StatusCode::CONFLICT, Json(json!({
+                    "status": "error",
+                    "message": "A variant with these option values already exists",
+                })))
+            }
+        },
+        Ok(_) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::BAD_REQUEST, Json(json!({
+                "status": "error",
+                "message": "valueids must hold one value of this product for each of its options",
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            return servererror(e)
+        }
+    }
+
+    let variantid = sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128());
+    let response = sqlx::query(
+        "INSERT INTO productvariants (variantid, productid, sku, price, availableqty, created_at)
+        VALUES ($1, $2, $3, NULLIF($4, ''), 0, $5)")
+        .bind(variantid)
+        .bind(productid)
+        .bind(req.sku.trim())
+        .bind(&req.price)
+        .bind(chrono::Utc::now())
+        .execute(&mut tx)
+        .await;
+    let response = match response {
+        Ok(_) => sqlx::query(
+            "INSERT INTO variantoptionvalues (variantid, valueid) SELECT $1, UNNEST($2::uuid[])")
+            .bind(variantid)
+            .bind(&req.valueids)
+            .execute(&mut tx)
+            .await
+            .map(|_| ()),
+        Err(e) => Err(e),
+    };
+    let response = match (response, &req.imageids) {
+        (Ok(_), Some(imageids)) => assignimages(&mut tx, productid, variantid, imageids).await,
+        (response, _) => response,
+    };
+    let response = match response {
+        Ok(_) if req.availableqty > 0 => inventory::recordmovement(&mut tx, StockMovement {
+            productid,
+            variantid: Some(variantid),
+            reason: StockReason::Restock,
+            delta: req.availableqty,
+            actor: Some(claims.sub),
+            orderid: None,
+            note: Some("initial stock")
+        }).await.map(|_| ()),
+        response => response,
+    };
+    let response = match response {
+        Ok(_) => webhooks::productupdated(&mut tx, productid).await,
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(_) => {
+            tx.commit().await.unwrap();
+            if req.availableqty > 0 {
+                if let Err(e) = notifications::restockcheck(&state, productid).await {
+                    println!("restock notifications failed for {}: {:?}", productid, e);
+                }
+            }
+            (StatusCode::CREATED, Json(json!({
+                "variantid": variantid
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            match e {
+                sqlx::Error::Database(e) if e.code().as_deref() == Some("23505") => (StatusCode::BAD_REQUEST, Json(json!({
+                    "status": "error",
+                    "message": "SKU already exists",
+                }))),
+                e => servererror(e),
+            }
+        }
+    }
+}
+
+
+//Update variant route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn updatevarianthandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((productid, variantid)): Path<(Uuid, Uuid)>, Json(req): Json<VariantUpdate>) -> impl IntoResponse {
+    if matches!(req.availableqty, Some(qty) if qty < 0) {
+        return (StatusCode::BAD_REQUEST, Json(json!({
+            "status": "error",
+            "message": "availableqty cannot be negative",
+        })))
+    }
+    if let Some(response) = invalidprice(&req.price) {
+        return response
+    }
+    let mut tx = state.database.db.begin().await.unwrap();
+    let response = sqlx::query_as::<_, (i64,)>(
+        "UPDATE productvariants
+        SET
+        sku = COALESCE(NULLIF($1, ''), sku),
+        price = CASE WHEN $2::text IS NULL THEN price ELSE NULLIF($2, '') END
+        WHERE productid = $3 AND variantid = $4
+        RETURNING availableqty")
+        .bind(&req.sku)
+        .bind(&req.price)
+        .bind(productid)
+        .bind(variantid)
+        .fetch_optional(&mut tx)
+        .await;
+    let restocked = matches!((&response, req.availableqty), (Ok(Some((onhand,))), Some(availableqty)) if availableqty > *onhand);
+    let response = match response {
+        Ok(None) => {
+            tx.rollback().await.unwrap();
+            return (StatusCode::NOT_FOUND, Json(json!({
+                "status": "error",
+                "message": "Variant not found",
+            })))
+        },
+        Ok(Some((onhand,))) => match req.availableqty {
+            Some(availableqty) if availableqty != onhand => inventory::recordmovement(&mut tx, StockMovement {
+                productid,
+                variantid: Some(variantid),
+                reason: StockReason::Adjustment,
+                delta: availableqty - onhand,
+                actor: Some(claims.sub),
+                orderid: None,
+                note: Some("variant update")
+            }).await.map(|_| ()),
+            _ => Ok(()),
+        },
+        Err(e) => Err(e),
+    };
+    let response = match (response, &req.imageids) {
+        (Ok(_), Some(imageids)) => assignimages(&mut tx, productid, variantid, imageids).await,
+        (response, _) => response,
+    };
+    let response = match response {
+        Ok(_) => webhooks::productupdated(&mut tx, productid).await,
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(_) => {
+            tx.commit().await.unwrap();
+            if restocked {
+                if let Err(e) = notifications::restockcheck(&state, productid).await {
+                    println!("restock notifications failed for {}: {:?}", productid, e);
+                }
+            }
+            (StatusCode::OK, Json(json!({
+                "updated": "success"
+            })))
+        },
+        Err(e) => {
+            tx.rollback().await.unwrap();
+            match e {
+                sqlx::Error::Database(e) if e.code().as_deref() == Some("23505") => (StatusCode::BAD_REQUEST, Json(json!({
+                    "status": "error",
+                    "message": "SKU already exists",
+                }))),
+                e => servererror(e),
+            }
+        }
+    }
+}
+
+
+//Delete variant route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn deletevarianthandler(State(state): State<AppState>, Path((productid, variantid)): Path<(Uuid, Uuid)>) -> impl IntoResponse {
+    let mut tx = state.database.db.begin().await.unwrap();
+    let response = sqlx::query(
+        "DELETE FROM productvariants WHERE productid = $1 AND variantid = $2")
+        .bind(productid)
+        .bind(variantid)
+        .execute(&mut tx)
+        .await;
+    let response = match response {
+        Ok(result) if result.rows_affected() > 0 => webhooks::productupdated(&mut tx, productid).await.map(|_| true),
+        Ok(_) => Ok(false),
+        Err(e) => Err(e),
+    };
+    match response {
+        Ok(false) => {
+            tx.rollback().await.unwrap();
+            (StatusCode::NOT_FOUND, Json(json!({
+                "status": "error",
+                "message": "Variant not found",
+            })))
+        },
+        Ok(true) => {
+            tx.commit().await.unwrap();
+            (StatusCode::OK, Json(json!({
+                "variant": "deleted"
+            })))
+        },
+        // Variants referenced by past orders or the stock ledger cannot be removed
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => (StatusCode::CONFLICT, Json(json!({
+            "status": "error",
+            "message": "Variant has orders or stock history and cannot be deleted",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
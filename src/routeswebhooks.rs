@@ -0,0 +1,264 @@
+use axum::{Json, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow};
+use uuid::Uuid;
+use crate::{AppState, customerrors::{badrequest, servererror}, webhooks::{self, WEBHOOK_EVENTS}};
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct Webhook {
+    webhookid: Uuid,
+    url: String,
+    events: Vec<String>,
+    description: Option<String>,
+    active: bool,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct WebhookDelivery {
+    deliveryid: Uuid,
+    webhookid: Uuid,
+    event: String,
+    payload: String,
+    status: String,
+    attempts: i32,
+    nextattempt_at: chrono::DateTime<chrono::Utc>,
+    lastattempt_at: Option<chrono::DateTime<chrono::Utc>>,
+    responsestatus: Option<i32>,
+    responsebody: Option<String>,
+    error: Option<String>,
+    redeliveryof: Option<Uuid>,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct NewWebhook {
+    url: String,
+    secret: Option<String>,
+    events: Vec<String>,
+    description: Option<String>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct WebhookUpdate {
+    url: Option<String>,
+    secret: Option<String>,
+    events: Option<Vec<String>>,
+    description: Option<String>,
+    active: Option<bool>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct DeliveryQuery {
+    status: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>
+}
+
+
+fn webhooknotfound() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::NOT_FOUND, Json(json!({
+        "status": "error",
+        "message": "Webhook not found",
+    })))
+}
+
+fn validurl(url: &str) -> bool {
+    matches!(reqwest::Url::parse(url), Ok(url) if url.scheme() == "http" || url.scheme() == "https")
+}
+
+fn validevents(events: &[String]) -> bool {
+    !events.is_empty() && events.iter().all(|event| WEBHOOK_EVENTS.contains(&event.as_str()))
+}
+
+
+//Create webhook route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn createwebhookhandler(State(state): State<AppState>, Json(req): Json<NewWebhook>) -> impl IntoResponse {
+    let url = req.url.trim();
+    if !validurl(url) {
+        return badrequest("url must be an http or https URL")
+    }
+    if !validevents(&req.events) {
+        return badrequest(&format!("events must be one or more of {}", WEBHOOK_EVENTS.join(", ")))
+    }
+    let secret = match req.secret.as_deref().map(str::trim) {
+        Some(secret) if secret.len() < 16 => return badrequest("secret must be at least 16 characters"),
+        Some(secret) => secret.to_string(),
+        None => webhooks::newsecret(),
+    };
+    let response = sqlx::query_as::<_, Webhook>(
+        "INSERT INTO webhooks (webhookid, url, secret, events, description) VALUES ($1, $2, $3, $4, $5) RETURNING *")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(url)
+        .bind(&secret)
+        .bind(&req.events)
+        .bind(&req.description)
+        .fetch_one(&state.database.db)
+        .await;
+    match response {
+        // The secret is only ever shown here
+        Ok(webhook) => (StatusCode::CREATED, Json(json!({
+            "status": "success",
+            "webhook": webhook,
+            "secret": secret
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Fetch webhooks route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchwebhookshandler(State(state): State<AppState>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks ORDER BY created_at DESC")
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(webhooks) => (StatusCode::OK, Json(json!({
+            "webhooks": webhooks,
+            "events": WEBHOOK_EVENTS
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Update webhook route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn updatewebhookhandler(State(state): State<AppState>, Path(webhookid): Path<Uuid>, Json(req): Json<WebhookUpdate>) -> impl IntoResponse {
+    let url = req.url.as_deref().map(str::trim);
+    if matches!(url, Some(url) if !validurl(url)) {
+        return badrequest("url must be an http or https URL")
+    }
+    if matches!(&req.events, Some(events) if !validevents(events)) {
+        return badrequest(&format!("events must be one or more of {}", WEBHOOK_EVENTS.join(", ")))
+    }
+    let secret = req.secret.as_deref().map(str::trim);
+    if matches!(secret, Some(secret) if secret.len() < 16) {
+        return badrequest("secret must be at least 16 characters")
+    }
+    let response = sqlx::query_as::<_, Webhook>(
+        "UPDATE webhooks SET
+        url = COALESCE($2, url),
+        secret = COALESCE($3, secret),
+        events = COALESCE($4, events),
+        description = COALESCE($5, description),
+        active = COALESCE($6, active)
+        WHERE webhookid = $1 RETURNING *")
+        .bind(webhookid)
+        .bind(url)
+        .bind(secret)
+        .bind(&req.events)
+        .bind(&req.description)
+        .bind(req.active)
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some(webhook)) => (StatusCode::OK, Json(json!({
+            "status": "success",
+            "webhook": webhook
+        }))),
+        Ok(None) => webhooknotfound(),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Delete webhook route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn deletewebhookhandler(State(state): State<AppState>, Path(webhookid): Path<Uuid>) -> impl IntoResponse {
+    let response = sqlx::query("DELETE FROM webhooks WHERE webhookid = $1")
+        .bind(webhookid)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(deleted) if deleted.rows_affected() == 0 => webhooknotfound(),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "status": "success"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Fetch webhook deliveries route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchdeliverieshandler(State(state): State<AppState>, Path(webhookid): Path<Uuid>, Query(query): Query<DeliveryQuery>) -> impl IntoResponse {
+    if !matches!(query.status.as_deref(), None | Some("pending") | Some("succeeded") | Some("failed")) {
+        return badrequest("status must be one of pending, succeeded, failed")
+    }
+    let response = sqlx::query_as::<_, WebhookDelivery>(
+        "SELECT * FROM webhookdeliveries
+        WHERE webhookid = $1 AND ($2::TEXT IS NULL OR status = $2)
+        ORDER BY created_at DESC
+        LIMIT $3 OFFSET $4")
+        .bind(webhookid)
+        .bind(&query.status)
+        .bind(query.limit.unwrap_or(50).clamp(1, 200))
+        .bind(query.offset.unwrap_or(0).max(0))
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(deliveries) => (StatusCode::OK, Json(json!({
+            "deliveries": deliveries
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Send test event route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Queues a webhook.test event for this webhook only, whatever it subscribes to
+pub async fn testwebhookhandler(State(state): State<AppState>, Path(webhookid): Path<Uuid>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, WebhookDelivery>(
+        "INSERT INTO webhookdeliveries (deliveryid, webhookid, event, payload)
+        SELECT $1, webhookid, 'webhook.test', $2 FROM webhooks WHERE webhookid = $3
+        RETURNING *")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(json!({ "message": "Test event" }).to_string())
+        .bind(webhookid)
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some(delivery)) => (StatusCode::ACCEPTED, Json(json!({
+            "status": "success",
+            "delivery": delivery
+        }))),
+        Ok(None) => webhooknotfound(),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Redeliver route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Sends the same payload again as a new delivery, leaving the original in the log
+pub async fn redeliverhandler(State(state): State<AppState>, Path(deliveryid): Path<Uuid>) -> impl IntoResponse {
+    let response = sqlx::query_as::<_, WebhookDelivery>(
+        "INSERT INTO webhookdeliveries (deliveryid, webhookid, event, payload, redeliveryof)
+        SELECT $1, webhookid, event, payload, deliveryid FROM webhookdeliveries WHERE deliveryid = $2
+        RETURNING *")
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(deliveryid)
+        .fetch_optional(&state.database.db)
+        .await;
+    match response {
+        Ok(Some(delivery)) => (StatusCode::ACCEPTED, Json(json!({
+            "status": "success",
+            "delivery": delivery
+        }))),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Delivery not found",
+        }))),
+        Err(e) => servererror(e),
+    }
+}
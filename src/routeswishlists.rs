@@ -0,0 +1,264 @@
+use axum::{Json, Extension, extract::{Path, Query, State}, response::IntoResponse, http::StatusCode};
+use bigdecimal::BigDecimal;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{self, FromRow, Pool, Postgres};
+use uuid::Uuid;
+use crate::{AppState, customerrors::{badrequest, forbidden, servererror}, inventory::{HELD_PRODUCT_SQL, HELD_VARIANT_SQL}, mware::ClaimsAccessToken, pricing::pricesql};
+
+#[derive(Deserialize, Debug)]
+
+pub struct FavouriteVariant {
+    variantid: Option<Uuid>
+}
+
+// pricechange is the current price minus the price when the item was added
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct WishlistItem {
+    productid: Uuid,
+    variantid: Option<Uuid>,
+    sku: String,
+    prodname: String,
+    price: String,
+    addedprice: Option<BigDecimal>,
+    pricechange: Option<BigDecimal>,
+    availabletosell: i64,
+    instock: bool,
+    image: Option<String>,
+    added_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct WishlistShare {
+    shareid: Uuid,
+    name: String,
+    token: String,
+    url: String,
+    created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Deserialize, Debug)]
+
+pub struct NewWishlistShare {
+    name: String
+}
+
+
+const SHARE_COLUMNS: &str = "shareid, name, token, $1::TEXT || '/wishlists/' || token AS url, created_at";
+
+async fn fetchwishlist(db: &Pool<Postgres>, userid: Uuid) -> Result<Vec<WishlistItem>, sqlx::Error> {
+    sqlx::query_as::<_, WishlistItem>(&format!(
+        "SELECT products.productid, favourites.variantid,
+        COALESCE(productvariants.sku, products.prodsku) AS sku,
+        products.prodname,
+        COALESCE(productvariants.price, products.price) AS price,
+        favourites.addedprice,
+        {} - favourites.addedprice AS pricechange,
+        stock.availabletosell,
+        stock.availabletosell > 0 AS instock,
+        cover.image,
+        favourites.added_at
+        FROM favourites
+        INNER JOIN products
+        ON products.productid = favourites.productid
+        LEFT JOIN productvariants
+        ON favourites.variantid = productvariants.variantid
+        CROSS JOIN LATERAL (
+            SELECT COALESCE(productvariants.availableqty - {}, products.availableqty - {}) AS availabletosell
+        ) stock
+        LEFT JOIN LATERAL (
+            SELECT COALESCE(thumbnail, original) AS image FROM productgallery
+            WHERE productgallery.productid = products.productid
+            ORDER BY (productgallery.variantid IS NOT DISTINCT FROM favourites.variantid) DESC, position LIMIT 1
+        ) cover ON true
+        WHERE favourites.userid = $1
+        AND products.status = 'active'
+        ORDER BY favourites.added_at DESC", pricesql("COALESCE(productvariants.price, products.price)"), HELD_VARIANT_SQL, HELD_PRODUCT_SQL))
+        .bind(userid)
+        .fetch_all(db)
+        .await
+}
+
+
+//Add favourite route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Adding an item that is already in the list leaves it as it was, including the price it was added at
+pub async fn addfavouriteitems(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((userid, productid)): Path<(Uuid, Uuid)>, Query(query): Query<FavouriteVariant>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own wishlist") {
+        return response
+    }
+    let response = sqlx::query_as::<_, (Uuid,)>(&format!(
+        "INSERT INTO favourites (favid, userid, productid, variantid, addedprice)
+        SELECT $1, $2, products.productid, productvariants.variantid, {}
+        FROM products
+        LEFT JOIN productvariants ON productvariants.productid = products.productid AND productvariants.variantid = $4
+        WHERE products.productid = $3 AND products.status = 'active'
+        AND ($4::uuid IS NULL OR productvariants.variantid IS NOT NULL)
+        ON CONFLICT (userid, productid, COALESCE(variantid, '00000000-0000-0000-0000-000000000000'::uuid))
+        DO UPDATE SET userid = favourites.userid
+        RETURNING favid", pricesql("COALESCE(productvariants.price, products.price)")))
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(userid)
+        .bind(productid)
+        .bind(query.variantid)
+        .fetch_optional(&state.database.db)
+        .await;
+    // A variantid that isn't one of the product's is not found rather than ignored
+    match response {
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": if query.variantid.is_some() { "Product or variant not found" } else { "Product not found" },
+        }))),
+        Ok(Some(_)) => (StatusCode::OK, Json(json!({
+            "favourite": "added"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Fetch favourites route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchfavouriteitems(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own wishlist") {
+        return response
+    }
+    match fetchwishlist(&state.database.db, userid).await {
+        Ok(product) => (StatusCode::OK, Json(json!({
+            "productlistres": product
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Delete favourite route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn deletefavorite(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((userid, productid)): Path<(Uuid, Uuid)>, Query(query): Query<FavouriteVariant>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own wishlist") {
+        return response
+    }
+    let response = sqlx::query(
+        "DELETE FROM favourites
+        WHERE userid = $1
+        AND productid = $2
+        AND variantid IS NOT DISTINCT FROM $3")
+        .bind(userid)
+        .bind(productid)
+        .bind(query.variantid)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Favourite not found",
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "favourite": "deleted"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Create wishlist share route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn createsharehandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>, Json(req): Json<NewWishlistShare>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own wishlist") {
+        return response
+    }
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return badrequest("name must be between 1 and 100 characters")
+    }
+    let token = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    let response = sqlx::query_as::<_, WishlistShare>(&format!(
+        "INSERT INTO wishlistshares (shareid, userid, name, token) VALUES ($2, $3, $4, $5) RETURNING {}", SHARE_COLUMNS))
+        .bind(&state.mailer.siteurl)
+        .bind(sqlx::types::Uuid::from_u128(uuid::Uuid::new_v4().as_u128()))
+        .bind(userid)
+        .bind(name)
+        .bind(&token)
+        .fetch_one(&state.database.db)
+        .await;
+    match response {
+        Ok(share) => (StatusCode::CREATED, Json(json!({
+            "status": "success",
+            "share": share
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Fetch wishlist shares route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn fetchshareshandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path(userid): Path<Uuid>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own wishlist") {
+        return response
+    }
+    let response = sqlx::query_as::<_, WishlistShare>(&format!(
+        "SELECT {} FROM wishlistshares WHERE userid = $2 ORDER BY created_at DESC", SHARE_COLUMNS))
+        .bind(&state.mailer.siteurl)
+        .bind(userid)
+        .fetch_all(&state.database.db)
+        .await;
+    match response {
+        Ok(shares) => (StatusCode::OK, Json(json!({
+            "shares": shares
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Delete wishlist share route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+pub async fn deletesharehandler(State(state): State<AppState>, Extension(claims): Extension<ClaimsAccessToken>, Path((userid, shareid)): Path<(Uuid, Uuid)>) -> impl IntoResponse {
+    if let Some(response) = forbidden(&claims, userid, "You can only manage your own wishlist") {
+        return response
+    }
+    let response = sqlx::query("DELETE FROM wishlistshares WHERE shareid = $1 AND userid = $2")
+        .bind(shareid)
+        .bind(userid)
+        .execute(&state.database.db)
+        .await;
+    match response {
+        Ok(result) if result.rows_affected() == 0 => (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Share link not found",
+        }))),
+        Ok(_) => (StatusCode::OK, Json(json!({
+            "status": "success"
+        }))),
+        Err(e) => servererror(e),
+    }
+}
+
+
+//Shared wishlist route ==============>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>>
+
+// Public view of a shared wishlist. Shows the list name and items, nothing about the owner.
+pub async fn sharedwishlisthandler(State(state): State<AppState>, Path(token): Path<String>) -> impl IntoResponse {
+    let share = sqlx::query_as::<_, (Uuid, String)>("SELECT userid, name FROM wishlistshares WHERE token = $1")
+        .bind(&token)
+        .fetch_optional(&state.database.db)
+        .await;
+    let (userid, name) = match share {
+        Ok(Some(share)) => share,
+        Ok(None) => return (StatusCode::NOT_FOUND, Json(json!({
+            "status": "error",
+            "message": "Wishlist not found",
+        }))),
+        Err(e) => return servererror(e),
+    };
+    match fetchwishlist(&state.database.db, userid).await {
+        Ok(items) => (StatusCode::OK, Json(json!({
+            "name": name,
+            "items": items
+        }))),
+        Err(e) => servererror(e),
+    }
+}
@@ -0,0 +1,166 @@
+use bigdecimal::BigDecimal;
+use serde::Serialize;
+use sqlx::{self, Executor, FromRow, Postgres, Transaction};
+use uuid::Uuid;
+use crate::pricing::{self, RateKind, ShippingRate};
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct ShippingMethod {
+    pub methodid: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub ratekind: String,
+    pub baserate: BigDecimal,
+    pub perkg: BigDecimal,
+    pub freeabove: Option<BigDecimal>,
+    pub maxweightgrams: Option<i32>,
+    pub postcodeprefixes: Vec<String>,
+    pub active: bool,
+    pub position: i32,
+    pub created_at: chrono::DateTime<chrono::Utc>
+}
+
+#[derive(Serialize, Debug)]
+
+pub struct ShippingOption {
+    pub methodid: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub cost: BigDecimal
+}
+
+#[derive(Debug)]
+
+pub enum ShippingError {
+    NotFound,
+    NotAvailable,
+    Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for ShippingError {
+    fn from(e: sqlx::Error) -> Self {
+        ShippingError::Database(e)
+    }
+}
+
+impl ShippingError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ShippingError::NotFound => "Shipping method not found",
+            ShippingError::NotAvailable => "Shipping method is not available for this basket and postcode",
+            ShippingError::Database(_) => "Something went wrong",
+        }
+    }
+}
+
+// Postcodes are compared without spaces, e.g. "IV27 4AB" has area IV and outward code IV27
+pub fn compactpostcode(postcode: &str) -> String {
+    postcode.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase()
+}
+
+// Checks the shape of a UK postcode and returns it as "SW1A 1AA"
+pub fn normaliseukpostcode(postcode: &str) -> Option<String> {
+    let postcode = compactpostcode(postcode);
+    if postcode == "GIR0AA" {
+        return Some("GIR 0AA".to_string())
+    }
+    if !(5..=7).contains(&postcode.len()) || !postcode.is_ascii() {
+        return None
+    }
+    let (outward, inward) = postcode.split_at(postcode.len() - 3);
+    let shape: String = outward.chars().map(|c| if c.is_ascii_digit() { '9' } else if c.is_ascii_alphabetic() { 'A' } else { '?' }).collect();
+    let inwardvalid = inward.chars().enumerate().all(|(index, c)| if index == 0 { c.is_ascii_digit() } else { c.is_ascii_alphabetic() });
+    if inwardvalid && ["A9", "A99", "AA9", "AA99", "A9A", "AA9A"].contains(&shape.as_str()) {
+        Some(format!("{} {}", outward, inward))
+    } else {
+        None
+    }
+}
+
+pub fn postcodematches(prefixes: &[String], postcode: &str) -> bool {
+    if prefixes.is_empty() {
+        return true
+    }
+    let postcode = compactpostcode(postcode);
+    let area: String = postcode.chars().take_while(|c| c.is_ascii_alphabetic()).collect();
+    let outward = postcode.get(..postcode.len().saturating_sub(3)).filter(|outward| !outward.is_empty()).unwrap_or(&postcode);
+    prefixes.iter().any(|prefix| *prefix == area || *prefix == outward)
+}
+
+impl ShippingMethod {
+    pub fn rate(&self) -> ShippingRate {
+        ShippingRate {
+            kind: RateKind::from_str(&self.ratekind).unwrap_or(RateKind::Flat),
+            baserate: self.baserate.clone(),
+            perkg: self.perkg.clone(),
+            freeabove: self.freeabove.clone()
+        }
+    }
+
+    pub fn availablefor(&self, postcode: &str, weightgrams: i64) -> bool {
+        self.active
+            && !matches!(self.maxweightgrams, Some(maxweight) if weightgrams > maxweight as i64)
+            && postcodematches(&self.postcodeprefixes, postcode)
+    }
+
+    pub fn option(&self, basket: &BigDecimal, weightgrams: i64) -> ShippingOption {
+        ShippingOption {
+            methodid: self.methodid,
+            name: self.name.clone(),
+            description: self.description.clone(),
+            cost: pricing::shippingcost(&self.rate(), basket, weightgrams)
+        }
+    }
+}
+
+pub async fn availablemethods<'e, E: Executor<'e, Database = Postgres>>(executor: E, postcode: &str, basket: &BigDecimal, weightgrams: i64) -> Result<Vec<ShippingOption>, sqlx::Error> {
+    let methods = sqlx::query_as::<_, ShippingMethod>(
+        "SELECT * FROM shippingmethods WHERE active ORDER BY position, created_at")
+        .fetch_all(executor)
+        .await?;
+    Ok(methods.iter()
+        .filter(|method| method.availablefor(postcode, weightgrams))
+        .map(|method| method.option(basket, weightgrams))
+        .collect())
+}
+
+pub async fn quote(tx: &mut Transaction<'_, Postgres>, methodid: Uuid, postcode: &str, basket: &BigDecimal, weightgrams: i64) -> Result<ShippingOption, ShippingError> {
+    let method = sqlx::query_as::<_, ShippingMethod>("SELECT * FROM shippingmethods WHERE methodid = $1")
+        .bind(methodid)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ShippingError::NotFound)?;
+    if !method.availablefor(postcode, weightgrams) {
+        return Err(ShippingError::NotAvailable)
+    }
+    Ok(method.option(basket, weightgrams))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn postcodes_are_normalised() {
+        assert_eq!(normaliseukpostcode("sw1a1aa").as_deref(), Some("SW1A 1AA"));
+        assert_eq!(normaliseukpostcode(" iv27  4ab ").as_deref(), Some("IV27 4AB"));
+        assert_eq!(normaliseukpostcode("M1 1AE").as_deref(), Some("M1 1AE"));
+        assert_eq!(normaliseukpostcode("B33 8TH").as_deref(), Some("B33 8TH"));
+        assert_eq!(normaliseukpostcode("CR2 6XH").as_deref(), Some("CR2 6XH"));
+        assert_eq!(normaliseukpostcode("W1A 0AX").as_deref(), Some("W1A 0AX"));
+        assert_eq!(normaliseukpostcode("gir0aa").as_deref(), Some("GIR 0AA"));
+    }
+
+    #[test]
+    fn malformed_postcodes_are_rejected() {
+        assert_eq!(normaliseukpostcode(""), None);
+        assert_eq!(normaliseukpostcode("SW1A"), None);
+        assert_eq!(normaliseukpostcode("SW1A 1AAA"), None);
+        assert_eq!(normaliseukpostcode("1W1A 1AA"), None);
+        assert_eq!(normaliseukpostcode("SW1A AAA"), None);
+        assert_eq!(normaliseukpostcode("SW1A 11A"), None);
+        assert_eq!(normaliseukpostcode("SW-A 1AA"), None);
+        assert_eq!(normaliseukpostcode("ŚW1A 1AA"), None);
+    }
+}
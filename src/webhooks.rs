@@ -0,0 +1,268 @@
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use sqlx::{self, Executor, FromRow, Pool, Postgres, Transaction};
+use uuid::Uuid;
+
+pub const WEBHOOK_EVENTS: [&str; 8] = [
+    "order.created", "order.paid", "order.shipped", "order.delivered", "order.cancelled", "order.refunded",
+    "product.updated", "stock.low"];
+
+const DISPATCH_INTERVAL_SECONDS: u64 = 5;
+const DISPATCH_BATCH: i64 = 20;
+const MAX_ATTEMPTS: i32 = 8;
+// A claimed delivery is retried after this long if the instance sending it dies
+const CLAIM_SECONDS: i64 = 300;
+
+#[derive(FromRow, Debug)]
+
+struct DueDelivery {
+    deliveryid: Uuid,
+    event: String,
+    payload: String,
+    attempts: i32,
+    created_at: chrono::DateTime<chrono::Utc>,
+    url: String,
+    secret: String
+}
+
+#[derive(Serialize, FromRow, Debug)]
+
+pub struct ProductSnapshot {
+    productid: Uuid,
+    prodname: String,
+    prodsku: String,
+    price: String,
+    category: Option<String>,
+    status: String,
+    taxclass: String,
+    availableqty: i64
+}
+
+// Queues the event for every active webhook subscribed to it. Pass the
+// transaction making the change so the event is only sent if it commits.
+pub async fn enqueue<'e, E: Executor<'e, Database = Postgres>>(executor: E, event: &str, data: &Value) -> Result<u64, sqlx::Error> {
+    let queued = sqlx::query(
+        "INSERT INTO webhookdeliveries (deliveryid, webhookid, event, payload)
+        SELECT gen_random_uuid(), webhookid, $1, $2 FROM webhooks
+        WHERE active AND $1 = ANY(events)")
+        .bind(event)
+        .bind(data.to_string())
+        .execute(executor)
+        .await?;
+    Ok(queued.rows_affected())
+}
+
+// The order, where it ships to and its lines as JSON text, for order events
+const ORDER_PAYLOAD_SQL: &str = "SELECT json_build_object(
+    'order', json_build_object(
+        'orderid', orderdet.orderid,
+        'status', orderdet.status,
+        'userid', orderdet.userid,
+        'guestid', orderdet.guestid,
+        'subtotal', orderdet.subtotal,
+        'discounttotal', orderdet.discounttotal,
+        'shippingcost', orderdet.shippingcost,
+        'nettotal', orderdet.nettotal,
+        'taxtotal', orderdet.taxtotal,
+        'total', orderdet.total,
+        'shippingmethod', orderdet.shippingmethod,
+        'carrier', orderdet.carrier,
+        'trackingnumber', orderdet.trackingnumber,
+        'created_at', orderdet.created_at,
+        'paid_at', orderdet.paid_at,
+        'shipped_at', orderdet.shipped_at,
+        'delivered_at', orderdet.delivered_at),
+    'shipto', json_build_object(
+        'fullname', orderdet.shipname,
+        'address', orderdet.shipaddress,
+        'city', orderdet.shipcity,
+        'postcode', orderdet.shippostcode),
+    'lines', COALESCE((SELECT json_agg(json_build_object(
+        'productid', listitems.productid,
+        'variantid', listitems.variantid,
+        'sku', listitems.sku,
+        'prodname', listitems.prodname,
+        'quantity', listitems.quantity,
+        'unitprice', listitems.unitprice,
+        'netamount', listitems.netamount,
+        'taxamount', listitems.taxamount))
+        FROM listitems WHERE listitems.orderidretr = orderdet.orderid), '[]'::json))::text AS body
+    FROM orderdet WHERE orderdet.orderid = $2";
+
+// Same as enqueue with the order as the event data, read at the time of the change
+pub async fn orderevent<'e, E: Executor<'e, Database = Postgres>>(executor: E, event: &str, orderid: i64) -> Result<u64, sqlx::Error> {
+    let queued = sqlx::query(&format!(
+        "INSERT INTO webhookdeliveries (deliveryid, webhookid, event, payload)
+        SELECT gen_random_uuid(), webhooks.webhookid, $1, payload.body
+        FROM webhooks, ({}) payload
+        WHERE webhooks.active AND $1 = ANY(webhooks.events)", ORDER_PAYLOAD_SQL))
+        .bind(event)
+        .bind(orderid)
+        .execute(executor)
+        .await?;
+    Ok(queued.rows_affected())
+}
+
+pub async fn productupdated(tx: &mut Transaction<'_, Postgres>, productid: Uuid) -> Result<(), sqlx::Error> {
+    let product = sqlx::query_as::<_, ProductSnapshot>(
+        "SELECT productid, prodname, prodsku, price, category, status, taxclass, availableqty FROM products WHERE productid = $1")
+        .bind(productid)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if let Some(product) = product {
+        enqueue(&mut *tx, "product.updated", &json!({ "product": product })).await?;
+    }
+    Ok(())
+}
+
+// The receiver recomputes HMAC-SHA256 of "<timestamp>.<body>" with its secret and
+// compares it with the X-Webhook-Signature header
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub fn newsecret() -> String {
+    format!("whsec_{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+// 30 seconds after the first failure, doubling up to six hours
+fn backoffseconds(attempts: i32) -> i64 {
+    (30 * 2_i64.pow(attempts.clamp(1, 16) as u32 - 1)).min(6 * 60 * 60)
+}
+
+// Due deliveries are claimed with SKIP LOCKED so several instances can run this.
+// Deliveries for a paused webhook wait until it is active again.
+async fn claimdue(db: &Pool<Postgres>) -> Result<Vec<DueDelivery>, sqlx::Error> {
+    sqlx::query_as::<_, DueDelivery>(
+        "UPDATE webhookdeliveries SET nextattempt_at = now() + make_interval(secs => $2)
+        FROM webhooks
+        WHERE webhooks.webhookid = webhookdeliveries.webhookid
+        AND webhookdeliveries.deliveryid IN (
+            SELECT deliveryid FROM webhookdeliveries
+            WHERE status = 'pending' AND nextattempt_at <= now()
+            AND webhookid IN (SELECT webhookid FROM webhooks WHERE active)
+            ORDER BY nextattempt_at
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED)
+        RETURNING webhookdeliveries.deliveryid, webhookdeliveries.event, webhookdeliveries.payload, webhookdeliveries.attempts,
+        webhookdeliveries.created_at, webhooks.url, webhooks.secret")
+        .bind(DISPATCH_BATCH)
+        .bind(CLAIM_SECONDS as f64)
+        .fetch_all(db)
+        .await
+}
+
+async fn deliver(client: &reqwest::Client, db: &Pool<Postgres>, delivery: DueDelivery) -> Result<(), sqlx::Error> {
+    let data: Value = serde_json::from_str(&delivery.payload).unwrap_or(Value::Null);
+    let body = json!({
+        "id": delivery.deliveryid,
+        "event": delivery.event,
+        "created_at": delivery.created_at,
+        "data": data
+    }).to_string();
+    let timestamp = chrono::Utc::now().timestamp();
+    let response = client.post(&delivery.url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Id", delivery.deliveryid.to_string())
+        .header("X-Webhook-Event", &delivery.event)
+        .header("X-Webhook-Timestamp", timestamp.to_string())
+        .header("X-Webhook-Signature", sign(&delivery.secret, timestamp, &body))
+        .body(body)
+        .send()
+        .await;
+    let (responsestatus, responsebody, error) = match response {
+        Ok(response) => {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            let error = if status.is_success() { None } else { Some(format!("receiver responded with {}", status)) };
+            (Some(status.as_u16() as i32), Some(text.chars().take(1000).collect::<String>()), error)
+        },
+        Err(e) => (None, None, Some(e.to_string())),
+    };
+    let attempts = delivery.attempts + 1;
+    let status = match &error {
+        None => "succeeded",
+        Some(_) if attempts >= MAX_ATTEMPTS => "failed",
+        Some(_) => "pending",
+    };
+    sqlx::query(
+        "UPDATE webhookdeliveries SET
+        status = $2,
+        attempts = $3,
+        lastattempt_at = now(),
+        nextattempt_at = now() + make_interval(secs => $4),
+        responsestatus = $5,
+        responsebody = $6,
+        error = $7
+        WHERE deliveryid = $1")
+        .bind(delivery.deliveryid)
+        .bind(status)
+        .bind(attempts)
+        .bind(backoffseconds(attempts) as f64)
+        .bind(responsestatus)
+        .bind(responsebody)
+        .bind(error)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+pub fn spawndispatcher(db: Pool<Postgres>) {
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()
+            .unwrap_or_default();
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(DISPATCH_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            let due = match claimdue(&db).await {
+                Ok(due) => due,
+                Err(e) => {
+                    println!("webhook dispatch failed: {:?}", e);
+                    continue;
+                }
+            };
+            for delivery in due {
+                let deliveryid = delivery.deliveryid;
+                if let Err(e) = deliver(&client, &db, delivery).await {
+                    println!("webhook delivery {} could not be recorded: {:?}", deliveryid, e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_covers_timestamp_and_body() {
+        let signature = sign("whsec_test", 1700000000, "{\"event\":\"order.paid\"}");
+        assert_eq!(signature, "sha256=0dc82e7b0e522c6b4d99a57cf6eea200eb7233cdf254b9adb99ea896c1b3ef3f");
+        assert_ne!(sign("whsec_test", 1700000001, "{\"event\":\"order.paid\"}"), signature);
+        assert_ne!(sign("whsec_other", 1700000000, "{\"event\":\"order.paid\"}"), signature);
+    }
+
+    #[test]
+    fn backoff_doubles_from_thirty_seconds() {
+        assert_eq!(backoffseconds(1), 30);
+        assert_eq!(backoffseconds(2), 60);
+        assert_eq!(backoffseconds(3), 120);
+        assert_eq!(backoffseconds(10), 15360);
+    }
+
+    #[test]
+    fn backoff_is_capped_at_six_hours() {
+        assert_eq!(backoffseconds(0), 30);
+        assert_eq!(backoffseconds(11), 6 * 60 * 60);
+        assert_eq!(backoffseconds(i32::MAX), 6 * 60 * 60);
+    }
+}